@@ -0,0 +1,295 @@
+//! CDN ごとの POP (colo) 取得方法。
+//!
+//! Cloudflare は `/cdn-cgi/trace` のボディから、それ以外はターゲット自身への HEAD リクエストの
+//! レスポンスヘッダから POP を取り出す。どのプロバイダでも結果は `CheckResult.colo` に入る。
+
+use crate::probe::{Method, ProbeResponse};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use url::Url;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum CdnProvider {
+    #[default]
+    Cloudflare,
+    Cloudfront,
+    Fastly,
+    Akamai,
+    Bunny,
+    Vercel,
+    /// レスポンスヘッダからプロバイダを自動判別する
+    Auto,
+}
+
+impl fmt::Display for CdnProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            CdnProvider::Cloudflare => "cloudflare",
+            CdnProvider::Cloudfront => "cloudfront",
+            CdnProvider::Fastly => "fastly",
+            CdnProvider::Akamai => "akamai",
+            CdnProvider::Bunny => "bunny",
+            CdnProvider::Vercel => "vercel",
+            CdnProvider::Auto => "auto",
+        })
+    }
+}
+
+/// `Auto` のときに試す順序
+const DETECTION_ORDER: [CdnProvider; 6] = [
+    CdnProvider::Cloudflare,
+    CdnProvider::Cloudfront,
+    CdnProvider::Fastly,
+    CdnProvider::Vercel,
+    CdnProvider::Bunny,
+    CdnProvider::Akamai,
+];
+
+impl CdnProvider {
+    /// 実際にリクエストを送る URL。
+    pub fn probe_url(&self, target: &Url) -> Result<Url> {
+        match self {
+            CdnProvider::Cloudflare => Ok(target.join("/cdn-cgi/trace")?),
+            _ => Ok(target.clone()),
+        }
+    }
+
+    /// Cloudflare 以外はヘッダしか見ないので、ページ本体をダウンロードしないよう HEAD を送る。
+    pub fn method(&self) -> Method {
+        match self {
+            CdnProvider::Cloudflare => Method::Get,
+            _ => Method::Head,
+        }
+    }
+
+    /// POP 情報を出してもらうために追加で送るリクエストヘッダ。
+    pub fn request_headers(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            CdnProvider::Akamai | CdnProvider::Auto => &[(
+                "Pragma",
+                "akamai-x-cache-on, akamai-x-get-request-id, akamai-x-request-trace",
+            )],
+            _ => &[],
+        }
+    }
+
+    /// レスポンスヘッダから POP を取り出す。取れたらそのプロバイダと一緒に返す。
+    pub fn pop_from_headers(&self, resp: &ProbeResponse) -> Option<(CdnProvider, String)> {
+        let pop = match self {
+            CdnProvider::Cloudflare => resp.header("cf-ray").and_then(pop_from_cf_ray),
+            CdnProvider::Cloudfront => {
+                resp.header("x-amz-cf-pop")
+                    .and_then(leading_iata)
+                    .or_else(|| {
+                        resp.header("server-timing")
+                            .and_then(pop_from_server_timing)
+                    })
+            }
+            CdnProvider::Fastly => resp.header("x-served-by").and_then(pop_from_fastly),
+            // x-cache のエッジホスト名 (a23-45-67-89) はサーバー単位で変わり POP を表さないので使わない
+            CdnProvider::Akamai => resp
+                .header("akamai-request-bc")
+                .and_then(pop_from_akamai_bc),
+            CdnProvider::Bunny => resp.header("server").and_then(pop_from_bunny_server),
+            CdnProvider::Vercel => resp.header("x-vercel-id").and_then(pop_from_vercel_id),
+            CdnProvider::Auto => {
+                return DETECTION_ORDER
+                    .iter()
+                    .find_map(|p| p.pop_from_headers(resp));
+            }
+        };
+        pop.map(|pop| (*self, pop))
+    }
+}
+
+/// `cf-ray: 8a1b2c3d4e5f6789-NRT` の末尾が colo。
+pub fn pop_from_cf_ray(value: &str) -> Option<String> {
+    let (_, colo) = value.trim().rsplit_once('-')?;
+    (!colo.is_empty()).then(|| colo.to_ascii_uppercase())
+}
+
+/// `x-amz-cf-pop: NRT57-P2` のような値から先頭の英字 (IATA コード) を取り出す。
+fn leading_iata(value: &str) -> Option<String> {
+    let code: String = value
+        .trim()
+        .chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    (!code.is_empty()).then(|| code.to_ascii_uppercase())
+}
+
+/// `server-timing` の `*pop*; desc="NRT57-P2"` エントリから POP を取り出す。
+fn pop_from_server_timing(value: &str) -> Option<String> {
+    value.split(',').find_map(|entry| {
+        let mut params = entry.split(';').map(str::trim);
+        let name = params.next()?;
+        if !name.to_ascii_lowercase().contains("pop") {
+            return None;
+        }
+        params
+            .find_map(|p| p.strip_prefix("desc="))
+            .map(|d| d.trim_matches('"'))
+            .and_then(leading_iata)
+    })
+}
+
+/// `x-served-by: cache-iad2120-IAD, cache-nrt-rjtf7700042-NRT` の最後 (クライアント側エッジ)。
+fn pop_from_fastly(value: &str) -> Option<String> {
+    let edge = value.split(',').next_back()?.trim();
+    let (_, pop) = edge.rsplit_once('-')?;
+    (!pop.is_empty()).then(|| pop.to_ascii_uppercase())
+}
+
+/// `akamai-request-bc: [a=23.1.2.3,b=...,n=JP_13_TOKYO,o=20940]` の `n=` (エッジの所在地)。
+fn pop_from_akamai_bc(value: &str) -> Option<String> {
+    value
+        .trim_matches(|c| c == '[' || c == ']')
+        .split(',')
+        .find_map(|kv| kv.trim().strip_prefix("n="))
+        .filter(|n| !n.is_empty())
+        .map(str::to_string)
+}
+
+/// `server: BunnyCDN-JP1-1153` の POP ID (`JP1`)。末尾はサーバー番号なので捨てる。
+/// 国コード + 番号の形なので、IATA コードの colo 表や `expected_colos` の 3 文字コードとは重ならない。
+fn pop_from_bunny_server(value: &str) -> Option<String> {
+    let rest = value.trim().strip_prefix("BunnyCDN-")?;
+    let pop = match rest.rsplit_once('-') {
+        Some((pop, server)) if server.chars().all(|c| c.is_ascii_digit()) => pop,
+        _ => rest,
+    };
+    (!pop.is_empty()).then(|| pop.to_ascii_uppercase())
+}
+
+/// `x-vercel-id: hnd1::iad1::abcd-1700000000000-0123` の先頭 (エッジリージョン)。
+fn pop_from_vercel_id(value: &str) -> Option<String> {
+    let edge = value.trim().split("::").next()?;
+    leading_iata(edge)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::Duration;
+
+    fn response(headers: &[(&str, &str)]) -> ProbeResponse {
+        ProbeResponse {
+            status: 200,
+            peer: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            headers: headers
+                .iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v.to_string()))
+                .collect(),
+            body: String::new(),
+            timings: Default::default(),
+            total: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn parses_pop_header_values() {
+        type Parser = fn(&str) -> Option<String>;
+        let cases: &[(Parser, &str, Option<&str>)] = &[
+            (pop_from_cf_ray, "8a1b2c3d4e5f6789-NRT", Some("NRT")),
+            (pop_from_cf_ray, "8a1b2c3d4e5f6789-kix", Some("KIX")),
+            (pop_from_cf_ray, "8a1b2c3d4e5f6789", None),
+            (pop_from_cf_ray, "8a1b2c3d4e5f6789-", None),
+            (leading_iata, "NRT57-P2", Some("NRT")),
+            (leading_iata, "FRA56-P5", Some("FRA")),
+            (leading_iata, "57-P2", None),
+            (
+                pop_from_server_timing,
+                "cdn-upstream-layer;desc=\"EDGE\",cdn-cache-miss,cdn-pop;desc=\"HND50-C1\",cdn-rid;desc=\"abc\"",
+                Some("HND"),
+            ),
+            (
+                pop_from_server_timing,
+                "cdn-cache-hit,cdn-rid;desc=\"abc\"",
+                None,
+            ),
+            (
+                pop_from_fastly,
+                "cache-iad-kiad7000025-IAD, cache-nrt-rjtf7700042-NRT",
+                Some("NRT"),
+            ),
+            (pop_from_fastly, "cache-lhr7351-LHR", Some("LHR")),
+            (
+                pop_from_akamai_bc,
+                "[a=23.50.55.14,b=123456789,c=g,n=JP_13_TOKYO,o=20940]",
+                Some("JP_13_TOKYO"),
+            ),
+            (pop_from_akamai_bc, "[a=23.50.55.14,b=123456789,n=]", None),
+            (pop_from_bunny_server, "BunnyCDN-JP1-1153", Some("JP1")),
+            (pop_from_bunny_server, "BunnyCDN-DE1-1079", Some("DE1")),
+            (pop_from_bunny_server, "BunnyCDN-NY1", Some("NY1")),
+            (pop_from_bunny_server, "nginx", None),
+            (
+                pop_from_vercel_id,
+                "hnd1::iad1::8b9xk-1700000000000-0123456789ab",
+                Some("HND"),
+            ),
+            (
+                pop_from_vercel_id,
+                "sfo1::n8q4p-1700000000000-abcdef",
+                Some("SFO"),
+            ),
+        ];
+        for (parse, value, expected) in cases {
+            assert_eq!(parse(value).as_deref(), *expected, "{}", value);
+        }
+    }
+
+    #[test]
+    fn akamai_ignores_x_cache_edge_host() {
+        let resp = response(&[(
+            "X-Cache",
+            "TCP_HIT from a23-45-67-89.deploy.akamaitechnologies.com (AkamaiGHost/22.1.0-123) (-)",
+        )]);
+        assert_eq!(CdnProvider::Akamai.pop_from_headers(&resp), None);
+    }
+
+    #[test]
+    fn auto_detects_provider_from_headers() {
+        let cases = [
+            (
+                response(&[("cf-ray", "8a1b2c3d4e5f6789-NRT"), ("server", "cloudflare")]),
+                Some((CdnProvider::Cloudflare, "NRT")),
+            ),
+            (
+                response(&[
+                    ("x-amz-cf-pop", "NRT57-P2"),
+                    ("x-cache", "Hit from cloudfront"),
+                ]),
+                Some((CdnProvider::Cloudfront, "NRT")),
+            ),
+            (
+                response(&[("x-served-by", "cache-nrt-rjtf7700042-NRT")]),
+                Some((CdnProvider::Fastly, "NRT")),
+            ),
+            (
+                response(&[(
+                    "x-vercel-id",
+                    "hnd1::iad1::8b9xk-1700000000000-0123456789ab",
+                )]),
+                Some((CdnProvider::Vercel, "HND")),
+            ),
+            (
+                response(&[("server", "BunnyCDN-JP1-1153")]),
+                Some((CdnProvider::Bunny, "JP1")),
+            ),
+            (response(&[("server", "nginx")]), None),
+        ];
+        for (resp, expected) in cases {
+            let detected = CdnProvider::Auto.pop_from_headers(&resp);
+            assert_eq!(
+                detected.as_ref().map(|(p, pop)| (*p, pop.as_str())),
+                expected,
+                "{:?}",
+                resp.headers
+            );
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! colo (POP) コードと所在地の対応表。
+//!
+//! Cloudflare のデータセンターを中心とした表 (`data/colos.csv`) をバイナリに埋め込み、
+//! `colo_metadata_path` で指定した同じ書式の CSV で追加・上書きできる。
+//! 1 行は `code,city,country,continent,latitude,longitude` で、`#` から始まる行は無視する。
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+const EMBEDDED: &str = include_str!("../data/colos.csv");
+
+static DB: OnceLock<HashMap<String, ColoInfo>> = OnceLock::new();
+
+/// 地球の平均半径 (km)
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// 緯度経度 (度)
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+pub struct GeoPoint {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl GeoPoint {
+    /// 大円距離 (haversine)
+    pub fn distance_km(&self, other: &GeoPoint) -> f64 {
+        let (lat1, lat2) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.longitude - self.longitude).to_radians();
+        let a = (dlat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (dlon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+}
+
+/// 1 つの colo の所在地
+#[derive(Debug, Serialize, Clone)]
+pub struct ColoInfo {
+    pub code: String,
+    pub city: String,
+    /// ISO 3166-1 alpha-2
+    pub country: String,
+    /// AF / AS / EU / NA / OC / SA
+    pub continent: String,
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+impl ColoInfo {
+    /// "Tokyo, JP"
+    pub fn place(&self) -> String {
+        format!("{}, {}", self.city, self.country)
+    }
+
+    pub fn point(&self) -> GeoPoint {
+        GeoPoint {
+            latitude: self.latitude,
+            longitude: self.longitude,
+        }
+    }
+}
+
+/// 埋め込みの表に上書きファイルを重ねて読み込む。起動時に 1 度だけ呼ぶ。
+pub fn init(override_path: Option<&str>) -> Result<()> {
+    let mut db = parse(EMBEDDED).context("Invalid embedded colo table")?;
+    if let Some(path) = override_path {
+        let source = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read colo metadata {}", path))?;
+        let overrides =
+            parse(&source).with_context(|| format!("Invalid colo metadata {}", path))?;
+        println!("Loaded {} colo(s) from {}", overrides.len(), path);
+        db.extend(overrides);
+    }
+    DB.set(db)
+        .map_err(|_| anyhow::anyhow!("colo metadata is already loaded"))
+}
+
+/// コードから所在地を引く。CloudFront の `NRT57-P2` のような
+/// 空港コード + 番号の形式は先頭 3 文字で引き直す。
+pub fn lookup(code: &str) -> Option<&'static ColoInfo> {
+    let db = DB.get_or_init(|| parse(EMBEDDED).unwrap_or_default());
+    let code = code.trim().to_ascii_uppercase();
+    db.get(&code).or_else(|| {
+        let prefix = code.get(..3)?;
+        let rest = &code[3..];
+        (prefix.chars().all(|c| c.is_ascii_alphabetic())
+            && rest.starts_with(|c: char| c.is_ascii_digit() || c == '-'))
+        .then(|| db.get(prefix))
+        .flatten()
+    })
+}
+
+/// "NRT (Tokyo, JP)"。表にないコードはそのまま返す。
+pub fn describe(code: &str) -> String {
+    match lookup(code) {
+        Some(info) => format!("{} ({})", code, info.place()),
+        None => code.to_string(),
+    }
+}
+
+fn parse(source: &str) -> Result<HashMap<String, ColoInfo>> {
+    let mut db = HashMap::new();
+    for (index, line) in source.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let info = parse_line(line).with_context(|| format!("line {}: {}", index + 1, line))?;
+        db.insert(info.code.clone(), info);
+    }
+    Ok(db)
+}
+
+fn parse_line(line: &str) -> Result<ColoInfo> {
+    let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+    let [code, city, country, continent, latitude, longitude] = fields[..] else {
+        anyhow::bail!("expected 6 fields, got {}", fields.len());
+    };
+    if code.is_empty() {
+        anyhow::bail!("empty code");
+    }
+    Ok(ColoInfo {
+        code: code.to_ascii_uppercase(),
+        city: city.to_string(),
+        country: country.to_ascii_uppercase(),
+        continent: continent.to_ascii_uppercase(),
+        latitude: latitude.parse().context("invalid latitude")?,
+        longitude: longitude.parse().context("invalid longitude")?,
+    })
+}
@@ -0,0 +1,371 @@
+//! レポートと通知文の言語別メッセージ。
+//!
+//! 固定の見出しは文字列で、語順が言語で変わる文は関数で持つ。
+//! 新しい言語は [`Locale`] に variant を足し、[`Messages`] を 1 つ書けばよい。
+
+use serde::{Deserialize, Serialize};
+
+/// 出力の言語
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Locale {
+    #[default]
+    Ja,
+    En,
+}
+
+impl Locale {
+    pub fn messages(self) -> &'static Messages {
+        match self {
+            Locale::Ja => &JA,
+            Locale::En => &EN,
+        }
+    }
+}
+
+/// 1 言語分のメッセージカタログ
+pub struct Messages {
+    // レポート (通知用)
+    pub report_title: &'static str,
+    pub period: &'static str,
+    pub range: fn(from: &str, to: &str) -> String,
+    pub summary: &'static str,
+    pub targets: &'static str,
+    pub target_count: fn(reported: usize, configured: usize) -> String,
+    pub overall_uptime: &'static str,
+    pub uptime: &'static str,
+    pub successful: fn(successful: usize, total: usize) -> String,
+    pub failures: &'static str,
+    pub incidents: &'static str,
+    pub more_incidents: fn(count: usize) -> String,
+    pub sample_loss: &'static str,
+    pub sample_range: fn(min_ms: u64, max_ms: u64) -> String,
+    pub phases: &'static str,
+    pub colo_summary: fn(transitions: usize, most_frequent: &str, unique: &str) -> String,
+    pub per_colo: &'static str,
+    pub colo_stats_detail:
+        fn(time: &str, time_share: f64, checks: usize, check_share: f64, rtt: &str) -> String,
+    pub transition_matrix: &'static str,
+    pub transition_count: fn(count: usize) -> String,
+    pub timeline: &'static str,
+    pub segment_detail: fn(duration: &str, checks: usize) -> String,
+    pub colo_mismatch: &'static str,
+    pub colo_mismatch_detail: fn(mismatches: usize, fallbacks: usize) -> String,
+    pub colo_missing: &'static str,
+    pub colo_missing_detail: fn(count: usize) -> String,
+    pub flapping: &'static str,
+    pub flap_period_count: fn(count: usize) -> String,
+    pub flap_period_detail: fn(transitions: usize, colos: &str) -> String,
+    pub unexpected_colos: &'static str,
+    pub unexpected_colo_time: fn(duration: &str, share: f64, colos: &str) -> String,
+    // レポート (コンソール)
+    pub console_summary: fn(reported: usize, configured: usize, uptime: f64) -> String,
+    pub name: &'static str,
+    pub console_rtt: fn(
+        min_ms: u64,
+        max_ms: u64,
+        avg: &str,
+        avg_threshold_ms: u64,
+        median_ms: f64,
+        p95: &str,
+        p95_threshold_ms: u64,
+    ) -> String,
+    pub colo_transitions: &'static str,
+    pub flapping_transitions: fn(count: usize) -> String,
+    pub most_frequent_colo: &'static str,
+    pub unique_colos: &'static str,
+    // 障害
+    pub ongoing: &'static str,
+    pub incident_detail: fn(duration: &str, failed_checks: usize, errors: &str) -> String,
+    pub incident_count: fn(count: usize) -> String,
+    // イベント
+    pub colo_digest_detail: fn(changes: usize, bounces: u32, duration: &str) -> String,
+    pub colo_flapping: &'static str,
+    pub colo_flapping_detail: fn(changes: u32, window: &str) -> String,
+    pub colo_flapping_stopped: &'static str,
+    pub colo_flapping_stopped_detail: fn(changes: u32, duration: &str) -> String,
+    pub unexpected_colo: &'static str,
+    pub unexpected_colo_outside: &'static str,
+    pub unexpected_colo_distance: fn(distance_km: u64, max_km: u64) -> String,
+    pub unexpected_colo_cleared: &'static str,
+    pub unexpected_colo_cleared_detail: fn(duration: &str) -> String,
+    pub down_detail: fn(failures: u32, since: &str, duration: &str) -> String,
+    pub recovered_detail: fn(downtime: &str, last_error: &str) -> String,
+    pub rtt_high: &'static str,
+    pub rtt_high_detail: fn(window: usize, median_ms: u64, trigger_ms: u64) -> String,
+    pub rtt_cleared: &'static str,
+    pub rtt_cleared_detail: fn(median_ms: u64, clear_ms: u64, duration: &str) -> String,
+    // メール件名
+    pub report_subject: &'static str,
+    /// アウトボックスの種別 ("colo change" など) を件名用の言葉にする
+    pub events_subject: fn(what: &str) -> String,
+    // 設定エラー
+    pub report_needs_output: &'static str,
+}
+
+static JA: Messages = Messages {
+    report_title: "📊 監視レポート",
+    period: "期間:",
+    range: |from, to| format!("{} ～ {}", from, to),
+    summary: "総合サマリー",
+    targets: "監視対象:",
+    target_count: |reported, configured| format!("{} / {} サイト", reported, configured),
+    overall_uptime: "全体の平均稼働率:",
+    uptime: "稼働率:",
+    successful: |successful, total| format!("({} / {} 成功)", successful, total),
+    failures: "失敗内訳:",
+    incidents: "障害:",
+    more_incidents: |count| format!("他 {} 件", count),
+    sample_loss: "サンプル損失率:",
+    sample_range: |min_ms, max_ms| format!("個別サンプル Min/Max: {}/{}ms", min_ms, max_ms),
+    phases: "フェーズ別 (Median/P95):",
+    colo_summary: |transitions, most_frequent, unique| {
+        format!(
+            "{}回遷移, 最頻出: {}, ユニーク: {}",
+            transitions, most_frequent, unique
+        )
+    },
+    per_colo: "Colo 別 (滞在時間, チェック数, RTT Median/P95):",
+    colo_stats_detail: |time, time_share, checks, check_share, rtt| {
+        format!(
+            "{} ({:.1}%), {}回 ({:.1}%), {}",
+            time, time_share, checks, check_share, rtt
+        )
+    },
+    transition_matrix: "Colo 遷移 (from→to):",
+    transition_count: |count| format!("{}回", count),
+    timeline: "Colo タイムライン:",
+    segment_detail: |duration, checks| format!("{}, {}回", duration, checks),
+    colo_mismatch: "⚠️ Colo不一致:",
+    colo_mismatch_detail: |mismatches, fallbacks| {
+        format!(
+            "{}回 (trace と cf-ray), cf-ray補完: {}回",
+            mismatches, fallbacks
+        )
+    },
+    colo_missing: "⚠️ Colo 不明:",
+    colo_missing_detail: |count| format!("{}回 (成功したが colo を取得できず)", count),
+    flapping: "Colo ばたつき:",
+    flap_period_count: |count| format!("{}回", count),
+    flap_period_detail: |transitions, colos| format!("{}回遷移: {}", transitions, colos),
+    unexpected_colos: "想定外 Colo:",
+    unexpected_colo_time: |duration, share, colos| {
+        format!("{} ({:.1}%): {}", duration, share, colos)
+    },
+    console_summary: |reported, configured, uptime| {
+        format!(
+            "総合サマリー: {} / {} サイト, 平均稼働率: {:.3}%",
+            reported, configured, uptime
+        )
+    },
+    name: "名前:",
+    console_rtt: |min_ms, max_ms, avg, avg_threshold_ms, median_ms, p95, p95_threshold_ms| {
+        format!(
+            "RTT - Min: {}ms, Max: {}ms, 平均: {} (閾値 {}ms), 中央値: {:.2}ms, P95: {} (閾値 {}ms)",
+            min_ms, max_ms, avg, avg_threshold_ms, median_ms, p95, p95_threshold_ms
+        )
+    },
+    colo_transitions: "Colo 遷移回数:",
+    flapping_transitions: |count| format!("(ばたつき中の {}回は別)", count),
+    most_frequent_colo: "最頻出 Colo:",
+    unique_colos: "ユニーク Colo:",
+    ongoing: "継続中",
+    incident_detail: |duration, failed_checks, errors| {
+        format!("{}, {}回失敗, {}", duration, failed_checks, errors)
+    },
+    incident_count: |count| format!("{}件", count),
+    colo_digest_detail: |changes, bounces, duration| {
+        format!("{}間に{}回変更 (戻り {}回)", duration, changes, bounces)
+    },
+    colo_flapping: "Colo ばたつき",
+    colo_flapping_detail: |changes, window| {
+        format!(
+            "直近 {} で{}回切り替わりました。収まるまで個別の変更は通知しません",
+            window, changes
+        )
+    },
+    colo_flapping_stopped: "ばたつき終息",
+    colo_flapping_stopped_detail: |changes, duration| {
+        format!("に落ち着きました ({}間に{}回切替)", duration, changes)
+    },
+    unexpected_colo: "想定外の Colo",
+    unexpected_colo_outside: "許可された範囲外",
+    unexpected_colo_distance: |distance_km, max_km| {
+        format!("プローブから {}km (上限 {}km)", distance_km, max_km)
+    },
+    unexpected_colo_cleared: "想定内の Colo に復帰",
+    unexpected_colo_cleared_detail: |duration| format!("(想定外だった時間: {})", duration),
+    down_detail: |failures, since, duration| {
+        format!("で{}回連続失敗 ({}～, {})", failures, since, duration)
+    },
+    recovered_detail: |downtime, last_error| {
+        format!("停止時間: {} (最後のエラー: {})", downtime, last_error)
+    },
+    rtt_high: "RTT上昇",
+    rtt_high_detail: |window, median_ms, trigger_ms| {
+        format!(
+            "直近{}回の中央値 {}ms (閾値 {}ms)",
+            window, median_ms, trigger_ms
+        )
+    },
+    rtt_cleared: "RTT回復",
+    rtt_cleared_detail: |median_ms, clear_ms, duration| {
+        format!(
+            "中央値 {}ms (解除閾値 {}ms, 継続時間: {})",
+            median_ms, clear_ms, duration
+        )
+    },
+    report_subject: "監視レポート",
+    events_subject: |what| {
+        match what {
+            "health change" => "死活状態の変化",
+            "colo change" => "Colo 変更",
+            "colo digest" => "Colo 変更のまとめ",
+            "unexpected colo" => "想定外の Colo",
+            other => other,
+        }
+        .to_string()
+    },
+    report_needs_output: "レポート機能が有効になっていますが、output_format が 'none' に設定されています。\nレポートを使用するには、output_format を 'json'、'jsonl' または 'sqlite' に設定してください。",
+};
+
+static EN: Messages = Messages {
+    report_title: "📊 Monitoring Report",
+    period: "Period:",
+    range: |from, to| format!("{} - {}", from, to),
+    summary: "Summary",
+    targets: "Targets:",
+    target_count: |reported, configured| format!("{} / {} sites", reported, configured),
+    overall_uptime: "Average uptime:",
+    uptime: "Uptime:",
+    successful: |successful, total| format!("({} / {} successful)", successful, total),
+    failures: "Failures:",
+    incidents: "Incidents:",
+    more_incidents: |count| format!("{} more", count),
+    sample_loss: "Sample loss:",
+    sample_range: |min_ms, max_ms| format!("individual samples min/max: {}/{}ms", min_ms, max_ms),
+    phases: "Phases (Median/P95):",
+    colo_summary: |transitions, most_frequent, unique| {
+        format!(
+            "{} transitions, most frequent: {}, unique: {}",
+            transitions, most_frequent, unique
+        )
+    },
+    per_colo: "Per colo (time, checks, RTT Median/P95):",
+    colo_stats_detail: |time, time_share, checks, check_share, rtt| {
+        format!(
+            "{} ({:.1}%), {} checks ({:.1}%), {}",
+            time, time_share, checks, check_share, rtt
+        )
+    },
+    transition_matrix: "Colo transitions (from→to):",
+    transition_count: |count| format!("×{}", count),
+    timeline: "Colo timeline:",
+    segment_detail: |duration, checks| match checks {
+        1 => format!("{}, 1 check", duration),
+        _ => format!("{}, {} checks", duration, checks),
+    },
+    colo_mismatch: "⚠️ Colo mismatches:",
+    colo_mismatch_detail: |mismatches, fallbacks| {
+        format!(
+            "{} (trace vs cf-ray), cf-ray fallbacks: {}",
+            mismatches, fallbacks
+        )
+    },
+    colo_missing: "⚠️ No colo:",
+    colo_missing_detail: |count| match count {
+        1 => "1 successful check without a colo".to_string(),
+        _ => format!("{} successful checks without a colo", count),
+    },
+    flapping: "Colo flapping:",
+    flap_period_count: |count| match count {
+        1 => "1 period".to_string(),
+        _ => format!("{} periods", count),
+    },
+    flap_period_detail: |transitions, colos| format!("{} transitions: {}", transitions, colos),
+    unexpected_colos: "Unexpected colos:",
+    unexpected_colo_time: |duration, share, colos| {
+        format!("{} ({:.1}%): {}", duration, share, colos)
+    },
+    console_summary: |reported, configured, uptime| {
+        format!(
+            "Summary: {} / {} sites, average uptime: {:.3}%",
+            reported, configured, uptime
+        )
+    },
+    name: "Name:",
+    console_rtt: |min_ms, max_ms, avg, avg_threshold_ms, median_ms, p95, p95_threshold_ms| {
+        format!(
+            "RTT - Min: {}ms, Max: {}ms, Avg: {} (thr: {}ms), Median: {:.2}ms, P95: {} (thr: {}ms)",
+            min_ms, max_ms, avg, avg_threshold_ms, median_ms, p95, p95_threshold_ms
+        )
+    },
+    colo_transitions: "Colo transitions:",
+    flapping_transitions: |count| format!("(+{} while flapping)", count),
+    most_frequent_colo: "Most frequent colo:",
+    unique_colos: "Unique colos:",
+    ongoing: "ongoing",
+    incident_detail: |duration, failed_checks, errors| {
+        format!("{}, {} failed checks, {}", duration, failed_checks, errors)
+    },
+    incident_count: |count| match count {
+        1 => "1 incident".to_string(),
+        _ => format!("{} incidents", count),
+    },
+    colo_digest_detail: |changes, bounces, duration| {
+        format!("{} changes in {} ({} bounces)", changes, duration, bounces)
+    },
+    colo_flapping: "COLO FLAPPING",
+    colo_flapping_detail: |changes, window| {
+        format!(
+            "{} changes in the last {}; individual colo changes are not notified until it settles",
+            changes, window
+        )
+    },
+    colo_flapping_stopped: "FLAPPING OVER",
+    colo_flapping_stopped_detail: |changes, duration| {
+        format!("settled ({} changes over {})", changes, duration)
+    },
+    unexpected_colo: "UNEXPECTED COLO",
+    unexpected_colo_outside: "outside the expected colos",
+    unexpected_colo_distance: |distance_km, max_km| {
+        format!("{} km from the probe (limit {} km)", distance_km, max_km)
+    },
+    unexpected_colo_cleared: "COLO EXPECTED AGAIN",
+    unexpected_colo_cleared_detail: |duration| format!("after {} on unexpected colos", duration),
+    down_detail: |failures, since, duration| {
+        format!(
+            "failed {} times in a row (since {}, {})",
+            failures, since, duration
+        )
+    },
+    recovered_detail: |downtime, last_error| {
+        format!("downtime: {} (last error: {})", downtime, last_error)
+    },
+    rtt_high: "RTT HIGH",
+    rtt_high_detail: |window, median_ms, trigger_ms| {
+        format!(
+            "median of the last {} checks {}ms (threshold {}ms)",
+            window, median_ms, trigger_ms
+        )
+    },
+    rtt_cleared: "RTT OK",
+    rtt_cleared_detail: |median_ms, clear_ms, duration| {
+        format!(
+            "median {}ms (clear threshold {}ms, lasted {})",
+            median_ms, clear_ms, duration
+        )
+    },
+    report_subject: "Monitoring report",
+    events_subject: |what| {
+        match what {
+            "health change" => "Health change",
+            "colo change" => "Colo change",
+            "colo digest" => "Colo digest",
+            "unexpected colo" => "Unexpected colo",
+            other => other,
+        }
+        .to_string()
+    },
+    report_needs_output: "Reporting is enabled but output_format is set to 'none'.\nSet output_format to 'json', 'jsonl' or 'sqlite' to use reports.",
+};
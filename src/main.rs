@@ -1,21 +1,43 @@
+mod cdn;
+mod colo;
+mod i18n;
+mod notify;
+mod outbox;
+mod probe;
+mod store;
+mod template;
+
 use anyhow::Result;
+use cdn::CdnProvider;
 use chrono::{DateTime, Duration as ChronoDuration, Local, Utc};
 use clap::Parser;
+use colo::GeoPoint;
 use colored::*;
 use config::{Config, File};
-use futures::stream::StreamExt;
-use humantime::parse_duration;
+use humantime::{format_duration, parse_duration};
+use i18n::Locale;
+use notify::{Event, Markup, Notifiers, Sink, SinkKind, SinkSettings, TargetRef};
+use outbox::Outbox;
+use probe::{
+    AddressFamily, ErrorKind, PhaseTimings, ProbeError, ProbeRequest, ProbeResponse, Prober,
+    Sampling,
+};
 use rand::{Rng, rng};
 use reqwest::Client;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use statistical::{mean, median};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::fs::{File as StdFile, OpenOptions};
 use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::sync::Semaphore;
-use tokio::time::{self, MissedTickBehavior};
+use template::Templates;
+use tokio::sync::{Mutex, Semaphore, watch};
+use tokio::task::JoinSet;
+use tokio::time;
 use url::Url;
 
 #[derive(Parser, Debug)]
@@ -30,6 +52,9 @@ struct Cli {
     until: Option<DateTime<Utc>>,
     #[arg(long)]
     dry_run: bool,
+    /// JSONL のチェック結果を `output_format = "sqlite"` のデータベースに取り込んで終了する
+    #[arg(long, value_name = "PATH")]
+    import_jsonl: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -37,7 +62,13 @@ struct ReportingSettings {
     enabled: bool,
     interval: String,
     output_to_console: bool,
-    output_to_misskey: bool,
+    /// コンソールに出すレポートのテンプレートファイル (未指定なら組み込みの書式)
+    #[serde(default)]
+    console_template: Option<String>,
+    /// `reports = true` の通知先にレポートを送る
+    #[serde(alias = "output_to_misskey")]
+    output_to_notifiers: bool,
+    /// 旧形式の `misskey_url` / `misskey_token` で作る通知先の公開範囲
     misskey_visibility: String,
     rtt_threshold_ms: u64,
     p95_rtt_threshold_ms: u64,
@@ -47,66 +78,1252 @@ struct ReportingSettings {
 
 #[derive(Debug, Deserialize)]
 struct Settings {
+    /// レポートと通知文の言語 (通知先ごとに `locale` で上書きできる)
+    #[serde(default)]
+    locale: Locale,
+    /// 旧形式の Misskey 設定 (`[[notifiers]]` に "misskey" がなければこれで作る)
+    #[serde(default)]
     misskey_url: String,
     misskey_token: Option<String>,
+    /// 通知先の一覧
+    #[serde(default)]
+    notifiers: Vec<SinkSettings>,
+    /// 旧形式のターゲット一覧 (全項目がグローバル設定に従う)
+    #[serde(default)]
     target_urls: Vec<String>,
+    /// ターゲットごとに設定を上書きできる形式
+    #[serde(default)]
+    targets: Vec<TargetSettings>,
+    /// 各ターゲットをどのアドレスファミリーで計測するか (ファミリーごとに 1 回ずつ計測)
+    #[serde(default = "default_address_families")]
+    address_families: Vec<AddressFamily>,
+    /// POP の取得方法 (cloudflare / cloudfront / fastly / akamai / bunny / vercel / auto)
+    #[serde(default)]
+    cdn_provider: CdnProvider,
     check_interval_seconds: u64,
     user_agent: String,
     request_timeout_seconds: u64,
     output_format: String,
     output_path: String,
     max_concurrent_checks: usize,
-    colo_change_notify_misskey: bool, // 即時通知の設定を分離
-    misskey_concurrent_notifications: usize,
+    /// 初回チェックを間隔内のランダムな位置に分散させる
+    #[serde(default = "default_true")]
+    spread_checks: bool,
+    /// 各チェックの実行時刻に加えるランダムな揺らぎ (±秒)
+    #[serde(default)]
+    check_jitter_seconds: u64,
+    /// 1 回のチェックで送るリクエスト数 (RTT は中央値を記録)
+    #[serde(default = "default_samples_per_check")]
+    samples_per_check: usize,
+    /// ウォームアップ後は同じ接続を使い回してサンプルを取る
+    #[serde(default)]
+    reuse_connection: bool,
+    /// `notify` 未指定のターゲットが全通知先に通知するか (colo 変更・ダウン/復旧・RTT のすべて)。既定は true
+    notify_by_default: Option<bool>,
+    /// `notify_by_default` の旧名 (非推奨)
+    colo_change_notify_misskey: Option<bool>,
+    /// 同じ系列の colo 変更を続けて通知しない時間
+    #[serde(default = "default_colo_change_cooldown")]
+    colo_change_cooldown: String,
+    /// 新しい colo を何回続けて観測したら変更とみなすか
+    #[serde(default = "default_colo_change_settle_checks")]
+    colo_change_settle_checks: u32,
+    /// 設定するとこの時間内の colo 変更を 1 通のまとめにして送る
+    colo_change_digest_window: Option<String>,
+    /// `colo_flap_window` の間にこの回数を超えて colo が変わったらばたつきとみなす (未設定なら無効)
+    colo_flap_threshold: Option<u32>,
+    #[serde(default = "default_colo_flap_window")]
+    colo_flap_window: String,
+    /// 組み込みの colo 所在地表に追加・上書きする CSV
+    colo_metadata_path: Option<String>,
+    /// このプローブの位置 (`max_colo_distance_km` の基準)
+    probe_location: Option<ProbeLocation>,
+    /// プローブの位置からこれより遠い colo を想定外とみなす
+    max_colo_distance_km: Option<f64>,
+    /// 何回連続で失敗したらダウンとみなして通知するか
+    #[serde(default = "default_down_after_failures")]
+    down_after_failures: u32,
+    /// 直近 `rtt_alert_window` 回の RTT 中央値がこの値以上になったら通知する (未設定なら無効)
+    rtt_alert_trigger_ms: Option<u64>,
+    /// 通知中に中央値がこの値以下へ戻ったら解除を通知する (未設定なら trigger の 80%)
+    rtt_alert_clear_ms: Option<u64>,
+    #[serde(default = "default_rtt_alert_window")]
+    rtt_alert_window: usize,
+    /// 全通知先合計の同時送信数
+    #[serde(alias = "misskey_concurrent_notifications")]
+    notification_concurrency: usize,
+    /// 送れないままこの時間が過ぎた通知はアウトボックスから捨てる
+    #[serde(default = "default_outbox_max_age")]
+    outbox_max_age: String,
+    /// 終了時に送信中の通知を待つ最大秒数
+    #[serde(default = "default_shutdown_grace_seconds")]
+    shutdown_grace_seconds: u64,
     reporting: ReportingSettings,
 }
+
+/// `probe_location` は colo コード ("NRT")、"緯度,経度" の文字列、`{ latitude, longitude }` のどれかで書ける
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+enum ProbeLocation {
+    Text(String),
+    Point(GeoPoint),
+}
+
+fn default_samples_per_check() -> usize {
+    1
+}
+
+fn default_down_after_failures() -> u32 {
+    3
+}
+
+fn default_rtt_alert_window() -> usize {
+    5
+}
+
+fn default_colo_change_cooldown() -> String {
+    "5m".to_string()
+}
+
+fn default_colo_change_settle_checks() -> u32 {
+    1
+}
+
+fn default_colo_flap_window() -> String {
+    "30m".to_string()
+}
+
+fn default_outbox_max_age() -> String {
+    "24h".to_string()
+}
+
+fn default_shutdown_grace_seconds() -> u64 {
+    10
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_address_families() -> Vec<AddressFamily> {
+    vec![AddressFamily::Any]
+}
+
+/// `expected_continents` に書ける大陸コード
+const CONTINENTS: [&str; 6] = ["AF", "AS", "EU", "NA", "OC", "SA"];
+
+/// プローブに足すヘッダを検査する。リクエストにそのまま書き込むので、改行などで
+/// 別のヘッダ行を作れないようにし、接続の管理に使うヘッダは上書きさせない。
+fn validate_headers(url: &str, headers: &BTreeMap<String, String>) -> Result<()> {
+    const MANAGED: [&str; 3] = ["connection", "content-length", "transfer-encoding"];
+    let mut seen: Vec<String> = Vec::new();
+    for (name, value) in headers {
+        let is_token = !name.is_empty()
+            && name
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !is_token {
+            anyhow::bail!("Invalid header name {:?} (target {})", name, url);
+        }
+        if value.bytes().any(|b| b == b'\r' || b == b'\n' || b == 0) {
+            anyhow::bail!(
+                "Header {} must not contain CR, LF or NUL (target {})",
+                name,
+                url
+            );
+        }
+        let lower = name.to_ascii_lowercase();
+        if MANAGED.contains(&lower.as_str()) {
+            anyhow::bail!(
+                "Header {} is managed by tracekey and cannot be set (target {})",
+                name,
+                url
+            );
+        }
+        if seen.contains(&lower) {
+            anyhow::bail!("Header {} is set more than once (target {})", name, url);
+        }
+        seen.push(lower);
+    }
+    Ok(())
+}
+
+/// `[[targets]]` の 1 エントリ。未指定の項目はグローバル設定を使う。
+#[derive(Debug, Deserialize, Clone, Default)]
+struct TargetSettings {
+    url: String,
+    name: Option<String>,
+    check_interval_seconds: Option<u64>,
+    request_timeout_seconds: Option<u64>,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    expected_colos: Option<Vec<String>>,
+    /// ISO 3166-1 alpha-2 の国コード
+    expected_countries: Option<Vec<String>>,
+    /// AF / AS / EU / NA / OC / SA
+    expected_continents: Option<Vec<String>>,
+    max_colo_distance_km: Option<f64>,
+    notify: Option<Vec<String>>,
+    address_families: Option<Vec<AddressFamily>>,
+    cdn_provider: Option<CdnProvider>,
+    samples_per_check: Option<usize>,
+    reuse_connection: Option<bool>,
+    down_after_failures: Option<u32>,
+    rtt_alert_trigger_ms: Option<u64>,
+    rtt_alert_clear_ms: Option<u64>,
+    rtt_alert_window: Option<usize>,
+    colo_change_cooldown: Option<String>,
+    colo_change_settle_checks: Option<u32>,
+    /// "0s" でこのターゲットだけまとめを無効にできる
+    colo_change_digest_window: Option<String>,
+    colo_flap_threshold: Option<u32>,
+    colo_flap_window: Option<String>,
+}
+
+/// グローバル設定で補完済みのターゲット設定
+#[derive(Debug, Clone)]
+struct Target {
+    url: String,
+    name: Option<String>,
+    check_interval: Duration,
+    request_timeout: Duration,
+    headers: Vec<(String, String)>,
+    colo_expectation: ColoExpectation,
+    notify: Vec<String>,
+    address_families: Vec<AddressFamily>,
+    cdn_provider: CdnProvider,
+    samples_per_check: usize,
+    reuse_connection: bool,
+    down_after_failures: u32,
+    rtt_alert: Option<RttAlert>,
+    colo_change: ColoChangePolicy,
+}
+
+/// colo 変更の通知方針
+#[derive(Debug, Clone, Copy)]
+struct ColoChangePolicy {
+    cooldown: ChronoDuration,
+    settle_checks: u32,
+    /// `Some` なら変更をすぐには送らず、この時間ごとにまとめて送る
+    digest_window: Option<ChronoDuration>,
+    flap: Option<ColoFlap>,
+}
+
+/// 想定する colo の範囲。どれも設定しなければ制限なし
+#[derive(Debug, Clone, Default)]
+struct ColoExpectation {
+    /// 許可する colo / 国 / 大陸 (どれか 1 つに当たれば想定内)
+    colos: Vec<String>,
+    countries: Vec<String>,
+    continents: Vec<String>,
+    /// プローブの位置と、そこからの最大距離 (km)
+    max_distance: Option<(GeoPoint, f64)>,
+}
+
+/// 想定外と判定した理由
+#[derive(Debug, Clone, Copy)]
+struct UnexpectedColo {
+    /// 許可する colo / 国 / 大陸のどれにも当たらない
+    not_allowed: bool,
+    /// プローブの位置からの距離 (所在地が分かるときだけ)
+    distance_km: Option<f64>,
+}
+
+impl ColoExpectation {
+    fn has_allow_list(&self) -> bool {
+        !(self.colos.is_empty() && self.countries.is_empty() && self.continents.is_empty())
+    }
+
+    /// 想定内なら `None`。所在地表にない colo は距離では判定せず、国や大陸にも当たらない扱いにする。
+    fn check(&self, colo: &str) -> Option<UnexpectedColo> {
+        let info = colo::lookup(colo);
+        let not_allowed = self.has_allow_list()
+            && !self.colos.iter().any(|c| c == colo)
+            && !info.is_some_and(|info| {
+                self.countries.contains(&info.country) || self.continents.contains(&info.continent)
+            });
+        let distance_km = self
+            .max_distance
+            .zip(info)
+            .map(|((probe, _), info)| probe.distance_km(&info.point()));
+        let too_far = self
+            .max_distance
+            .zip(distance_km)
+            .is_some_and(|((_, max), distance)| distance > max);
+        (not_allowed || too_far).then_some(UnexpectedColo {
+            not_allowed,
+            distance_km,
+        })
+    }
+
+    /// ログ用の説明 ("NRT, KIX, JP, AS, <= 1000 km")
+    fn describe(&self) -> String {
+        let mut parts: Vec<String> = self
+            .colos
+            .iter()
+            .chain(&self.countries)
+            .chain(&self.continents)
+            .cloned()
+            .collect();
+        if let Some((_, max)) = self.max_distance {
+            parts.push(format!("<= {:.0} km", max));
+        }
+        parts.join(", ")
+    }
+}
+
+/// `window` の間に `threshold` 回を超えて colo が変わったらばたつきとみなす
+#[derive(Debug, Clone, Copy)]
+struct ColoFlap {
+    threshold: u32,
+    window: ChronoDuration,
+}
+
+/// ローリング RTT アラートの閾値。trigger と clear を分けて通知のばたつきを防ぐ。
+#[derive(Debug, Clone, Copy)]
+struct RttAlert {
+    trigger_ms: u64,
+    clear_ms: u64,
+    window: usize,
+}
+
+impl Target {
+    fn notifies(&self, channel: &str) -> bool {
+        self.notify.iter().any(|c| c == channel)
+    }
+
+    fn is_expected_colo(&self, colo: &str) -> bool {
+        self.colo_expectation.check(colo).is_none()
+    }
+
+    /// 通知に使う表示名 (name があればそれ、なければホスト名)
+    fn display_name(&self) -> String {
+        if let Some(name) = &self.name {
+            name.clone()
+        } else if let Ok(parsed_url) = self.url.parse::<Url>() {
+            parsed_url.host_str().unwrap_or(&self.url).to_string()
+        } else {
+            self.url.clone()
+        }
+    }
+
+    /// 通知イベントに載せる系列の情報
+    fn event_ref(&self, address_family: AddressFamily) -> TargetRef {
+        TargetRef {
+            name: self.display_name(),
+            url: self.url.clone(),
+            address_family,
+        }
+    }
+}
+
+impl Settings {
+    /// `output_format = "sqlite"` なら colo 変更検知の状態も同じデータベースに置く
+    fn state_database(&self) -> Option<String> {
+        (self.output_format == "sqlite").then(|| self.output_path.clone())
+    }
+
+    /// `probe_location` を座標にする。colo コードは所在地表から引く。
+    fn probe_point(&self) -> Result<Option<GeoPoint>> {
+        match &self.probe_location {
+            Some(ProbeLocation::Point(point)) => Ok(Some(*point)),
+            Some(ProbeLocation::Text(text)) => {
+                if let Some((latitude, longitude)) = text.split_once(',') {
+                    let parse = |v: &str| {
+                        v.trim().parse::<f64>().map_err(|_| {
+                            anyhow::anyhow!("Invalid coordinates '{}' in probe_location", text)
+                        })
+                    };
+                    return Ok(Some(GeoPoint {
+                        latitude: parse(latitude)?,
+                        longitude: parse(longitude)?,
+                    }));
+                }
+                colo::lookup(text)
+                    .map(|info| Some(info.point()))
+                    .ok_or_else(|| anyhow::anyhow!("Unknown colo '{}' in probe_location", text))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// `[[notifiers]]` に旧形式の Misskey 設定を加えた通知先の一覧。
+    /// 旧形式の "misskey" はトークンが空なら無効な通知先として残す (`notify = ["misskey"]` を壊さないため)。
+    fn resolve_sinks(&self) -> Result<Vec<Sink>> {
+        let mut sink_settings = self.notifiers.clone();
+        if !sink_settings.iter().any(|s| s.name == "misskey") {
+            let token = self.misskey_token.clone().unwrap_or_default();
+            sink_settings.push(SinkSettings {
+                name: "misskey".to_string(),
+                enabled: !token.is_empty(),
+                reports: true,
+                max_attempts: None,
+                retry_initial_delay_ms: None,
+                event_template: None,
+                report_template: None,
+                locale: None,
+                kind: SinkKind::Misskey {
+                    url: self.misskey_url.clone(),
+                    token,
+                    visibility: self.reporting.misskey_visibility.clone(),
+                },
+            });
+        }
+
+        let mut sinks: Vec<Sink> = Vec::new();
+        for s in &sink_settings {
+            if sinks.iter().any(|existing| existing.name == s.name) {
+                anyhow::bail!("Notifier {} is configured more than once", s.name);
+            }
+            // 無効な旧形式の Misskey は URL 未設定でもよい
+            if !s.enabled && s.name == "misskey" && self.misskey_url.is_empty() {
+                continue;
+            }
+            sinks.push(
+                Sink::from_settings(s, self.locale)
+                    .map_err(|e| anyhow::anyhow!("Invalid notifier {}: {:#}", s.name, e))?,
+            );
+        }
+        Ok(sinks)
+    }
+
+    /// `notify` のないターゲットが全 sink に通知するか (旧 `colo_change_notify_misskey` も見る)
+    fn notifies_by_default(&self) -> bool {
+        self.notify_by_default
+            .or(self.colo_change_notify_misskey)
+            .unwrap_or(true)
+    }
+
+    /// `[[targets]]` と旧形式の `target_urls` をまとめ、未指定の項目をグローバル設定で埋める。
+    fn resolve_targets(&self, sink_names: &[&str]) -> Result<Vec<Target>> {
+        let legacy = self.target_urls.iter().map(|url| TargetSettings {
+            url: url.clone(),
+            ..Default::default()
+        });
+
+        let probe_point = self.probe_point()?;
+        let mut targets: Vec<Target> = Vec::new();
+        for t in self.targets.iter().cloned().chain(legacy) {
+            let parsed =
+                Url::parse(&t.url).map_err(|e| anyhow::anyhow!("Invalid URL {}: {}", t.url, e))?;
+            match parsed.scheme() {
+                "http" | "https" => {}
+                other => anyhow::bail!("Unsupported URL scheme '{}' for {}", other, t.url),
+            }
+            if targets.iter().any(|existing| existing.url == t.url) {
+                anyhow::bail!("Target {} is configured more than once", t.url);
+            }
+
+            let check_interval = Duration::from_secs(
+                t.check_interval_seconds
+                    .unwrap_or(self.check_interval_seconds),
+            );
+            if check_interval.is_zero() {
+                anyhow::bail!("Check interval cannot be 0 (target {})", t.url);
+            }
+            let request_timeout = Duration::from_secs(
+                t.request_timeout_seconds
+                    .unwrap_or(self.request_timeout_seconds),
+            );
+            if request_timeout.is_zero() {
+                anyhow::bail!("request_timeout_seconds cannot be 0 (target {})", t.url);
+            }
+            validate_headers(&t.url, &t.headers)?;
+            let address_families = t
+                .address_families
+                .unwrap_or_else(|| self.address_families.clone());
+            if address_families.is_empty() {
+                anyhow::bail!("address_families must not be empty (target {})", t.url);
+            }
+            let samples_per_check = t.samples_per_check.unwrap_or(self.samples_per_check);
+            if samples_per_check == 0 {
+                anyhow::bail!("samples_per_check cannot be 0 (target {})", t.url);
+            }
+            let down_after_failures = t.down_after_failures.unwrap_or(self.down_after_failures);
+            if down_after_failures == 0 {
+                anyhow::bail!("down_after_failures cannot be 0 (target {})", t.url);
+            }
+            let rtt_alert = match t.rtt_alert_trigger_ms.or(self.rtt_alert_trigger_ms) {
+                Some(trigger_ms) => {
+                    let clear_ms = t
+                        .rtt_alert_clear_ms
+                        .or(self.rtt_alert_clear_ms)
+                        .unwrap_or(trigger_ms * 4 / 5);
+                    if clear_ms > trigger_ms {
+                        anyhow::bail!(
+                            "rtt_alert_clear_ms ({}) must not exceed rtt_alert_trigger_ms ({}) (target {})",
+                            clear_ms,
+                            trigger_ms,
+                            t.url
+                        );
+                    }
+                    let window = t.rtt_alert_window.unwrap_or(self.rtt_alert_window);
+                    if window == 0 {
+                        anyhow::bail!("rtt_alert_window cannot be 0 (target {})", t.url);
+                    }
+                    Some(RttAlert {
+                        trigger_ms,
+                        clear_ms,
+                        window,
+                    })
+                }
+                None => None,
+            };
+            let parse = |key: &str, value: &str| {
+                parse_duration(value)
+                    .ok()
+                    .and_then(|d| ChronoDuration::from_std(d).ok())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Invalid {} '{}' (target {})", key, value, t.url)
+                    })
+            };
+            let settle_checks = t
+                .colo_change_settle_checks
+                .unwrap_or(self.colo_change_settle_checks);
+            if settle_checks == 0 {
+                anyhow::bail!("colo_change_settle_checks cannot be 0 (target {})", t.url);
+            }
+            let colo_change = ColoChangePolicy {
+                cooldown: parse(
+                    "colo_change_cooldown",
+                    t.colo_change_cooldown
+                        .as_deref()
+                        .unwrap_or(&self.colo_change_cooldown),
+                )?,
+                settle_checks,
+                digest_window: match t
+                    .colo_change_digest_window
+                    .as_deref()
+                    .or(self.colo_change_digest_window.as_deref())
+                {
+                    Some(window) => {
+                        Some(parse("colo_change_digest_window", window)?).filter(|w| !w.is_zero())
+                    }
+                    None => None,
+                },
+                flap: match t.colo_flap_threshold.or(self.colo_flap_threshold) {
+                    Some(0) => {
+                        anyhow::bail!("colo_flap_threshold cannot be 0 (target {})", t.url);
+                    }
+                    Some(threshold) => {
+                        let window = parse(
+                            "colo_flap_window",
+                            t.colo_flap_window
+                                .as_deref()
+                                .unwrap_or(&self.colo_flap_window),
+                        )?;
+                        if window.is_zero() {
+                            anyhow::bail!("colo_flap_window cannot be 0 (target {})", t.url);
+                        }
+                        Some(ColoFlap { threshold, window })
+                    }
+                    None => None,
+                },
+            };
+            let upper = |codes: Option<Vec<String>>| -> Vec<String> {
+                codes
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|c| c.trim().to_ascii_uppercase())
+                    .collect()
+            };
+            let continents = upper(t.expected_continents);
+            if let Some(unknown) = continents
+                .iter()
+                .find(|c| !CONTINENTS.contains(&c.as_str()))
+            {
+                anyhow::bail!(
+                    "Unknown continent '{}' in expected_continents (target {}; use {})",
+                    unknown,
+                    t.url,
+                    CONTINENTS.join(", ")
+                );
+            }
+            let max_distance = match t.max_colo_distance_km.or(self.max_colo_distance_km) {
+                Some(max) => {
+                    let Some(probe) = probe_point else {
+                        anyhow::bail!(
+                            "max_colo_distance_km needs probe_location to be set (target {})",
+                            t.url
+                        );
+                    };
+                    if !max.is_finite() || max <= 0.0 {
+                        anyhow::bail!("max_colo_distance_km must be positive (target {})", t.url);
+                    }
+                    Some((probe, max))
+                }
+                None => None,
+            };
+            let colo_expectation = ColoExpectation {
+                colos: upper(t.expected_colos),
+                countries: upper(t.expected_countries),
+                continents,
+                max_distance,
+            };
+            let notify = t.notify.unwrap_or_else(|| {
+                if self.notifies_by_default() {
+                    sink_names.iter().map(|n| n.to_string()).collect()
+                } else {
+                    Vec::new()
+                }
+            });
+            if let Some(unknown) = notify.iter().find(|c| !sink_names.contains(&c.as_str())) {
+                anyhow::bail!(
+                    "Unknown notification channel '{}' for {} (available: {})",
+                    unknown,
+                    t.url,
+                    sink_names.join(", ")
+                );
+            }
+
+            targets.push(Target {
+                url: t.url,
+                name: t.name,
+                check_interval,
+                request_timeout,
+                headers: t.headers.into_iter().collect(),
+                colo_expectation,
+                notify,
+                address_families,
+                cdn_provider: t.cdn_provider.unwrap_or(self.cdn_provider),
+                samples_per_check,
+                reuse_connection: t.reuse_connection.unwrap_or(self.reuse_connection),
+                down_after_failures,
+                rtt_alert,
+                colo_change,
+            });
+        }
+
+        if targets.is_empty() {
+            anyhow::bail!("No targets configured (set target_urls or [[targets]])");
+        }
+        Ok(targets)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct CheckResult {
     timestamp: DateTime<Utc>,
     url: String,
+    #[serde(default, skip_serializing_if = "AddressFamily::is_any")]
+    address_family: AddressFamily,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    edge_ip: Option<IpAddr>,
+    /// colo を取得したプロバイダ (auto の場合は判別結果)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    provider: Option<CdnProvider>,
     success: bool,
     rtt_millis: Option<u64>,
     error: Option<String>,
+    /// 失敗の分類 (`error` と対になる)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_kind: Option<ErrorKind>,
+    colo: Option<String>,
+    /// `cf-ray` ヘッダ末尾から得た colo (trace の `colo=` との突き合わせ用)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    colo_ray: Option<String>,
+    /// trace と `cf-ray` の colo が食い違った
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    colo_mismatch: bool,
+    /// 成功したのに colo をどこからも取れなかった
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    colo_missing: bool,
+    /// trace が 4xx で拒否され、colo を `cf-ray` から取ったときのステータス
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace_blocked_status: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    trace: Option<TraceInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    phases: Option<PhaseTimings>,
+    /// 複数サンプルを取った場合の集計 (`rtt_millis` は中央値)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    samples: Option<SampleSummary>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct SampleSummary {
+    count: usize,
+    failures: usize,
+    min_millis: u64,
+    median_millis: u64,
+    max_millis: u64,
+    /// 失敗したサンプルの割合 (0.0 - 1.0)
+    loss_ratio: f64,
+}
+
+/// `/cdn-cgi/trace` のレスポンスを解析したもの。
+/// 既知のキーは型付きフィールドに、それ以外は `extra` に格納する。
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct TraceInfo {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    fl: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    h: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ip: Option<IpAddr>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ts: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    visit_scheme: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    uag: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     colo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sliver: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    http: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    loc: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sni: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    warp: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    gateway: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    rbi: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kex: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    extra: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct LastSuccessState {
     url: String,
+    #[serde(default, skip_serializing_if = "AddressFamily::is_any")]
+    address_family: AddressFamily,
     colo: Option<String>,
     timestamp: DateTime<Utc>,
     #[serde(default = "Utc::now")]
     last_notification_timestamp: DateTime<Utc>,
+    /// `colo` と違う colo が続いている間の候補と観測回数
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending_colo: Option<String>,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pending_count: u32,
+    /// まとめて送る前の colo 変更
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<ColoDigest>,
+    /// ばたつき判定の窓に入っている colo 変更
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    recent_changes: VecDeque<ColoChangeRecord>,
+    /// ばたついている間は開始時刻などが入る
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    flapping: Option<Flapping>,
+    /// 想定外の colo に載っている間はその開始時刻
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    unexpected_since: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ColoChangeRecord {
+    at: DateTime<Utc>,
+    from: String,
+    to: String,
+}
+
+impl ColoChangeRecord {
+    /// 変更の前後の colo を、まだ含まれていなければ順に加える
+    fn collect_colos(&self, colos: &mut Vec<String>) {
+        for colo in [&self.from, &self.to] {
+            if !colos.contains(colo) {
+                colos.push(colo.clone());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Flapping {
+    since: DateTime<Utc>,
+    /// ばたつき中の colo 変更回数 (開始時点の窓の分を含む)
+    changes: u32,
+}
+
+/// ばたつきの開始/終了
+enum FlapEvent {
+    Started {
+        changes: u32,
+        colos: Vec<String>,
+        since: DateTime<Utc>,
+    },
+    Stopped {
+        changes: u32,
+        since: DateTime<Utc>,
+    },
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+impl LastSuccessState {
+    fn new(url: &str, address_family: AddressFamily, now: DateTime<Utc>) -> Self {
+        Self {
+            url: url.to_string(),
+            address_family,
+            colo: None,
+            timestamp: now,
+            last_notification_timestamp: now,
+            pending_colo: None,
+            pending_count: 0,
+            digest: None,
+            recent_changes: VecDeque::new(),
+            flapping: None,
+            unexpected_since: None,
+        }
+    }
+
+    /// 観測した colo を反映する。同じ新しい colo を `settle_checks` 回続けて見たときだけ
+    /// 変更とみなし、それまでの colo を返す。
+    fn observe_colo(&mut self, colo: &str, settle_checks: u32) -> Option<String> {
+        let Some(current) = self.colo.as_deref() else {
+            self.colo = Some(colo.to_string());
+            return None;
+        };
+        if current == colo {
+            self.pending_colo = None;
+            self.pending_count = 0;
+            return None;
+        }
+        if self.pending_colo.as_deref() == Some(colo) {
+            self.pending_count += 1;
+        } else {
+            self.pending_colo = Some(colo.to_string());
+            self.pending_count = 1;
+        }
+        if self.pending_count < settle_checks {
+            return None;
+        }
+        self.pending_colo = None;
+        self.pending_count = 0;
+        self.colo.replace(colo.to_string())
+    }
+
+    /// 前回の通知から `cooldown` を過ぎていれば通知時刻を `now` に進めて true を返す。
+    fn take_notification_slot(&mut self, cooldown: ChronoDuration, now: DateTime<Utc>) -> bool {
+        if now - self.last_notification_timestamp <= cooldown {
+            return false;
+        }
+        self.last_notification_timestamp = now;
+        true
+    }
+
+    /// 確定した colo 変更を窓に積み、ばたつきが始まった/収まったときだけイベントを返す。
+    /// 窓の間に 1 度も変更がなければ収まったとみなす。
+    fn track_flapping(
+        &mut self,
+        change: Option<(&str, &str)>,
+        flap: &ColoFlap,
+        at: DateTime<Utc>,
+    ) -> Option<FlapEvent> {
+        if let Some((from, to)) = change {
+            self.recent_changes.push_back(ColoChangeRecord {
+                at,
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+            if let Some(flapping) = &mut self.flapping {
+                flapping.changes += 1;
+            }
+        }
+        while self
+            .recent_changes
+            .front()
+            .is_some_and(|c| at - c.at >= flap.window)
+        {
+            self.recent_changes.pop_front();
+        }
+
+        match &self.flapping {
+            None if self.recent_changes.len() > flap.threshold as usize => {
+                let since = self.recent_changes.front().map_or(at, |c| c.at);
+                let mut colos: Vec<String> = Vec::new();
+                for c in &self.recent_changes {
+                    c.collect_colos(&mut colos);
+                }
+                let changes = self.recent_changes.len() as u32;
+                self.flapping = Some(Flapping { since, changes });
+                Some(FlapEvent::Started {
+                    changes,
+                    colos,
+                    since,
+                })
+            }
+            Some(flapping) if self.recent_changes.is_empty() => {
+                let event = FlapEvent::Stopped {
+                    changes: flapping.changes,
+                    since: flapping.since,
+                };
+                self.flapping = None;
+                Some(event)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// digest モードでまとめている colo 変更
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct ColoDigest {
+    since: DateTime<Utc>,
+    from_colo: String,
+    /// 切り替わった先の colo (順番どおり、最後が現在の colo)
+    path: Vec<String>,
+    /// 窓の中で一度いた colo へ戻った回数
+    bounces: u32,
+    rtt_millis: Option<u64>,
+}
+
+impl ColoDigest {
+    fn new(from_colo: String, since: DateTime<Utc>) -> Self {
+        Self {
+            since,
+            from_colo,
+            path: Vec::new(),
+            bounces: 0,
+            rtt_millis: None,
+        }
+    }
+
+    fn push(&mut self, colo: &str, rtt_millis: Option<u64>) {
+        if colo == self.from_colo || self.path.iter().any(|c| c == colo) {
+            self.bounces += 1;
+        }
+        self.path.push(colo.to_string());
+        self.rtt_millis = rtt_millis;
+    }
+
+    fn into_event(self, target: &Target, family: AddressFamily, now: DateTime<Utc>) -> Event {
+        let to_colo = self.path.last().cloned().unwrap_or_default();
+        Event::ColoDigest {
+            target: target.event_ref(family),
+            unexpected: !target.is_expected_colo(&to_colo),
+            from_colo: self.from_colo,
+            to_colo,
+            path: self.path,
+            bounces: self.bounces,
+            rtt_millis: self.rtt_millis,
+            since: self.since,
+            duration: now - self.since,
+        }
+    }
+}
+
+/// ターゲットの死活状態
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum HealthStatus {
+    #[default]
+    Up,
+    /// 失敗しているがダウンの閾値には達していない
+    Degraded,
+    Down,
+    /// ダウンから復旧した直後 (次の成功で `Up` に戻る)
+    Recovered,
 }
 
-#[derive(Debug)]
+impl std::fmt::Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            HealthStatus::Up => "up",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Down => "down",
+            HealthStatus::Recovered => "recovered",
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct TargetHealthState {
+    url: String,
+    #[serde(default, skip_serializing_if = "AddressFamily::is_any")]
+    address_family: AddressFamily,
+    status: HealthStatus,
+    consecutive_failures: u32,
+    /// 現在の連続失敗が始まった時刻
+    failing_since: Option<DateTime<Utc>>,
+    last_error_kind: Option<ErrorKind>,
+    /// ローリング RTT 用の直近の RTT (成功したチェックのみ)
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    recent_rtts: VecDeque<u64>,
+    /// RTT アラートを出している間は発報時刻が入る
+    #[serde(default)]
+    rtt_alert_since: Option<DateTime<Utc>>,
+    updated: DateTime<Utc>,
+}
+
+/// 通知すべき状態遷移
+enum HealthEvent {
+    Down {
+        failures: u32,
+        kind: ErrorKind,
+        error: Option<String>,
+        since: DateTime<Utc>,
+    },
+    Recovered {
+        kind: Option<ErrorKind>,
+        since: DateTime<Utc>,
+    },
+    RttHigh {
+        median: u64,
+        alert: RttAlert,
+    },
+    RttCleared {
+        median: u64,
+        alert: RttAlert,
+        since: DateTime<Utc>,
+    },
+}
+
+impl TargetHealthState {
+    fn new(url: &str, address_family: AddressFamily, now: DateTime<Utc>) -> Self {
+        Self {
+            url: url.to_string(),
+            address_family,
+            status: HealthStatus::Up,
+            consecutive_failures: 0,
+            failing_since: None,
+            last_error_kind: None,
+            recent_rtts: VecDeque::new(),
+            rtt_alert_since: None,
+            updated: now,
+        }
+    }
+
+    /// チェック結果を反映し、ダウン/復旧に切り替わったときだけイベントを返す。
+    fn apply(&mut self, result: &CheckResult, down_after_failures: u32) -> Option<HealthEvent> {
+        self.updated = result.timestamp;
+        if result.success {
+            let event = (self.status == HealthStatus::Down).then(|| HealthEvent::Recovered {
+                kind: self.last_error_kind,
+                since: self.failing_since.unwrap_or(result.timestamp),
+            });
+            self.status = if event.is_some() {
+                HealthStatus::Recovered
+            } else {
+                HealthStatus::Up
+            };
+            self.consecutive_failures = 0;
+            self.failing_since = None;
+            return event;
+        }
+
+        let kind = result.error_kind.unwrap_or(ErrorKind::Other);
+        self.consecutive_failures += 1;
+        self.last_error_kind = Some(kind);
+        let since = *self.failing_since.get_or_insert(result.timestamp);
+        if self.status == HealthStatus::Down {
+            return None;
+        }
+        if self.consecutive_failures >= down_after_failures {
+            self.status = HealthStatus::Down;
+            Some(HealthEvent::Down {
+                failures: self.consecutive_failures,
+                kind,
+                error: result.error.clone(),
+                since,
+            })
+        } else {
+            self.status = HealthStatus::Degraded;
+            None
+        }
+    }
+
+    /// 成功したチェックの RTT を窓に積み、中央値が閾値をまたいだときだけイベントを返す。
+    /// 窓が埋まるまでは判定しない。
+    fn apply_rtt(&mut self, result: &CheckResult, alert: &RttAlert) -> Option<HealthEvent> {
+        let rtt = result.rtt_millis.filter(|_| result.success)?;
+        self.recent_rtts.push_back(rtt);
+        while self.recent_rtts.len() > alert.window {
+            self.recent_rtts.pop_front();
+        }
+        if self.recent_rtts.len() < alert.window {
+            return None;
+        }
+
+        // レポートと同じ中央値 (偶数個なら中央 2 つの平均) で判定する
+        let window: Vec<u64> = self.recent_rtts.iter().copied().collect();
+        let exact = median_of(&window);
+        let median = exact.round() as u64;
+        match self.rtt_alert_since {
+            None if exact >= alert.trigger_ms as f64 => {
+                self.rtt_alert_since = Some(result.timestamp);
+                Some(HealthEvent::RttHigh {
+                    median,
+                    alert: *alert,
+                })
+            }
+            Some(since) if exact <= alert.clear_ms as f64 => {
+                self.rtt_alert_since = None;
+                Some(HealthEvent::RttCleared {
+                    median,
+                    alert: *alert,
+                    since,
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// RTT の分布。5 つの値はすべて同じ母集団 (チェックごとの RTT、複数サンプルなら中央値) から求める。
+#[derive(Debug, Serialize)]
 struct RttStats {
     min: u64,
     max: u64,
     mean: f64,
     median: f64,
     p95: f64,
+    /// 複数サンプルを取ったチェックを含むとき、個々のサンプルの最小・最大
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_min: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sample_max: Option<u64>,
+}
+
+/// 1 つの colo に載っていた間の統計
+#[derive(Debug, Serialize)]
+struct ColoStats {
+    colo: String,
+    checks: usize,
+    /// colo を取得できたチェックに占める割合 (%)
+    check_share: f64,
+    /// 滞在時間 ([`colo_dwell`] の合計)
+    #[serde(rename = "time_secs", serialize_with = "notify::serialize_secs")]
+    time: ChronoDuration,
+    /// colo を観測できていた時間に占める割合 (%)
+    time_share: f64,
+    rtt_stats: Option<RttStats>,
 }
 
-#[derive(Debug)]
+/// フェーズ別の RTT 統計。観測値のないフェーズは `None`。
+#[derive(Debug, Serialize)]
+struct PhaseStats {
+    dns: Option<RttStats>,
+    connect: Option<RttStats>,
+    tls: Option<RttStats>,
+    ttfb: Option<RttStats>,
+    body: Option<RttStats>,
+}
+
+/// 連続した失敗チェックをひとまとめにした障害。
+#[derive(Debug, Serialize)]
+struct Incident {
+    /// 最初に失敗したチェックの時刻
+    start: DateTime<Utc>,
+    /// 復旧を確認したチェックの時刻。期間の終わりまで失敗が続いていれば `None`
+    end: Option<DateTime<Utc>>,
+    /// 復旧までの時間 (継続中ならレポート期間の終わりまで)
+    #[serde(rename = "duration_secs", serialize_with = "notify::serialize_secs")]
+    duration: ChronoDuration,
+    failed_checks: usize,
+    error_kinds: BTreeMap<ErrorKind, usize>,
+    colo_before: Option<String>,
+    colo_after: Option<String>,
+}
+
+/// 同じ colo が続いていた区間
+#[derive(Debug, Serialize)]
+struct ColoSegment {
+    colo: String,
+    start: DateTime<Utc>,
+    /// 次の colo を初めて観測した時刻。期間の終わりまで続いていれば `None`
+    end: Option<DateTime<Utc>>,
+    /// 区間内の各チェックの滞在時間 ([`colo_dwell`]) の合計。colo 別の `time` と同じ数え方なので、
+    /// 監視が止まっていた間は含まず `end - start` より短くなることがある
+    #[serde(rename = "duration_secs", serialize_with = "notify::serialize_secs")]
+    duration: ChronoDuration,
+    checks: usize,
+}
+
+/// colo がばたついていた期間
+#[derive(Debug, Serialize)]
+struct FlapPeriod {
+    start: DateTime<Utc>,
+    /// 最後の変更から判定の窓が過ぎた時刻。期間の終わりまでに収まっていなければ `None`
+    end: Option<DateTime<Utc>>,
+    transitions: usize,
+    colos: Vec<String>,
+}
+
+/// 障害の集計。障害がなければ各値は `None`。
+#[derive(Debug, Serialize)]
+struct IncidentStats {
+    incidents: Vec<Incident>,
+    /// 復旧済み障害の平均復旧時間
+    #[serde(rename = "mttr_secs", serialize_with = "notify::serialize_opt_secs")]
+    mttr: Option<ChronoDuration>,
+    /// 正常稼働時間の合計 / 障害件数
+    #[serde(rename = "mtbf_secs", serialize_with = "notify::serialize_opt_secs")]
+    mtbf: Option<ChronoDuration>,
+    #[serde(
+        rename = "longest_outage_secs",
+        serialize_with = "notify::serialize_opt_secs"
+    )]
+    longest_outage: Option<ChronoDuration>,
+}
+
+#[derive(Debug, Serialize)]
 struct TargetStats {
     url: String,
+    name: Option<String>,
+    address_family: AddressFamily,
     total_checks: usize,
     successful_checks: usize,
     uptime: f64,
     rtt_stats: RttStats,
+    /// サンプル単位の失敗率 (%)。複数サンプルを取っていない場合は `None`
+    sample_loss: Option<f64>,
+    phase_stats: PhaseStats,
+    /// 滞在時間の長い順
+    colo_stats: Vec<ColoStats>,
     unique_colos: Vec<String>,
+    /// ばたつき期間の外での遷移回数
     colo_transitions: usize,
+    /// ばたつき期間中の遷移回数 (`flapping_periods` の `transitions` の合計)
+    flapping_transitions: usize,
+    /// from → to → 回数
+    colo_transition_matrix: BTreeMap<String, BTreeMap<String, usize>>,
+    /// 時系列順
+    colo_timeline: Vec<ColoSegment>,
     most_frequent_colo: String,
+    /// trace に colo がなく `cf-ray` から補った回数
+    colo_fallbacks: usize,
+    /// trace と `cf-ray` の colo が食い違った回数
+    colo_mismatches: usize,
+    /// 成功したのに colo を取れなかった回数
+    colo_missing: usize,
+    /// `colo_flap_threshold` を設定したターゲットだけ判定する
+    flapping_periods: Vec<FlapPeriod>,
+    /// 想定外の colo にいた時間 (`colo_stats` の滞在時間の合計)
+    #[serde(
+        rename = "unexpected_colo_secs",
+        serialize_with = "notify::serialize_secs"
+    )]
+    unexpected_colo_time: ChronoDuration,
+    /// colo を観測できていた時間のうち想定外の colo にいた割合 (%)
+    unexpected_colo_share: f64,
+    unexpected_colos: Vec<String>,
+    /// 失敗の分類ごとの件数
+    failure_breakdown: BTreeMap<ErrorKind, usize>,
+    #[serde(rename = "incidents")]
+    incident_stats: IncidentStats,
 }
 
-#[derive(Debug)]
+/// テンプレートにはこの構造体をそのまま渡す (`target_stats` は `targets` という名前になる)
+#[derive(Debug, Serialize)]
 struct Report {
     since: DateTime<Utc>,
     until: DateTime<Utc>,
     configured_targets: usize,
     reported_targets: usize,
     overall_uptime: f64,
+    #[serde(rename = "targets")]
     target_stats: Vec<TargetStats>,
 }
 
@@ -114,32 +1331,68 @@ struct Report {
 async fn main() -> Result<()> {
     let cli = Cli::parse();
     let settings = load_settings()?;
+    colo::init(settings.colo_metadata_path.as_deref())?;
+    if settings.colo_change_notify_misskey.is_some() {
+        eprintln!(
+            "colo_change_notify_misskey is deprecated; use notify_by_default (it applies to every alert and notifier)"
+        );
+    }
+
+    if let Some(database) = settings.state_database() {
+        tokio::task::spawn_blocking(move || store::check_database(&database)).await??;
+    }
+
+    if let Some(path) = &cli.import_jsonl {
+        return import_jsonl(&settings, path).await;
+    }
 
     if settings.reporting.p95_rtt_threshold_ms < settings.reporting.rtt_threshold_ms {
         anyhow::bail!("p95_rtt_threshold_ms must be greater than or equal to rtt_threshold_ms");
     }
 
-    // URL バリデーション
-    for url in &settings.target_urls {
-        let parsed = Url::parse(url).map_err(|e| anyhow::anyhow!("Invalid URL {}: {}", url, e))?;
-        match parsed.scheme() {
-            "http" | "https" => {}
-            other => anyhow::bail!("Unsupported URL scheme '{}' for {}", other, url),
-        }
-    }
+    // 通知先と URL などターゲット設定のバリデーション
+    let sinks = settings.resolve_sinks()?;
+    let sink_names: Vec<&str> = sinks.iter().map(|s| s.name.as_str()).collect();
+    let targets = settings.resolve_targets(&sink_names)?;
 
     if settings.reporting.enabled && settings.output_format == "none" {
-        anyhow::bail!(
-            "レポート機能が有効になっていますが、output_format が 'none' に設定されています。\nレポートを使用するには、output_format を 'json' または 'jsonl' に設定してください。"
-        );
+        anyhow::bail!(settings.locale.messages().report_needs_output);
     }
     let client = Client::builder()
         .user_agent(&settings.user_agent)
         .timeout(Duration::from_secs(settings.request_timeout_seconds))
         .build()?;
+    let prober = Prober::new(&settings.user_agent)?;
+    if settings.notification_concurrency == 0 {
+        anyhow::bail!("notification_concurrency cannot be 0");
+    }
+    let outbox_max_age = ChronoDuration::from_std(parse_duration(&settings.outbox_max_age)?)
+        .map_err(|_| anyhow::anyhow!("outbox_max_age is too large"))?;
+    // --report はレポートを送るだけなので、監視中のアウトボックスには触れない
+    let outbox = if cli.report {
+        Outbox::in_memory()
+    } else {
+        Outbox::load().await?
+    };
+    let notifiers = Arc::new(Notifiers::new(
+        client,
+        sinks,
+        settings.notification_concurrency,
+        outbox,
+        outbox_max_age,
+    ));
+
+    let console_template = Templates::load(None, settings.reporting.console_template.as_deref())?;
 
     if cli.report {
-        run_report_once(&settings, &cli, &client).await?;
+        run_report_once(
+            &settings,
+            &targets,
+            &cli,
+            &notifiers,
+            console_template.as_ref(),
+        )
+        .await?;
         return Ok(());
     }
 
@@ -148,22 +1401,39 @@ async fn main() -> Result<()> {
         settings.user_agent
     );
 
-    let check_interval_duration = Duration::from_secs(settings.check_interval_seconds);
-    if check_interval_duration.is_zero() {
-        anyhow::bail!("Check interval cannot be 0");
-    }
     if settings.max_concurrent_checks == 0 {
         anyhow::bail!("max_concurrent_checks cannot be 0");
     }
-    if settings.misskey_concurrent_notifications == 0 {
-        anyhow::bail!("misskey_concurrent_notifications cannot be 0");
-    }
-    let misskey_semaphore = Arc::new(Semaphore::new(settings.misskey_concurrent_notifications));
-    let mut check_interval = time::interval(check_interval_duration);
-    check_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    let settings = Arc::new(settings);
+    let ctx = Arc::new(CheckContext {
+        settings: settings.clone(),
+        prober,
+        notifiers,
+        check_semaphore: Semaphore::new(settings.max_concurrent_checks),
+        state_lock: Mutex::new(()),
+    });
 
-    let report_interval_duration = parse_duration(&settings.reporting.interval)?;
-    if report_interval_duration.is_zero() {
+    // 前回の残りも含め、アウトボックスの通知を送り続ける
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let outbox_worker = tokio::spawn(ctx.notifiers.clone().run_outbox(
+        shutdown_rx,
+        Duration::from_secs(settings.shutdown_grace_seconds),
+    ));
+
+    // ターゲットごとに独立したタイマーで回す
+    let mut schedules = JoinSet::new();
+    for target in &targets {
+        schedules.spawn(run_target_schedule(ctx.clone(), target.clone()));
+    }
+    if targets
+        .iter()
+        .any(|t| t.colo_change.digest_window.is_some())
+    {
+        schedules.spawn(run_colo_digest_schedule(ctx.clone(), targets.clone()));
+    }
+
+    let report_interval_duration = parse_duration(&settings.reporting.interval)?;
+    if report_interval_duration.is_zero() {
         anyhow::bail!("Reporting interval cannot be 0");
     }
     let mut report_interval = time::interval(report_interval_duration);
@@ -173,15 +1443,10 @@ async fn main() -> Result<()> {
 
     loop {
         tokio::select! {
-            _ = check_interval.tick() => {
-                if let Err(e) = run_checks_once(&settings, &client, misskey_semaphore.clone()).await {
-                    eprintln!("Scheduled check failed: {}", e);
-                }
-            },
             _ = report_interval.tick() => {
                 if settings.reporting.enabled {
                     println!("Generating periodic report...");
-                    if let Err(e) = run_report_once(&settings, &cli, &client).await {
+                    if let Err(e) = run_report_once(&settings, &targets, &cli, &ctx.notifiers, console_template.as_ref()).await {
                         eprintln!("Failed to generate periodic report: {}", e);
                     }
                 }
@@ -192,190 +1457,527 @@ async fn main() -> Result<()> {
             }
         }
     }
+    schedules.abort_all();
+    let _ = shutdown_tx.send(true);
+    if let Err(e) = outbox_worker.await {
+        eprintln!("Notification outbox worker failed: {}", e);
+    }
 
     println!("Tracekey monitoring stopped.");
     Ok(())
 }
 
-async fn run_checks_once(
-    settings: &Settings,
-    client: &Client,
-    misskey_semaphore: Arc<Semaphore>,
-) -> Result<()> {
-    println!("Running check...");
+/// 監視ループ全体で共有する状態
+struct CheckContext {
+    settings: Arc<Settings>,
+    prober: Prober,
+    notifiers: Arc<Notifiers>,
+    /// 全ターゲット合計の同時リクエスト数の上限 (`max_concurrent_checks`)
+    check_semaphore: Semaphore,
+    /// 状態ファイルと結果ログの読み書きを直列化する
+    state_lock: Mutex<()>,
+}
 
-    let mut prev_states: HashMap<String, LastSuccessState> = match load_last_success_states().await
-    {
-        Ok(states) => states,
-        Err(e) => {
-            eprintln!("Failed to load previous success states: {}", e);
-            Vec::new()
+/// 1 ターゲットを自身の間隔で回し続ける。
+/// 初回は間隔内のランダムな位置に分散させ、各回に `check_jitter_seconds` の揺らぎを加える。
+async fn run_target_schedule(ctx: Arc<CheckContext>, target: Target) {
+    let interval = target.check_interval;
+    let jitter = Duration::from_secs(ctx.settings.check_jitter_seconds).min(interval / 2);
+
+    let offset = if ctx.settings.spread_checks {
+        interval.mul_f64(rng().random_range(0.0..1.0))
+    } else {
+        Duration::ZERO
+    };
+    let mut next = time::Instant::now() + offset;
+    loop {
+        // 揺らぎは基準時刻に対して加えるので、回を重ねてもずれが蓄積しない
+        let at = if jitter.is_zero() {
+            next
+        } else {
+            let shift = jitter.mul_f64(rng().random_range(0.0..=2.0));
+            (next + shift).checked_sub(jitter).unwrap_or(next)
+        };
+        time::sleep_until(at).await;
+
+        if let Err(e) = run_checks_once(&ctx, std::slice::from_ref(&target)).await {
+            eprintln!("Scheduled check for {} failed: {}", target.url, e);
+        }
+
+        next += interval;
+        // 処理が間隔を超えて遅れた場合は取りこぼした回をスキップ
+        let now = time::Instant::now();
+        while next <= now {
+            next += interval;
         }
     }
-    .into_iter()
-    .map(|state| (state.url.clone(), state))
-    .collect();
+}
 
-    let tasks = settings.target_urls.iter().cloned().map(|url| {
-        let client = client.clone();
-        async move {
-            let res = get_cloudflare_trace(&client, &url).await;
-            (url, res)
+/// colo 変更のまとめを確認しに行く間隔
+const COLO_DIGEST_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+async fn run_colo_digest_schedule(ctx: Arc<CheckContext>, targets: Vec<Target>) {
+    let mut interval = time::interval(COLO_DIGEST_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = flush_colo_digests(&ctx, &targets).await {
+            eprintln!("Failed to send colo change digests: {}", e);
         }
-    });
-    let outcomes = futures::stream::iter(tasks)
-        .buffer_unordered(settings.max_concurrent_checks)
-        .collect::<Vec<_>>()
+    }
+}
+
+/// 窓を過ぎた colo 変更のまとめを通知キューに積む。
+/// まとめを無効にしたターゲットに残っていたものもここで送る。
+async fn flush_colo_digests(ctx: &CheckContext, targets: &[Target]) -> Result<()> {
+    let _state_guard = ctx.state_lock.lock().await;
+    let now = Utc::now();
+    let mut states = load_last_success_states(&ctx.settings).await?;
+    let mut events = Vec::new();
+    let mut flushed = Vec::new();
+    for state in &mut states {
+        let Some(target) = targets.iter().find(|t| t.url == state.url) else {
+            continue;
+        };
+        let due = state.digest.as_ref().is_some_and(|digest| {
+            target
+                .colo_change
+                .digest_window
+                .is_none_or(|window| now - digest.since >= window)
+        });
+        if let Some(digest) = state.digest.take_if(|_| due) {
+            events.push(digest.into_event(target, state.address_family, now));
+            state.last_notification_timestamp = now;
+            flushed.push(state.clone());
+        }
+    }
+    if events.is_empty() {
+        return Ok(());
+    }
+
+    save_last_success_states(&ctx.settings, &flushed).await?;
+    let routes = |sink: &str, event: &Event| {
+        targets
+            .iter()
+            .any(|t| t.url == event.target().url && t.notifies(sink))
+    };
+    ctx.notifiers
+        .enqueue_events("colo digest", now, &events, routes)
         .await;
+    Ok(())
+}
+
+async fn run_checks_once(ctx: &CheckContext, targets: &[Target]) -> Result<()> {
+    let settings = &ctx.settings;
+    println!(
+        "Running check for {}...",
+        targets
+            .iter()
+            .map(|t| t.url.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let tasks = targets.iter().flat_map(|target| {
+        target
+            .address_families
+            .iter()
+            .map(move |&family| async move {
+                let res = match ctx.check_semaphore.acquire().await {
+                    Ok(_permit) => probe_target(&ctx.prober, target, family).await,
+                    Err(e) => Err(e.into()),
+                };
+                (target.url.clone(), family, res)
+            })
+    });
+    let outcomes = futures::future::join_all(tasks).await;
+
+    // 状態の読み込みから書き戻しまでを他ターゲットのチェックと排他にする
+    let _state_guard = ctx.state_lock.lock().await;
+
+    let mut prev_states: HashMap<(String, AddressFamily), LastSuccessState> =
+        match load_last_success_states(settings).await {
+            Ok(states) => states,
+            Err(e) => {
+                eprintln!("Failed to load previous success states: {}", e);
+                Vec::new()
+            }
+        }
+        .into_iter()
+        .map(|state| ((state.url.clone(), state.address_family), state))
+        .collect();
 
     let mut results: Vec<CheckResult> = Vec::new();
     for outcome in outcomes {
         match outcome {
-            (_url, Ok(result)) => {
+            (_url, _family, Ok(result)) => {
+                let trace = result.trace.as_ref();
                 println!(
-                    "Result for {}: colo={}, rtt={}ms",
-                    result.url,
+                    "Result for {}: colo={}, edge={}, rtt={}ms, ip={}, loc={}, http={}, tls={}",
+                    series_label(&result.url, result.address_family),
                     result.colo.as_deref().unwrap_or("N/A"),
+                    result
+                        .edge_ip
+                        .map_or_else(|| "N/A".to_string(), |ip| ip.to_string()),
                     result.rtt_millis.unwrap_or(0),
+                    trace
+                        .and_then(|t| t.ip)
+                        .map_or_else(|| "N/A".to_string(), |ip| ip.to_string()),
+                    trace.and_then(|t| t.loc.as_deref()).unwrap_or("N/A"),
+                    trace.and_then(|t| t.http.as_deref()).unwrap_or("N/A"),
+                    trace.and_then(|t| t.tls.as_deref()).unwrap_or("N/A"),
                 );
                 results.push(result);
             }
-            (url, Err(e)) => {
-                eprintln!("Failed to get trace for {}: {}", url, e);
+            (url, family, Err(e)) => {
+                eprintln!(
+                    "Failed to get trace for {} [{}]: {:#}",
+                    series_label(&url, family),
+                    ProbeError::kind_of(&e),
+                    e
+                );
                 results.push(CheckResult {
                     timestamp: Utc::now(),
                     url,
+                    address_family: family,
+                    edge_ip: None,
+                    provider: None,
                     success: false,
                     rtt_millis: None,
-                    error: Some(e.to_string()),
+                    error: Some(format!("{:#}", e)),
+                    error_kind: Some(ProbeError::kind_of(&e)),
                     colo: None,
+                    colo_ray: None,
+                    colo_mismatch: false,
+                    colo_missing: false,
+                    trace_blocked_status: None,
+                    trace: None,
+                    phases: None,
+                    samples: None,
                 });
             }
         }
     }
-    // Colo変更検知とMisskey投稿
-    let mut colo_change_messages = Vec::new();
+    let targets_by_url: HashMap<&str, &Target> =
+        targets.iter().map(|t| (t.url.as_str(), t)).collect();
+
+    // 想定外の colo を警告
     for result in &results {
-        if result.success {
-            if let Some(prev_state) = prev_states.get_mut(&result.url) {
-                if let (Some(curr_colo), Some(prev_colo)) =
-                    (result.colo.as_ref(), prev_state.colo.as_ref())
-                {
-                    if curr_colo != prev_colo {
-                        let now = Utc::now();
-                        if now - prev_state.last_notification_timestamp > ChronoDuration::minutes(5)
-                        {
-                            let domain = if let Ok(parsed_url) = result.url.parse::<url::Url>() {
-                                parsed_url.host_str().unwrap_or(&result.url).to_string()
-                            } else {
-                                result.url.clone()
-                            };
-                            let (rtt_color, rtt_text, rtt_unit): (&str, String, &str) =
-                                match result.rtt_millis {
-                                    Some(ms @ 0..=299) => ("3a3", ms.to_string(), "ms"), // green
-                                    Some(ms @ 300..=499) => ("991", ms.to_string(), "ms"), // yellow
-                                    Some(ms @ 500..=999) => ("c52", ms.to_string(), "ms"), // orange
-                                    Some(ms) => ("b22", ms.to_string(), "ms"),           // red
-                                    None => ("999", "N/A".into(), ""), // gray for no data
-                                };
-                            let message = format!(
-                                "<small>`{}`</small>→`{}` $[border.color=0000,radius=10 $[bg.color={} $[fg.color=fff  {}<small>{}</small> ]]] ?[{}]({})",
-                                prev_colo,
-                                curr_colo,
-                                rtt_color,
-                                rtt_text,
-                                rtt_unit,
-                                domain,
-                                result.url
-                            );
-                            colo_change_messages.push(message);
-                            prev_state.last_notification_timestamp = now;
-                        }
-                    }
-                }
-            }
+        if let Some(target) = targets_by_url.get(result.url.as_str())
+            && let Some(colo) = &result.colo
+            && let Some(unexpected) = target.colo_expectation.check(colo)
+        {
+            eprintln!(
+                "Unexpected colo {}{} for {} (expected: {})",
+                colo::describe(colo),
+                unexpected
+                    .distance_km
+                    .map_or_else(String::new, |d| format!(", {:.0} km away", d)),
+                series_label(&result.url, result.address_family),
+                target.colo_expectation.describe()
+            );
         }
     }
 
-    if !colo_change_messages.is_empty() && settings.colo_change_notify_misskey {
-        if let Some(token) = &settings.misskey_token {
-            if !token.is_empty() {
-                let message = colo_change_messages.join("\n");
-                let misskey_client = client.clone();
-                let misskey_url = settings.misskey_url.clone();
-                let misskey_token = token.clone();
-                let misskey_visibility = settings.reporting.misskey_visibility.clone();
-                let sem_clone = misskey_semaphore.clone();
-
-                tokio::spawn(async move {
-                    let permit = match sem_clone.acquire_owned().await {
-                        Ok(p) => p,
-                        Err(_) => {
-                            eprintln!(
-                                "Misskey notification semaphore closed, skipping notification."
-                            );
-                            return;
-                        }
-                    };
-                    let _permit = permit;
-                    println!("Posting colo change to Misskey...");
-                    match post_to_misskey(
-                        &misskey_client,
-                        &misskey_url,
-                        &misskey_token,
-                        &message,
-                        &misskey_visibility,
-                    )
-                    .await
-                    {
-                        Ok(_) => println!("Colo change posted to Misskey successfully."),
-                        Err(e) => eprintln!("Failed to post colo change to Misskey: {}", e),
-                    }
+    // Colo変更検知と通知 (settle 回数に達した変更だけを、cooldown または digest に従って送る)
+    let now = Utc::now();
+    let mut colo_change_events = Vec::new();
+    let mut unexpected_colo_events = Vec::new();
+    let mut success_states: Vec<LastSuccessState> = Vec::new();
+    for result in results.iter().filter(|r| r.success) {
+        let Some(target) = targets_by_url.get(result.url.as_str()) else {
+            continue;
+        };
+        let label = series_label(&result.url, result.address_family);
+        let state = prev_states
+            .entry((result.url.clone(), result.address_family))
+            .or_insert_with(|| LastSuccessState::new(&result.url, result.address_family, now));
+        state.timestamp = result.timestamp;
+        let Some(curr_colo) = result.colo.as_deref() else {
+            success_states.push(state.clone());
+            continue;
+        };
+        let change = state.observe_colo(curr_colo, target.colo_change.settle_checks);
+        let flap_event = target.colo_change.flap.and_then(|flap| {
+            state.track_flapping(
+                change.as_deref().map(|prev| (prev, curr_colo)),
+                &flap,
+                result.timestamp,
+            )
+        });
+        match &flap_event {
+            Some(FlapEvent::Started { changes, .. }) => eprintln!(
+                "{} is flapping between colos ({} changes); pausing colo change notes",
+                label, changes
+            ),
+            Some(FlapEvent::Stopped { changes, .. }) => println!(
+                "{} stopped flapping and settled on {} after {} changes",
+                label, curr_colo, changes
+            ),
+            None => {}
+        }
+        // 想定外の colo に入ったときと抜けたときだけ通知する (判定は確定した colo で行う)
+        let settled_colo = state.colo.clone().unwrap_or_else(|| curr_colo.to_string());
+        let unexpected_event = match (
+            target.colo_expectation.check(&settled_colo),
+            state.unexpected_since,
+        ) {
+            (Some(unexpected), None) => {
+                state.unexpected_since = Some(result.timestamp);
+                Some(Event::UnexpectedColo {
+                    target: target.event_ref(result.address_family),
+                    colo: settled_colo,
+                    not_allowed: unexpected.not_allowed,
+                    distance_km: unexpected.distance_km.map(|d| d.round() as u64),
+                    max_distance_km: target
+                        .colo_expectation
+                        .max_distance
+                        .map(|(_, max)| max.round() as u64),
+                    rtt_millis: result.rtt_millis,
+                })
+            }
+            (None, Some(since)) => {
+                state.unexpected_since = None;
+                let duration = result.timestamp - since;
+                println!(
+                    "{} is back on an expected colo ({}) after {}",
+                    label,
+                    settled_colo,
+                    format_chrono_duration(duration)
+                );
+                Some(Event::UnexpectedColoCleared {
+                    target: target.event_ref(result.address_family),
+                    colo: settled_colo,
+                    since,
+                    duration,
+                })
+            }
+            _ => None,
+        };
+        if target.notify.is_empty() {
+            success_states.push(state.clone());
+            continue;
+        }
+        let target_ref = target.event_ref(result.address_family);
+        match flap_event {
+            Some(FlapEvent::Started {
+                changes,
+                colos,
+                since,
+            }) => colo_change_events.push(Event::ColoFlapping {
+                target: target_ref.clone(),
+                changes,
+                colos,
+                window: target
+                    .colo_change
+                    .flap
+                    .map_or(ChronoDuration::zero(), |f| f.window),
+                since,
+            }),
+            Some(FlapEvent::Stopped { changes, since }) => {
+                colo_change_events.push(Event::ColoFlappingStopped {
+                    target: target_ref.clone(),
+                    colo: curr_colo.to_string(),
+                    changes,
+                    since,
+                    duration: result.timestamp - since,
+                })
+            }
+            None => {}
+        }
+        // ばたついている間は開始/終了だけを通知する
+        let mut change_queued = false;
+        if let Some(prev_colo) = change
+            && state.flapping.is_none()
+        {
+            if target.colo_change.digest_window.is_some() {
+                state
+                    .digest
+                    .get_or_insert_with(|| ColoDigest::new(prev_colo, now))
+                    .push(curr_colo, result.rtt_millis);
+            } else if state.take_notification_slot(target.colo_change.cooldown, now) {
+                colo_change_events.push(Event::ColoChange {
+                    target: target_ref,
+                    prev_colo,
+                    unexpected: !target.is_expected_colo(curr_colo),
+                    curr_colo: curr_colo.to_string(),
+                    rtt_millis: result.rtt_millis,
                 });
+                change_queued = true;
+            } else {
+                println!(
+                    "Colo change {} -> {} for {} is within the cooldown; not notifying",
+                    prev_colo, curr_colo, label
+                );
             }
         }
+        // 想定外の colo への移動は colo 変更の通知 (unexpected 付き) で伝わるので重ねて送らない
+        match unexpected_event {
+            Some(Event::UnexpectedColo { .. }) if change_queued => {}
+            event => unexpected_colo_events.extend(event),
+        }
+        success_states.push(state.clone());
     }
 
-    // 最後の成功状態を更新
-    let success_states: Vec<LastSuccessState> = results
-        .iter()
-        .filter(|r| r.success)
-        .map(|r| {
-            let last_notification_timestamp = prev_states
-                .get(&r.url)
-                .map(|s| s.last_notification_timestamp)
-                .unwrap_or_else(Utc::now);
-            LastSuccessState {
-                url: r.url.clone(),
-                colo: r.colo.clone(),
-                timestamp: r.timestamp,
-                last_notification_timestamp,
+    // 死活状態の遷移 (up → degraded → down → recovered) とダウン/復旧通知
+    let mut health_states: HashMap<(String, AddressFamily), TargetHealthState> =
+        match load_target_health_states().await {
+            Ok(states) => states,
+            Err(e) => {
+                eprintln!("Failed to load target health states: {}", e);
+                Vec::new()
             }
-        })
+        }
+        .into_iter()
+        .map(|state| ((state.url.clone(), state.address_family), state))
         .collect();
-
-    if !success_states.is_empty() {
-        if let Err(e) = save_last_success_states(&success_states).await {
-            eprintln!("Failed to save last success states: {}", e);
+    let mut health_events = Vec::new();
+    for result in &results {
+        let Some(target) = targets_by_url.get(result.url.as_str()) else {
+            continue;
+        };
+        let state = health_states
+            .entry((result.url.clone(), result.address_family))
+            .or_insert_with(|| {
+                TargetHealthState::new(&result.url, result.address_family, result.timestamp)
+            });
+        let events: Vec<HealthEvent> = state
+            .apply(result, target.down_after_failures)
+            .into_iter()
+            .chain(
+                target
+                    .rtt_alert
+                    .as_ref()
+                    .and_then(|alert| state.apply_rtt(result, alert)),
+            )
+            .collect();
+        let label = series_label(&result.url, result.address_family);
+        let target_ref = target.event_ref(result.address_family);
+        for event in events {
+            let event = match event {
+                HealthEvent::Down {
+                    failures,
+                    kind,
+                    error,
+                    since,
+                } => {
+                    let duration = result.timestamp - since;
+                    eprintln!(
+                        "{} is DOWN: {} consecutive failures ({}), failing for {}",
+                        label,
+                        failures,
+                        kind,
+                        format_chrono_duration(duration)
+                    );
+                    Event::Down {
+                        target: target_ref.clone(),
+                        kind,
+                        error,
+                        failures,
+                        since,
+                        duration,
+                    }
+                }
+                HealthEvent::Recovered { kind, since } => {
+                    let duration = result.timestamp - since;
+                    println!(
+                        "{} RECOVERED after {}",
+                        label,
+                        format_chrono_duration(duration)
+                    );
+                    Event::Recovered {
+                        target: target_ref.clone(),
+                        kind,
+                        since,
+                        duration,
+                    }
+                }
+                HealthEvent::RttHigh { median, alert } => {
+                    eprintln!(
+                        "{} RTT high: median of last {} checks is {}ms (trigger: {}ms)",
+                        label, alert.window, median, alert.trigger_ms
+                    );
+                    Event::RttHigh {
+                        target: target_ref.clone(),
+                        median_millis: median,
+                        window: alert.window,
+                        trigger_ms: alert.trigger_ms,
+                    }
+                }
+                HealthEvent::RttCleared {
+                    median,
+                    alert,
+                    since,
+                } => {
+                    let duration = result.timestamp - since;
+                    println!(
+                        "{} RTT back to normal: median {}ms (clear: {}ms) after {}",
+                        label,
+                        median,
+                        alert.clear_ms,
+                        format_chrono_duration(duration)
+                    );
+                    Event::RttCleared {
+                        target: target_ref.clone(),
+                        median_millis: median,
+                        clear_ms: alert.clear_ms,
+                        since,
+                        duration,
+                    }
+                }
+            };
+            health_events.push(event);
         }
     }
+    if let Err(e) = save_target_health_states(health_states.into_values().collect::<Vec<_>>()).await
+    {
+        eprintln!("Failed to save target health states: {}", e);
+    }
+
+    // 各イベントはターゲットの `notify` に含まれる通知先にだけ送る
+    let observed = results.iter().map(|r| r.timestamp).max().unwrap_or(now);
+    let routes = |sink: &str, event: &Event| {
+        targets_by_url
+            .get(event.target().url.as_str())
+            .is_some_and(|t| t.notifies(sink))
+    };
+    ctx.notifiers
+        .enqueue_events("health change", observed, &health_events, routes)
+        .await;
+    ctx.notifiers
+        .enqueue_events("colo change", observed, &colo_change_events, routes)
+        .await;
+    ctx.notifiers
+        .enqueue_events("unexpected colo", observed, &unexpected_colo_events, routes)
+        .await;
+
+    // 最後の成功状態を更新
+    if !success_states.is_empty()
+        && let Err(e) = save_last_success_states(settings, &success_states).await
+    {
+        eprintln!("Failed to save last success states: {}", e);
+    }
 
-    if !results.is_empty() {
-        if let Err(e) = write_results(
+    if !results.is_empty()
+        && let Err(e) = write_results(
             settings.output_path.clone(),
             settings.output_format.clone(),
             results,
         )
         .await
-        {
-            eprintln!("Failed to write results: {}", e);
-        }
+    {
+        eprintln!("Failed to write results: {}", e);
     }
 
     Ok(())
 }
 
-async fn run_report_once(settings: &Settings, cli: &Cli, client: &Client) -> Result<()> {
+async fn run_report_once(
+    settings: &Settings,
+    targets: &[Target],
+    cli: &Cli,
+    notifiers: &Notifiers,
+    console_template: Option<&Templates>,
+) -> Result<()> {
     let until = cli.until.unwrap_or_else(Utc::now);
     let since = if let Some(s) = cli.since {
         s
@@ -408,7 +2010,7 @@ async fn run_report_once(settings: &Settings, cli: &Cli, client: &Client) -> Res
         Ok(r) => r,
         Err(e) => {
             if e.downcast_ref::<std::io::Error>()
-                .map_or(true, |io_err| io_err.kind() != std::io::ErrorKind::NotFound)
+                .is_none_or(|io_err| io_err.kind() != std::io::ErrorKind::NotFound)
             {
                 eprintln!(
                     "Could not load check results: {}. No report will be generated.",
@@ -424,32 +2026,26 @@ async fn run_report_once(settings: &Settings, cli: &Cli, client: &Client) -> Res
         return Ok(());
     }
 
-    let report = generate_report(&filtered_results, &settings.target_urls, since, until);
+    let report = generate_report(&filtered_results, targets, since, until);
 
     if settings.reporting.output_to_console {
-        format_report_console(&report, &settings.reporting);
-    }
-
-    if settings.reporting.output_to_misskey {
-        let mfm_report = format_report_mfm(&report);
-        if cli.dry_run {
-            println!("\n--- Misskey Dry Run ---\n{}", mfm_report);
-        } else if let Some(token) = &settings.misskey_token {
-            if !token.is_empty() {
-                println!("Posting report to Misskey...");
-                post_to_misskey(
-                    client,
-                    &settings.misskey_url,
-                    token,
-                    &mfm_report,
-                    &settings.reporting.misskey_visibility,
-                )
-                .await?;
-                println!("Report posted to Misskey successfully.");
+        match console_template.map(|t| t.render_report(&report, "console", settings.locale)) {
+            Some(Ok(text)) => println!("{}", text),
+            Some(Err(e)) => {
+                eprintln!(
+                    "Failed to render console template: {:#}; using the built-in format",
+                    e
+                );
+                format_report_console(&report, &settings.reporting, settings.locale);
             }
+            None => format_report_console(&report, &settings.reporting, settings.locale),
         }
     }
 
+    if settings.reporting.output_to_notifiers {
+        notifiers.send_report(&report, cli.dry_run).await?;
+    }
+
     Ok(())
 }
 
@@ -473,185 +2069,931 @@ fn percentile(sorted: &[f64], p: f64) -> f64 {
     }
 }
 
-fn generate_report(
-    results: &[CheckResult],
-    targets: &[String],
-    since: DateTime<Utc>,
-    until: DateTime<Utc>,
-) -> Report {
-    let mut target_stats = Vec::new();
-    for target in targets {
-        let mut target_results: Vec<_> = results
-            .iter()
-            .filter(|r| &r.url == target)
-            .cloned()
-            .collect();
-        if target_results.is_empty() {
-            continue;
-        }
+fn sorted_f64(values: &[u64]) -> Vec<f64> {
+    let mut sorted: Vec<f64> = values.iter().map(|&v| v as f64).collect();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    sorted
+}
 
-        // 時系列でソートして正確なcolo遷移を計算
-        target_results.sort_by_key(|r| r.timestamp);
+/// 中央値 (偶数個なら中央 2 つの平均)。レポート、サンプル集計、RTT アラートで共通に使う。
+fn median_of(values: &[u64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    median(&sorted_f64(values))
+}
 
-        let total_checks = target_results.len();
-        let successful_checks = target_results.iter().filter(|r| r.success).count();
-        let uptime = if total_checks > 0 {
-            (successful_checks as f64 / total_checks as f64) * 100.0
-        } else {
-            0.0
-        };
+fn compute_rtt_stats(values: &[u64]) -> Option<RttStats> {
+    if values.is_empty() {
+        return None;
+    }
+    let sorted = sorted_f64(values);
 
-        let rtts: Vec<f64> = target_results
-            .iter()
-            .filter_map(|r| r.rtt_millis.map(|rtt| rtt as f64))
-            .collect();
+    Some(RttStats {
+        min: values.iter().copied().min().unwrap_or(0),
+        max: values.iter().copied().max().unwrap_or(0),
+        mean: mean(&sorted),
+        median: median_of(values),
+        p95: percentile(&sorted, 0.95),
+        sample_min: None,
+        sample_max: None,
+    })
+}
 
-        let rtt_stats = if !rtts.is_empty() {
-            let mut sorted_rtts = rtts.clone();
-            sorted_rtts.sort_by(|a, b| a.partial_cmp(b).unwrap());
-            let p95 = percentile(&sorted_rtts, 0.95);
+/// 個々のサンプルの最小・最大を " (…)" 形式で返す。サンプルを取っていなければ空。
+fn format_sample_range(stats: &RttStats, locale: Locale) -> String {
+    match (stats.sample_min, stats.sample_max) {
+        (Some(min), Some(max)) => format!(" ({})", (locale.messages().sample_range)(min, max)),
+        _ => String::new(),
+    }
+}
 
-            RttStats {
-                min: target_results
-                    .iter()
-                    .filter_map(|r| r.rtt_millis)
-                    .min()
-                    .unwrap_or(0),
-                max: target_results
-                    .iter()
-                    .filter_map(|r| r.rtt_millis)
-                    .max()
-                    .unwrap_or(0),
-                mean: mean(&rtts),
-                median: median(&rtts),
-                p95,
-            }
-        } else {
-            RttStats {
-                min: 0,
-                max: 0,
-                mean: 0.0,
-                median: 0.0,
-                p95: 0.0,
-            }
-        };
-        // 実際の観測回数ベースで最頻出coloを算出
-        let mut colo_frequency = std::collections::HashMap::new();
-        for r in &target_results {
-            if let Some(ref colo) = r.colo {
-                *colo_frequency.entry(colo.clone()).or_insert(0) += 1;
-            }
-        }
+/// フェーズ別統計を "DNS 1.00/2.00ms, ..." (中央値/P95) 形式に整形する。
+fn format_phase_stats(phases: &PhaseStats) -> Option<String> {
+    let parts: Vec<String> = [
+        ("DNS", &phases.dns),
+        ("Connect", &phases.connect),
+        ("TLS", &phases.tls),
+        ("TTFB", &phases.ttfb),
+        ("Body", &phases.body),
+    ]
+    .iter()
+    .filter_map(|(name, stats)| {
+        stats
+            .as_ref()
+            .map(|s| format!("{} {:.2}/{:.2}ms", name, s.median, s.p95))
+    })
+    .collect();
+    if parts.is_empty() {
+        None
+    } else {
+        Some(parts.join(", "))
+    }
+}
 
-        let most_frequent_colo = colo_frequency
-            .iter()
-            .max_by(|(ca, a), (cb, b)| a.cmp(b).then_with(|| ca.cmp(cb)))
-            .map(|(colo, _)| colo.clone())
-            .unwrap_or_default();
+/// URL とアドレスファミリーから系列の表示名を作る (`Any` は URL のみ)。
+fn series_label(url: &str, family: AddressFamily) -> String {
+    if family.is_any() {
+        url.to_string()
+    } else {
+        format!("{} ({})", url, family)
+    }
+}
 
-        let mut unique_colos_list: Vec<_> = colo_frequency.keys().cloned().collect();
-        unique_colos_list.sort();
+/// 失敗内訳を "timeout×3, http_503×1" 形式に整形する (件数の多い順)。
+fn format_failure_breakdown(breakdown: &BTreeMap<ErrorKind, usize>) -> Option<String> {
+    if breakdown.is_empty() {
+        return None;
+    }
+    let mut entries: Vec<_> = breakdown.iter().collect();
+    entries.sort_by(|(ka, a), (kb, b)| b.cmp(a).then_with(|| ka.cmp(kb)));
+    Some(
+        entries
+            .iter()
+            .map(|(kind, count)| format!("{}×{}", kind, count))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
 
-        // colo遷移回数を算出
-        let mut colo_transitions = 0;
-        let mut last_colo = None;
-        for r in &target_results {
-            if let Some(ref colo) = r.colo {
-                if let Some(ref last) = last_colo {
-                    if last != colo {
-                        colo_transitions += 1;
-                    }
-                }
-                last_colo = Some(colo.clone());
+/// 時系列順のチェック結果から障害を抽出し、MTTR/MTBF を算出する。
+fn detect_incidents(sorted: &[CheckResult], until: DateTime<Utc>) -> IncidentStats {
+    let mut incidents = Vec::new();
+    let mut last_colo: Option<&String> = None;
+    let mut current: Option<Incident> = None;
+    for r in sorted {
+        if r.success {
+            if let Some(mut incident) = current.take() {
+                incident.end = Some(r.timestamp);
+                incident.duration = r.timestamp - incident.start;
+                incident.colo_after = r.colo.clone();
+                incidents.push(incident);
             }
+        } else {
+            let incident = current.get_or_insert_with(|| Incident {
+                start: r.timestamp,
+                end: None,
+                duration: ChronoDuration::zero(),
+                failed_checks: 0,
+                error_kinds: BTreeMap::new(),
+                colo_before: last_colo.cloned(),
+                colo_after: None,
+            });
+            incident.failed_checks += 1;
+            *incident
+                .error_kinds
+                .entry(r.error_kind.unwrap_or(ErrorKind::Other))
+                .or_insert(0) += 1;
+        }
+        if r.colo.is_some() {
+            last_colo = r.colo.as_ref();
         }
-        target_stats.push(TargetStats {
-            url: target.clone(),
-            total_checks,
-            successful_checks,
-            uptime,
-            rtt_stats,
-            unique_colos: unique_colos_list,
-            colo_transitions,
-            most_frequent_colo,
-        });
+    }
+    if let Some(mut incident) = current {
+        incident.duration = (until - incident.start).max(ChronoDuration::zero());
+        incidents.push(incident);
     }
 
-    let (succ, total): (usize, usize) = target_stats
+    let resolved: Vec<ChronoDuration> = incidents
         .iter()
-        .map(|s| (s.successful_checks, s.total_checks))
-        .fold((0, 0), |(a, b), (x, y)| (a + x, b + y));
-
-    let overall_uptime = if total > 0 {
-        (succ as f64 / total as f64) * 100.0
-    } else {
-        0.0
-    };
+        .filter(|i| i.end.is_some())
+        .map(|i| i.duration)
+        .collect();
+    let mttr = (!resolved.is_empty())
+        .then(|| resolved.iter().sum::<ChronoDuration>() / resolved.len() as i32);
+    let mtbf = sorted
+        .first()
+        .filter(|_| !incidents.is_empty())
+        .map(|first| {
+            let downtime: ChronoDuration = incidents.iter().map(|i| i.duration).sum();
+            let uptime = (until - first.timestamp - downtime).max(ChronoDuration::zero());
+            uptime / incidents.len() as i32
+        });
+    let longest_outage = incidents.iter().map(|i| i.duration).max();
 
-    Report {
-        since,
-        until,
-        configured_targets: targets.len(),
-        reported_targets: target_stats.len(),
-        overall_uptime,
-        target_stats,
+    IncidentStats {
+        incidents,
+        mttr,
+        mtbf,
+        longest_outage,
     }
 }
 
-fn format_report_mfm(report: &Report) -> String {
-    let mut mfm = String::new();
-
-    // 期間情報をローカル時刻で表示
-    let since_local = report.since.with_timezone(&Local);
-    let until_local = report.until.with_timezone(&Local);
-
-    mfm.push_str(&format!(
-        "**📊 監視レポート**\n**期間:** {} ～ {}\n\n**総合サマリー**\n- **監視対象:** {} / {} サイト\n- **全体の平均稼働率:** {:.3}%\n\n",
-        since_local.format("%Y-%m-%d %H:%M:%S %Z"),
-        until_local.format("%Y-%m-%d %H:%M:%S %Z"),
-        report.reported_targets, report.configured_targets, report.overall_uptime
-    ));
-
-    for stats in &report.target_stats {
-        mfm.push_str(&format!("**?[{}]({})**\n", stats.url, stats.url));
-        mfm.push_str(&format!(
-            "- **稼働率:** {:.3}% ({} / {} 成功)\n",
-            stats.uptime, stats.successful_checks, stats.total_checks
-        ));
-        mfm.push_str(&format!(
-            "- **RTT:** Min: {}ms, Max: {}ms, Avg: {:.2}ms, Median: {:.2}ms, P95: {:.2}ms\n",
-            stats.rtt_stats.min,
-            stats.rtt_stats.max,
-            stats.rtt_stats.mean,
-            stats.rtt_stats.median,
-            stats.rtt_stats.p95
-        ));
-        mfm.push_str(&format!(
-            "- **Colo:** {}回遷移, 最頻出: {}, ユニーク: {}\n\n",
-            stats.colo_transitions,
-            stats.most_frequent_colo,
-            stats.unique_colos.join(", ")
-        ));
-    }
+/// 秒単位に丸めて "1h 2m 3s" 形式にする。
+fn format_chrono_duration(duration: ChronoDuration) -> String {
+    let secs = duration.num_seconds().max(0) as u64;
+    format_duration(Duration::from_secs(secs)).to_string()
+}
 
-    mfm
+fn format_incident(incident: &Incident, locale: Locale) -> String {
+    let msg = locale.messages();
+    let start = incident
+        .start
+        .with_timezone(&Local)
+        .format("%m-%d %H:%M:%S");
+    let end = incident.end.map_or_else(
+        || msg.ongoing.to_string(),
+        |end| {
+            end.with_timezone(&Local)
+                .format("%m-%d %H:%M:%S")
+                .to_string()
+        },
+    );
+    let colo = |c: &Option<String>| c.clone().unwrap_or_else(|| "N/A".to_string());
+    format!(
+        "{} ({}) Colo: {} → {}",
+        (msg.range)(&start.to_string(), &end),
+        (msg.incident_detail)(
+            &format_chrono_duration(incident.duration),
+            incident.failed_checks,
+            &format_failure_breakdown(&incident.error_kinds).unwrap_or_default()
+        ),
+        colo(&incident.colo_before),
+        colo(&incident.colo_after)
+    )
 }
 
-fn format_report_console(report: &Report, settings: &ReportingSettings) {
+/// MTTR/MTBF/最長障害を "MTTR: 5m, MTBF: 3h, Longest: 12m" 形式にする。
+fn format_incident_summary(stats: &IncidentStats, locale: Locale) -> String {
+    let fmt =
+        |d: Option<ChronoDuration>| d.map_or_else(|| "N/A".to_string(), format_chrono_duration);
+    format!(
+        "{}, MTTR: {}, MTBF: {}, Longest: {}",
+        (locale.messages().incident_count)(stats.incidents.len()),
+        fmt(stats.mttr),
+        fmt(stats.mtbf),
+        fmt(stats.longest_outage)
+    )
+}
+
+/// 監視中と同じ基準 (窓の中で閾値を超える変更) でばたついていた期間を求める。
+/// 窓の間に変更がなくなった時点を終わりとする。
+fn detect_flapping(
+    transitions: &[ColoChangeRecord],
+    flap: &ColoFlap,
+    until: DateTime<Utc>,
+) -> Vec<FlapPeriod> {
+    let mut periods = Vec::new();
+    let mut current: Option<FlapPeriod> = None;
+    let mut window: VecDeque<&ColoChangeRecord> = VecDeque::new();
+    let mut last_at: Option<DateTime<Utc>> = None;
+    for t in transitions {
+        if let Some(last_at) = last_at
+            && t.at - last_at >= flap.window
+            && let Some(mut period) = current.take()
+        {
+            period.end = Some(last_at + flap.window);
+            periods.push(period);
+        }
+        window.push_back(t);
+        while window.front().is_some_and(|c| t.at - c.at >= flap.window) {
+            window.pop_front();
+        }
+        match &mut current {
+            Some(period) => {
+                period.transitions += 1;
+                t.collect_colos(&mut period.colos);
+            }
+            None if window.len() > flap.threshold as usize => {
+                let mut colos: Vec<String> = Vec::new();
+                for c in &window {
+                    c.collect_colos(&mut colos);
+                }
+                current = Some(FlapPeriod {
+                    start: window.front().map_or(t.at, |c| c.at),
+                    end: None,
+                    transitions: window.len(),
+                    colos,
+                });
+            }
+            None => {}
+        }
+        last_at = Some(t.at);
+    }
+    if let Some(mut period) = current
+        && let Some(last_at) = last_at
+    {
+        period.end = Some(last_at + flap.window).filter(|end| *end <= until);
+        periods.push(period);
+    }
+    periods
+}
+
+/// "NRT (Tokyo, JP): 3h (75.0%), 36回 (75.0%), 12.00/20.00ms"。
+/// RTT には基準の colo (最も長くいた colo) の中央値との差を添える。
+fn format_colo_stats(stats: &ColoStats, baseline: &ColoStats, locale: Locale) -> String {
+    let rtt = match &stats.rtt_stats {
+        Some(rtt) => {
+            let delta = baseline
+                .rtt_stats
+                .as_ref()
+                .filter(|_| baseline.colo != stats.colo)
+                .map(|base| format!(" ({:+.2}ms)", rtt.median - base.median))
+                .unwrap_or_default();
+            format!("{:.2}/{:.2}ms{}", rtt.median, rtt.p95, delta)
+        }
+        None => "N/A".to_string(),
+    };
+    format!(
+        "{}: {}",
+        colo::describe(&stats.colo),
+        (locale.messages().colo_stats_detail)(
+            &format_chrono_duration(stats.time),
+            stats.time_share,
+            stats.checks,
+            stats.check_share,
+            &rtt
+        )
+    )
+}
+
+/// 遷移行列を表にする (行が from、列が to)。
+fn format_transition_table(matrix: &BTreeMap<String, BTreeMap<String, usize>>) -> Vec<String> {
+    let mut colos: Vec<&String> = matrix
+        .iter()
+        .flat_map(|(from, tos)| std::iter::once(from).chain(tos.keys()))
+        .collect();
+    colos.sort();
+    colos.dedup();
+    let width = colos
+        .iter()
+        .map(|c| c.len())
+        .chain(
+            matrix
+                .values()
+                .flat_map(|tos| tos.values().map(|n| n.to_string().len())),
+        )
+        .max()
+        .unwrap_or(1);
+    let mut rows = vec![format!(
+        "{:width$} {}",
+        "",
+        colos
+            .iter()
+            .map(|c| format!("{:>width$}", c))
+            .collect::<Vec<_>>()
+            .join(" ")
+    )];
+    for from in &colos {
+        let cells: Vec<String> = colos
+            .iter()
+            .map(|to| {
+                let cell = if from == to {
+                    "-".to_string()
+                } else {
+                    matrix
+                        .get(*from)
+                        .and_then(|tos| tos.get(*to))
+                        .copied()
+                        .unwrap_or(0)
+                        .to_string()
+                };
+                format!("{:>width$}", cell)
+            })
+            .collect();
+        rows.push(format!("{:width$} {}", from, cells.join(" ")));
+    }
+    rows
+}
+
+/// 遷移を多い順に "NRT→KIX 3回" の形で並べる。
+fn sorted_transitions(
+    matrix: &BTreeMap<String, BTreeMap<String, usize>>,
+) -> Vec<(&String, &String, usize)> {
+    let mut pairs: Vec<(&String, &String, usize)> = matrix
+        .iter()
+        .flat_map(|(from, tos)| tos.iter().map(move |(to, n)| (from, to, *n)))
+        .collect();
+    pairs.sort_by(|a, b| b.2.cmp(&a.2).then_with(|| (a.0, a.1).cmp(&(b.0, b.1))));
+    pairs
+}
+
+fn format_colo_segment(segment: &ColoSegment, locale: Locale) -> String {
+    let msg = locale.messages();
+    let fmt = |t: DateTime<Utc>| t.with_timezone(&Local).format("%m-%d %H:%M:%S").to_string();
+    format!(
+        "{} {} ({})",
+        (msg.range)(
+            &fmt(segment.start),
+            &segment.end.map_or_else(|| msg.ongoing.to_string(), fmt)
+        ),
+        colo::describe(&segment.colo),
+        (msg.segment_detail)(&format_chrono_duration(segment.duration), segment.checks)
+    )
+}
+
+fn format_unexpected_colos(stats: &TargetStats, locale: Locale) -> String {
+    (locale.messages().unexpected_colo_time)(
+        &format_chrono_duration(stats.unexpected_colo_time),
+        stats.unexpected_colo_share,
+        &stats
+            .unexpected_colos
+            .iter()
+            .map(|c| colo::describe(c))
+            .collect::<Vec<_>>()
+            .join(", "),
+    )
+}
+
+fn format_flap_period(period: &FlapPeriod, locale: Locale) -> String {
+    let msg = locale.messages();
+    let fmt = |t: DateTime<Utc>| t.with_timezone(&Local).format("%m-%d %H:%M:%S").to_string();
+    format!(
+        "{} ({})",
+        (msg.range)(
+            &fmt(period.start),
+            &period.end.map_or_else(|| msg.ongoing.to_string(), fmt)
+        ),
+        (msg.flap_period_detail)(period.transitions, &period.colos.join(", "))
+    )
+}
+
+/// `sorted[i]` のチェックの colo にいたとみなす時間。次のチェックまでとするが、監視が止まっていた間を
+/// 数えないようチェック間隔の 2 倍で打ち切り、最後のチェックはチェック間隔か `until` までとする。
+/// colo 別の滞在時間とタイムラインはどちらもこれを足し合わせる。
+fn colo_dwell(
+    sorted: &[CheckResult],
+    i: usize,
+    interval: ChronoDuration,
+    until: DateTime<Utc>,
+) -> ChronoDuration {
+    let r = &sorted[i];
+    let end = match sorted.get(i + 1) {
+        Some(next) => next.timestamp.min(r.timestamp + interval * 2),
+        None => (r.timestamp + interval).min(until),
+    };
+    (end - r.timestamp).max(ChronoDuration::zero())
+}
+
+/// colo を取得できたチェックを同じ colo が続く区間にまとめる。
+fn build_colo_timeline(
+    sorted: &[CheckResult],
+    interval: ChronoDuration,
+    until: DateTime<Utc>,
+) -> Vec<ColoSegment> {
+    let mut timeline: Vec<ColoSegment> = Vec::new();
+    for (i, r) in sorted.iter().enumerate() {
+        let Some(colo) = &r.colo else {
+            continue;
+        };
+        match timeline.last_mut() {
+            Some(segment) if segment.colo == *colo => segment.checks += 1,
+            current => {
+                if let Some(segment) = current {
+                    segment.end = Some(r.timestamp);
+                }
+                timeline.push(ColoSegment {
+                    colo: colo.clone(),
+                    start: r.timestamp,
+                    end: None,
+                    duration: ChronoDuration::zero(),
+                    checks: 1,
+                });
+            }
+        }
+        if let Some(segment) = timeline.last_mut() {
+            segment.duration += colo_dwell(sorted, i, interval, until);
+        }
+    }
+    timeline
+}
+
+/// チェックごとの RTT 統計。複数サンプルのチェックは中央値を代表値とし、個別サンプルの最小・最大は
+/// `sample_min` / `sample_max` に分けて持つ。
+fn compute_check_rtt_stats<'a>(results: impl Iterator<Item = &'a CheckResult>) -> Option<RttStats> {
+    let results: Vec<&CheckResult> = results.collect();
+    let rtts: Vec<u64> = results.iter().filter_map(|r| r.rtt_millis).collect();
+    let mut rtt_stats = compute_rtt_stats(&rtts)?;
+    // サンプルの最小・最大は別の母集団なので min/max には混ぜず別に持つ
+    if results.iter().any(|r| r.success && r.samples.is_some()) {
+        let extremes =
+            results
+                .iter()
+                .filter(|r| r.success)
+                .filter_map(|r| match (&r.samples, r.rtt_millis) {
+                    (Some(s), _) => Some((s.min_millis, s.max_millis)),
+                    (None, Some(rtt)) => Some((rtt, rtt)),
+                    (None, None) => None,
+                });
+        rtt_stats.sample_min = extremes.clone().map(|(min, _)| min).min();
+        rtt_stats.sample_max = extremes.map(|(_, max)| max).max();
+    }
+    Some(rtt_stats)
+}
+
+/// colo ごとの滞在時間・チェック数・RTT を滞在時間の長い順に返す。
+/// 滞在時間は [`colo_dwell`] の合計。
+fn compute_colo_stats(
+    sorted: &[CheckResult],
+    interval: ChronoDuration,
+    until: DateTime<Utc>,
+) -> Vec<ColoStats> {
+    let mut dwell: HashMap<&str, ChronoDuration> = HashMap::new();
+    for (i, r) in sorted.iter().enumerate() {
+        let Some(colo) = &r.colo else {
+            continue;
+        };
+        *dwell.entry(colo).or_insert(ChronoDuration::zero()) +=
+            colo_dwell(sorted, i, interval, until);
+    }
+    let observed = dwell
+        .values()
+        .fold(ChronoDuration::zero(), |sum, d| sum + *d);
+    let total_checks = sorted.iter().filter(|r| r.colo.is_some()).count();
+    let share = |part: f64, whole: f64| {
+        if whole > 0.0 {
+            part / whole * 100.0
+        } else {
+            0.0
+        }
+    };
+
+    let mut stats: Vec<ColoStats> = dwell
+        .into_iter()
+        .map(|(colo, time)| {
+            let on_colo = || {
+                sorted
+                    .iter()
+                    .filter(move |r| r.colo.as_deref() == Some(colo))
+            };
+            let checks = on_colo().count();
+            ColoStats {
+                colo: colo.to_string(),
+                checks,
+                check_share: share(checks as f64, total_checks as f64),
+                time,
+                time_share: share(
+                    time.num_milliseconds() as f64,
+                    observed.num_milliseconds() as f64,
+                ),
+                rtt_stats: compute_check_rtt_stats(on_colo()),
+            }
+        })
+        .collect();
+    stats.sort_by(|a, b| b.time.cmp(&a.time).then_with(|| a.colo.cmp(&b.colo)));
+    stats
+}
+
+/// 通知用レポートに載せる障害の件数 (新しいものから)
+const MFM_MAX_INCIDENTS: usize = 5;
+/// 通知用レポートに載せる colo 別統計・遷移・タイムライン区間の件数
+const MFM_MAX_COLOS: usize = 5;
+/// コンソールに出すタイムライン区間の件数 (新しいものから)
+const CONSOLE_MAX_SEGMENTS: usize = 20;
+
+fn generate_report(
+    results: &[CheckResult],
+    targets: &[Target],
+    since: DateTime<Utc>,
+    until: DateTime<Utc>,
+) -> Report {
+    // (url, family) の組を別系列として扱う。設定から外れたファミリーも過去データにあれば含める
+    let mut series: Vec<(&Target, AddressFamily)> = Vec::new();
+    for target in targets {
+        let mut target_families: Vec<AddressFamily> = results
+            .iter()
+            .filter(|r| r.url == target.url)
+            .map(|r| r.address_family)
+            .collect();
+        target_families.sort();
+        target_families.dedup();
+        series.extend(target_families.into_iter().map(|f| (target, f)));
+    }
+
+    let mut target_stats = Vec::new();
+    for (target, family) in series {
+        let mut target_results: Vec<_> = results
+            .iter()
+            .filter(|r| r.url == target.url && r.address_family == family)
+            .cloned()
+            .collect();
+        if target_results.is_empty() {
+            continue;
+        }
+
+        // 時系列でソートして正確なcolo遷移を計算
+        target_results.sort_by_key(|r| r.timestamp);
+
+        let total_checks = target_results.len();
+        let successful_checks = target_results.iter().filter(|r| r.success).count();
+        let uptime = if total_checks > 0 {
+            (successful_checks as f64 / total_checks as f64) * 100.0
+        } else {
+            0.0
+        };
+
+        let rtt_stats = compute_check_rtt_stats(target_results.iter()).unwrap_or(RttStats {
+            min: 0,
+            max: 0,
+            mean: 0.0,
+            median: 0.0,
+            p95: 0.0,
+            sample_min: None,
+            sample_max: None,
+        });
+        let (lost, sent) = target_results
+            .iter()
+            .filter_map(|r| r.samples.as_ref())
+            .fold((0, 0), |(l, c), s| (l + s.failures, c + s.count));
+        let sample_loss = (sent > 0).then(|| lost as f64 / sent as f64 * 100.0);
+
+        let phase = |f: fn(&PhaseTimings) -> Option<u64>| {
+            let values: Vec<u64> = target_results
+                .iter()
+                .filter_map(|r| r.phases.as_ref().and_then(f))
+                .collect();
+            compute_rtt_stats(&values)
+        };
+        let phase_stats = PhaseStats {
+            dns: phase(|p| p.dns_millis),
+            connect: phase(|p| p.connect_millis),
+            tls: phase(|p| p.tls_millis),
+            ttfb: phase(|p| p.ttfb_millis),
+            body: phase(|p| p.body_millis),
+        };
+        // 実際の観測回数ベースで最頻出coloを算出
+        let mut colo_frequency = std::collections::HashMap::new();
+        for r in &target_results {
+            if let Some(ref colo) = r.colo {
+                *colo_frequency.entry(colo.clone()).or_insert(0) += 1;
+            }
+        }
+
+        let most_frequent_colo = colo_frequency
+            .iter()
+            .max_by(|(ca, a), (cb, b)| a.cmp(b).then_with(|| ca.cmp(cb)))
+            .map(|(colo, _)| colo.clone())
+            .unwrap_or_default();
+
+        let mut unique_colos_list: Vec<_> = colo_frequency.keys().cloned().collect();
+        unique_colos_list.sort();
+
+        // colo遷移回数を算出
+        let mut transitions: Vec<ColoChangeRecord> = Vec::new();
+        let mut last_colo: Option<&String> = None;
+        for r in &target_results {
+            if let Some(ref colo) = r.colo {
+                if let Some(last) = last_colo
+                    && last != colo
+                {
+                    transitions.push(ColoChangeRecord {
+                        at: r.timestamp,
+                        from: last.clone(),
+                        to: colo.clone(),
+                    });
+                }
+                last_colo = Some(colo);
+            }
+        }
+        let mut colo_transition_matrix: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+        for t in &transitions {
+            *colo_transition_matrix
+                .entry(t.from.clone())
+                .or_default()
+                .entry(t.to.clone())
+                .or_default() += 1;
+        }
+        let interval =
+            ChronoDuration::from_std(target.check_interval).unwrap_or(ChronoDuration::zero());
+        let colo_timeline = build_colo_timeline(&target_results, interval, until);
+        let flapping_periods = target
+            .colo_change
+            .flap
+            .map(|flap| detect_flapping(&transitions, &flap, until))
+            .unwrap_or_default();
+        // ばたつき中の遷移は二重に数えないよう分けておく
+        let flapping_transitions: usize = flapping_periods.iter().map(|p| p.transitions).sum();
+        let colo_transitions = transitions.len() - flapping_transitions;
+        let colo_stats = compute_colo_stats(&target_results, interval, until);
+        let unexpected: Vec<&ColoStats> = colo_stats
+            .iter()
+            .filter(|c| !target.is_expected_colo(&c.colo))
+            .collect();
+        let unexpected_colo_time = unexpected
+            .iter()
+            .fold(ChronoDuration::zero(), |sum, c| sum + c.time);
+        let unexpected_colo_share = unexpected.iter().map(|c| c.time_share).sum();
+        let unexpected_colos = unexpected.iter().map(|c| c.colo.clone()).collect();
+        let colo_fallbacks = target_results
+            .iter()
+            .filter(|r| {
+                r.colo.is_some()
+                    && r.colo_ray.is_some()
+                    && r.trace.as_ref().and_then(|t| t.colo.as_ref()).is_none()
+            })
+            .count();
+        let colo_mismatches = target_results.iter().filter(|r| r.colo_mismatch).count();
+        // 古いログには colo_missing がないので、成功かつ colo なしで数える
+        let colo_missing = target_results
+            .iter()
+            .filter(|r| r.success && r.colo.is_none())
+            .count();
+
+        let mut failure_breakdown = BTreeMap::new();
+        for r in target_results.iter().filter(|r| !r.success) {
+            *failure_breakdown
+                .entry(r.error_kind.unwrap_or(ErrorKind::Other))
+                .or_insert(0) += 1;
+        }
+        let incident_stats = detect_incidents(&target_results, until);
+
+        target_stats.push(TargetStats {
+            url: target.url.clone(),
+            name: target.name.clone(),
+            address_family: family,
+            total_checks,
+            successful_checks,
+            uptime,
+            rtt_stats,
+            sample_loss,
+            phase_stats,
+            colo_stats,
+            unique_colos: unique_colos_list,
+            colo_transitions,
+            flapping_transitions,
+            colo_transition_matrix,
+            colo_timeline,
+            most_frequent_colo,
+            colo_fallbacks,
+            colo_mismatches,
+            colo_missing,
+            flapping_periods,
+            unexpected_colo_time,
+            unexpected_colo_share,
+            unexpected_colos,
+            failure_breakdown,
+            incident_stats,
+        });
+    }
+
+    let (succ, total): (usize, usize) = target_stats
+        .iter()
+        .map(|s| (s.successful_checks, s.total_checks))
+        .fold((0, 0), |(a, b), (x, y)| (a + x, b + y));
+
+    let overall_uptime = if total > 0 {
+        (succ as f64 / total as f64) * 100.0
+    } else {
+        0.0
+    };
+
+    Report {
+        since,
+        until,
+        configured_targets: targets.iter().map(|t| t.address_families.len()).sum(),
+        reported_targets: target_stats.len(),
+        overall_uptime,
+        target_stats,
+    }
+}
+
+/// レポートを通知先の方言で整形する (Misskey なら MFM)。
+fn format_report_markup(report: &Report, m: Markup, locale: Locale) -> String {
+    let msg = locale.messages();
+    let mut text = String::new();
+
+    // 期間情報をローカル時刻で表示
+    let since_local = report.since.with_timezone(&Local);
+    let until_local = report.until.with_timezone(&Local);
+
+    text.push_str(&format!(
+        "{}\n{} {}\n\n{}\n- {} {}\n- {} {:.3}%\n\n",
+        m.bold(msg.report_title),
+        m.bold(msg.period),
+        (msg.range)(
+            &since_local.format("%Y-%m-%d %H:%M:%S %Z").to_string(),
+            &until_local.format("%Y-%m-%d %H:%M:%S %Z").to_string()
+        ),
+        m.bold(msg.summary),
+        m.bold(msg.targets),
+        (msg.target_count)(report.reported_targets, report.configured_targets),
+        m.bold(msg.overall_uptime),
+        report.overall_uptime
+    ));
+
+    for stats in &report.target_stats {
+        let link_text = stats.name.as_deref().unwrap_or(&stats.url);
+        let link = m.bold(&m.link(link_text, &stats.url));
+        if stats.address_family.is_any() {
+            text.push_str(&format!("{}\n", link));
+        } else {
+            text.push_str(&format!("{} ({})\n", link, stats.address_family));
+        }
+        text.push_str(&format!(
+            "- {} {:.3}% {}\n",
+            m.bold(msg.uptime),
+            stats.uptime,
+            (msg.successful)(stats.successful_checks, stats.total_checks)
+        ));
+        if let Some(failures) = format_failure_breakdown(&stats.failure_breakdown) {
+            text.push_str(&format!("- {} {}\n", m.bold(msg.failures), failures));
+        }
+        let incidents = &stats.incident_stats.incidents;
+        if !incidents.is_empty() {
+            text.push_str(&format!(
+                "- {} {}\n",
+                m.bold(msg.incidents),
+                format_incident_summary(&stats.incident_stats, locale)
+            ));
+            for incident in incidents.iter().rev().take(MFM_MAX_INCIDENTS) {
+                text.push_str(&format!("  - {}\n", format_incident(incident, locale)));
+            }
+            if incidents.len() > MFM_MAX_INCIDENTS {
+                text.push_str(&format!(
+                    "  - {}\n",
+                    (msg.more_incidents)(incidents.len() - MFM_MAX_INCIDENTS)
+                ));
+            }
+        }
+        text.push_str(&format!(
+            "- {} Min: {}ms, Max: {}ms, Avg: {:.2}ms, Median: {:.2}ms, P95: {:.2}ms\n",
+            m.bold("RTT:"),
+            stats.rtt_stats.min,
+            stats.rtt_stats.max,
+            stats.rtt_stats.mean,
+            stats.rtt_stats.median,
+            stats.rtt_stats.p95
+        ));
+        if let Some(loss) = stats.sample_loss {
+            text.push_str(&format!(
+                "- {} {:.2}%{}\n",
+                m.bold(msg.sample_loss),
+                loss,
+                format_sample_range(&stats.rtt_stats, locale)
+            ));
+        }
+        if let Some(phases) = format_phase_stats(&stats.phase_stats) {
+            text.push_str(&format!("- {} {}\n", m.bold(msg.phases), phases));
+        }
+        text.push_str(&format!(
+            "- {} {}\n",
+            m.bold("Colo:"),
+            (msg.colo_summary)(
+                stats.colo_transitions,
+                &colo::describe(&stats.most_frequent_colo),
+                &stats
+                    .unique_colos
+                    .iter()
+                    .map(|c| colo::describe(c))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        ));
+        if stats.colo_stats.len() > 1 {
+            let baseline = &stats.colo_stats[0];
+            text.push_str(&format!("- {}\n", m.bold(msg.per_colo)));
+            for colo_stats in stats.colo_stats.iter().take(MFM_MAX_COLOS) {
+                text.push_str(&format!(
+                    "  - {}\n",
+                    format_colo_stats(colo_stats, baseline, locale)
+                ));
+            }
+            if stats.colo_stats.len() > MFM_MAX_COLOS {
+                text.push_str(&format!(
+                    "  - {}\n",
+                    (msg.more_incidents)(stats.colo_stats.len() - MFM_MAX_COLOS)
+                ));
+            }
+        }
+        if !stats.colo_transition_matrix.is_empty() {
+            let pairs = sorted_transitions(&stats.colo_transition_matrix);
+            let mut shown: Vec<String> = pairs
+                .iter()
+                .take(MFM_MAX_COLOS)
+                .map(|(from, to, n)| {
+                    format!(
+                        "{}→{} {}",
+                        m.code(from),
+                        m.code(to),
+                        (msg.transition_count)(*n)
+                    )
+                })
+                .collect();
+            if pairs.len() > MFM_MAX_COLOS {
+                shown.push((msg.more_incidents)(pairs.len() - MFM_MAX_COLOS));
+            }
+            text.push_str(&format!(
+                "- {} {}\n",
+                m.bold(msg.transition_matrix),
+                shown.join(", ")
+            ));
+            text.push_str(&format!("- {}\n", m.bold(msg.timeline)));
+            for segment in stats.colo_timeline.iter().rev().take(MFM_MAX_COLOS) {
+                text.push_str(&format!("  - {}\n", format_colo_segment(segment, locale)));
+            }
+            if stats.colo_timeline.len() > MFM_MAX_COLOS {
+                text.push_str(&format!(
+                    "  - {}\n",
+                    (msg.more_incidents)(stats.colo_timeline.len() - MFM_MAX_COLOS)
+                ));
+            }
+        }
+        if stats.colo_mismatches > 0 || stats.colo_fallbacks > 0 {
+            text.push_str(&format!(
+                "- {} {}\n",
+                m.bold(msg.colo_mismatch),
+                (msg.colo_mismatch_detail)(stats.colo_mismatches, stats.colo_fallbacks)
+            ));
+        }
+        if stats.colo_missing > 0 {
+            text.push_str(&format!(
+                "- {} {}\n",
+                m.bold(msg.colo_missing),
+                (msg.colo_missing_detail)(stats.colo_missing)
+            ));
+        }
+        if !stats.flapping_periods.is_empty() {
+            text.push_str(&format!(
+                "- {} {}\n",
+                m.bold(msg.flapping),
+                (msg.flap_period_count)(stats.flapping_periods.len())
+            ));
+            for period in stats.flapping_periods.iter().rev().take(MFM_MAX_INCIDENTS) {
+                text.push_str(&format!("  - {}\n", format_flap_period(period, locale)));
+            }
+            if stats.flapping_periods.len() > MFM_MAX_INCIDENTS {
+                text.push_str(&format!(
+                    "  - {}\n",
+                    (msg.more_incidents)(stats.flapping_periods.len() - MFM_MAX_INCIDENTS)
+                ));
+            }
+        }
+        if !stats.unexpected_colos.is_empty() {
+            text.push_str(&format!(
+                "- {} {}\n",
+                m.bold(msg.unexpected_colos),
+                format_unexpected_colos(stats, locale)
+            ));
+        }
+        text.push('\n');
+    }
+
+    text
+}
+
+fn format_report_console(report: &Report, settings: &ReportingSettings, locale: Locale) {
+    let msg = locale.messages();
     // 期間情報をローカル時刻で表示
     let since_local = report.since.with_timezone(&Local);
     let until_local = report.until.with_timezone(&Local);
 
-    println!("📊 監視レポート");
+    println!("{}", msg.report_title);
     println!("-----------------");
     println!(
-        "期間: {} ～ {}",
-        since_local.format("%Y-%m-%d %H:%M:%S %Z"),
-        until_local.format("%Y-%m-%d %H:%M:%S %Z")
+        "{} {}",
+        msg.period,
+        (msg.range)(
+            &since_local.format("%Y-%m-%d %H:%M:%S %Z").to_string(),
+            &until_local.format("%Y-%m-%d %H:%M:%S %Z").to_string()
+        )
     );
     println!(
-        "総合サマリー: {} / {} サイト, 平均稼働率: {:.3}%",
-        report.reported_targets, report.configured_targets, report.overall_uptime
+        "{}",
+        (msg.console_summary)(
+            report.reported_targets,
+            report.configured_targets,
+            report.overall_uptime
+        )
     );
     println!("-----------------");
 
@@ -678,31 +3020,139 @@ fn format_report_console(report: &Report, settings: &ReportingSettings) {
             rtt_p95_str.green()
         };
 
-        println!("URL: {}", stats.url.bold());
-        println!("  稼働率: {}", uptime_colored);
         println!(
-            "  RTT - Min: {}ms, Max: {}ms, Avg: {} (thr: {}ms), Median: {:.2}ms, P95: {} (thr: {}ms)",
-            stats.rtt_stats.min,
-            stats.rtt_stats.max,
-            rtt_avg_colored,
-            settings.rtt_threshold_ms,
-            stats.rtt_stats.median,
-            rtt_p95_colored,
-            settings.p95_rtt_threshold_ms
+            "URL: {}",
+            series_label(&stats.url, stats.address_family).bold()
         );
+        if let Some(name) = &stats.name {
+            println!("  {} {}", msg.name, name);
+        }
+        println!("  {} {}", msg.uptime, uptime_colored);
+        if let Some(failures) = format_failure_breakdown(&stats.failure_breakdown) {
+            println!("  {} {}", msg.failures, failures.red());
+        }
+        if !stats.incident_stats.incidents.is_empty() {
+            println!(
+                "  {} {}",
+                msg.incidents,
+                format_incident_summary(&stats.incident_stats, locale)
+            );
+            for incident in &stats.incident_stats.incidents {
+                let line = format_incident(incident, locale);
+                if incident.end.is_none() {
+                    println!("    - {}", line.red());
+                } else {
+                    println!("    - {}", line);
+                }
+            }
+        }
+        println!(
+            "  {}",
+            (msg.console_rtt)(
+                stats.rtt_stats.min,
+                stats.rtt_stats.max,
+                &rtt_avg_colored.to_string(),
+                settings.rtt_threshold_ms,
+                stats.rtt_stats.median,
+                &rtt_p95_colored.to_string(),
+                settings.p95_rtt_threshold_ms
+            )
+        );
+        if let Some(loss) = stats.sample_loss {
+            println!(
+                "  {} {:.2}%{}",
+                msg.sample_loss,
+                loss,
+                format_sample_range(&stats.rtt_stats, locale)
+            );
+        }
+        if let Some(phases) = format_phase_stats(&stats.phase_stats) {
+            println!("  {} {}", msg.phases, phases);
+        }
         let most = if stats.most_frequent_colo.is_empty() {
-            "N/A"
+            "N/A".to_string()
         } else {
-            &stats.most_frequent_colo
+            colo::describe(&stats.most_frequent_colo)
         };
         let uniques = if stats.unique_colos.is_empty() {
             "N/A".to_string()
         } else {
-            stats.unique_colos.join(", ")
+            stats
+                .unique_colos
+                .iter()
+                .map(|c| colo::describe(c))
+                .collect::<Vec<_>>()
+                .join(", ")
         };
-        println!("  Colo Transitions: {}", stats.colo_transitions);
-        println!("  Most Frequent Colo: {}", most);
-        println!("  Unique Colos: {}", uniques);
+        let flapping = if stats.flapping_transitions > 0 {
+            format!(
+                " {}",
+                (msg.flapping_transitions)(stats.flapping_transitions)
+            )
+        } else {
+            String::new()
+        };
+        println!(
+            "  {} {}{}",
+            msg.colo_transitions, stats.colo_transitions, flapping
+        );
+        println!("  {} {}", msg.most_frequent_colo, most);
+        println!("  {} {}", msg.unique_colos, uniques);
+        if stats.colo_stats.len() > 1 {
+            let baseline = &stats.colo_stats[0];
+            println!("  {}", msg.per_colo);
+            for colo_stats in &stats.colo_stats {
+                println!("    - {}", format_colo_stats(colo_stats, baseline, locale));
+            }
+        }
+        if !stats.colo_transition_matrix.is_empty() {
+            println!("  {}", msg.transition_matrix);
+            for row in format_transition_table(&stats.colo_transition_matrix) {
+                println!("    {}", row);
+            }
+            println!("  {}", msg.timeline);
+            let skipped = stats
+                .colo_timeline
+                .len()
+                .saturating_sub(CONSOLE_MAX_SEGMENTS);
+            if skipped > 0 {
+                println!("    - {}", (msg.more_incidents)(skipped));
+            }
+            for segment in &stats.colo_timeline[skipped..] {
+                println!("    - {}", format_colo_segment(segment, locale));
+            }
+        }
+        if stats.colo_mismatches > 0 || stats.colo_fallbacks > 0 {
+            println!(
+                "  {} {}",
+                msg.colo_mismatch,
+                (msg.colo_mismatch_detail)(stats.colo_mismatches, stats.colo_fallbacks).yellow()
+            );
+        }
+        if stats.colo_missing > 0 {
+            println!(
+                "  {} {}",
+                msg.colo_missing,
+                (msg.colo_missing_detail)(stats.colo_missing).yellow()
+            );
+        }
+        if !stats.flapping_periods.is_empty() {
+            println!(
+                "  {} {}",
+                msg.flapping,
+                (msg.flap_period_count)(stats.flapping_periods.len()).yellow()
+            );
+            for period in &stats.flapping_periods {
+                println!("    - {}", format_flap_period(period, locale));
+            }
+        }
+        if !stats.unexpected_colos.is_empty() {
+            println!(
+                "  {} {}",
+                msg.unexpected_colos,
+                format_unexpected_colos(stats, locale).yellow()
+            );
+        }
     }
 }
 
@@ -714,94 +3164,219 @@ fn load_settings() -> Result<Settings> {
     Ok(settings.try_deserialize()?)
 }
 
-async fn get_cloudflare_trace(client: &Client, url: &str) -> Result<CheckResult> {
+async fn probe_target(
+    prober: &Prober,
+    target: &Target,
+    family: AddressFamily,
+) -> Result<CheckResult> {
+    let url = target.url.as_str();
+    let provider = target.cdn_provider;
     let base_url = Url::parse(url)?;
-    let trace_url = base_url.join("/cdn-cgi/trace")?.to_string();
-    let start_time = time::Instant::now();
-    let resp = client
-        .get(&trace_url)
-        .send()
-        .await?
-        .error_for_status()?
-        .text()
-        .await?;
-    let rtt = start_time.elapsed();
+    let probe_url = provider.probe_url(&base_url)?;
+    let headers: Vec<(&str, &str)> = provider
+        .request_headers()
+        .iter()
+        .copied()
+        .chain(target.headers.iter().map(|(k, v)| (k.as_str(), v.as_str())))
+        .collect();
+    let request = ProbeRequest {
+        method: provider.method(),
+        url: &probe_url,
+        family,
+        headers: &headers,
+        timeout: target.request_timeout,
+    };
+    let fetched = if target.samples_per_check <= 1 && !target.reuse_connection {
+        prober.send(&request).await.map(|resp| {
+            let phases = resp.timings.clone();
+            (resp, phases, None)
+        })
+    } else {
+        let sampling = prober
+            .sample(&request, target.samples_per_check, target.reuse_connection)
+            .await;
+        summarize_samples(sampling).map(|(resp, phases, summary)| (resp, phases, Some(summary)))
+    };
+    // WAF ルールなどで trace 自体が 4xx で拒否されても、エッジが cf-ray を返していれば colo は分かる
+    let mut trace_blocked_status = None;
+    let (resp, phases, samples) = match fetched {
+        Ok(fetched) => fetched,
+        Err(e) => match ProbeError::response_of(&e) {
+            Some(blocked)
+                if provider == CdnProvider::Cloudflare
+                    && (400..500).contains(&blocked.status)
+                    && blocked.header("cf-ray").is_some() =>
+            {
+                trace_blocked_status = Some(blocked.status);
+                (blocked.clone(), blocked.timings.clone(), None)
+            }
+            _ => return Err(e),
+        },
+    };
 
-    const COLO_PREFIX: &str = "colo=";
-    let colo_opt = resp
-        .lines()
-        .find_map(|line| line.strip_prefix(COLO_PREFIX))
-        .map(|s| s.to_string());
+    // Cloudflare は trace のボディ、それ以外はレスポンスヘッダから POP を得る
+    let mut colo_ray = None;
+    let mut colo_mismatch = false;
+    let (trace, detected) = match provider {
+        CdnProvider::Cloudflare => {
+            let trace = trace_blocked_status
+                .is_none()
+                .then(|| parse_trace(&resp.body));
+            colo_ray = resp.header("cf-ray").and_then(cdn::pop_from_cf_ray);
+            // WAF などで trace が拒否・書き換えされた場合は cf-ray にフォールバック
+            let trace_colo = trace.as_ref().and_then(|t| t.colo.as_ref());
+            let detected = match (trace_colo, &colo_ray) {
+                (Some(trace_colo), Some(ray_colo)) => {
+                    if trace_colo != ray_colo {
+                        colo_mismatch = true;
+                        eprintln!(
+                            "Colo mismatch for {}: trace={}, cf-ray={}",
+                            series_label(url, family),
+                            trace_colo,
+                            ray_colo
+                        );
+                    }
+                    Some(trace_colo.clone())
+                }
+                (Some(trace_colo), None) => Some(trace_colo.clone()),
+                (None, Some(ray_colo)) => {
+                    match trace_blocked_status {
+                        Some(status) => eprintln!(
+                            "Trace blocked with HTTP {} for {}, using cf-ray header ({})",
+                            status,
+                            series_label(url, family),
+                            ray_colo
+                        ),
+                        None => eprintln!(
+                            "No colo in trace body for {}, using cf-ray header ({})",
+                            series_label(url, family),
+                            ray_colo
+                        ),
+                    }
+                    Some(ray_colo.clone())
+                }
+                (None, None) => {
+                    eprintln!(
+                        "No colo found in trace body or cf-ray header for {}",
+                        series_label(url, family)
+                    );
+                    None
+                }
+            };
+            (trace, detected.map(|colo| (provider, colo)))
+        }
+        _ => (None, provider.pop_from_headers(&resp)),
+    };
+    let (provider, colo) = match detected {
+        Some((p, colo)) => (Some(p), Some(colo)),
+        None => (Some(provider), None),
+    };
 
     Ok(CheckResult {
         timestamp: Utc::now(),
         url: url.to_string(),
+        address_family: family,
+        edge_ip: Some(resp.peer),
+        provider,
         success: true,
-        rtt_millis: Some(std::cmp::min(rtt.as_millis(), u64::MAX as u128) as u64),
+        rtt_millis: Some(
+            samples
+                .as_ref()
+                .map_or_else(|| probe::millis(resp.total), |s| s.median_millis),
+        ),
         error: None,
-        colo: colo_opt,
+        error_kind: None,
+        colo_missing: colo.is_none(),
+        colo,
+        colo_ray,
+        colo_mismatch,
+        trace_blocked_status,
+        trace,
+        phases: Some(phases),
+        samples,
     })
 }
 
-async fn post_to_misskey(
-    client: &Client,
-    url: &str,
-    token: &str,
-    text: &str,
-    visibility: &str,
-) -> Result<()> {
-    let base_url = Url::parse(url)?;
-    let api_url = base_url.join("/api/notes/create")?.to_string();
-    let mut params = HashMap::new();
-    params.insert("i", token.to_string());
-    params.insert("text", text.to_string());
-    params.insert("visibility", visibility.to_string());
-
-    let mut attempts = 0;
-    let max_attempts = 5;
-    let mut delay = Duration::from_secs(1);
+/// サンプル群を集計する。colo などは最初に成功したサンプルから取り、
+/// フェーズ別の時間は接続確立を含むウォームアップ (なければ最初の成功) のものを使う。
+/// 全サンプルが失敗した場合は最後のエラーを返す。
+fn summarize_samples(sampling: Sampling) -> Result<(ProbeResponse, PhaseTimings, SampleSummary)> {
+    let count = sampling.samples.len();
+    let mut rtts: Vec<u64> = Vec::new();
+    let mut representative = None;
+    let mut last_err = None;
+    for sample in sampling.samples {
+        match sample {
+            Ok(resp) => {
+                rtts.push(probe::millis(resp.total));
+                if representative.is_none() {
+                    representative = Some(resp);
+                }
+            }
+            Err(e) => last_err = Some(e),
+        }
+    }
+    let Some(resp) = representative else {
+        return Err(last_err.unwrap_or_else(|| anyhow::anyhow!("No samples were taken")));
+    };
 
-    loop {
-        attempts += 1;
-        let response = client.post(&api_url).json(&params).send().await;
+    let phases = match sampling.warmup {
+        Some(Ok(warmup)) => warmup.timings,
+        _ => resp.timings.clone(),
+    };
+    let failures = count - rtts.len();
+    let summary = SampleSummary {
+        count,
+        failures,
+        min_millis: rtts.iter().copied().min().unwrap_or(0),
+        median_millis: median_of(&rtts).round() as u64,
+        max_millis: rtts.iter().copied().max().unwrap_or(0),
+        loss_ratio: failures as f64 / count as f64,
+    };
+    Ok((resp, phases, summary))
+}
 
-        match response {
-            Ok(resp) if resp.status().is_success() => return Ok(()),
-            Ok(resp) => {
-                let status = resp.status();
-                let error_text = resp.text().await.unwrap_or_else(|_| "No body".to_string());
-                if status.is_client_error() {
-                    // クライアントエラーは即失敗
-                    return Err(anyhow::anyhow!(
-                        "Misskey API client error {} - {}",
-                        status,
-                        error_text
-                    ));
+fn parse_trace(body: &str) -> TraceInfo {
+    let mut trace = TraceInfo::default();
+    for line in body.lines() {
+        let Some((key, value)) = line.trim().split_once('=') else {
+            continue;
+        };
+        let value = value.to_string();
+        match key {
+            "fl" => trace.fl = Some(value),
+            "h" => trace.h = Some(value),
+            // 型変換に失敗した値は捨てずに extra に残す
+            "ip" => match value.parse() {
+                Ok(ip) => trace.ip = Some(ip),
+                Err(_) => {
+                    trace.extra.insert(key.to_string(), value);
+                }
+            },
+            "ts" => match value.parse() {
+                Ok(ts) => trace.ts = Some(ts),
+                Err(_) => {
+                    trace.extra.insert(key.to_string(), value);
                 }
-                eprintln!(
-                    "Attempt {} failed: Misskey API returned status {} - {}",
-                    attempts, status, error_text
-                );
-            }
-            Err(e) => {
-                eprintln!("Attempt {} failed: Request error: {}", attempts, e);
+            },
+            "visit_scheme" => trace.visit_scheme = Some(value),
+            "uag" => trace.uag = Some(value),
+            "colo" => trace.colo = Some(value),
+            "sliver" => trace.sliver = Some(value),
+            "http" => trace.http = Some(value),
+            "loc" => trace.loc = Some(value),
+            "tls" => trace.tls = Some(value),
+            "sni" => trace.sni = Some(value),
+            "warp" => trace.warp = Some(value),
+            "gateway" => trace.gateway = Some(value),
+            "rbi" => trace.rbi = Some(value),
+            "kex" => trace.kex = Some(value),
+            _ => {
+                trace.extra.insert(key.to_string(), value);
             }
         }
-
-        if attempts >= max_attempts {
-            return Err(anyhow::anyhow!(
-                "Failed to post to Misskey after {} attempts",
-                max_attempts
-            ));
-        }
-
-        time::sleep(delay).await;
-        // jitter は u64 を明示し、Duration は飽和演算で安全に拡大
-        let jitter_ms: u64 = rng().random_range(0u64..1000u64);
-        delay = delay
-            .saturating_mul(2)
-            .saturating_add(Duration::from_millis(jitter_ms));
     }
+    trace
 }
 
 async fn write_results(path: String, format: String, results: Vec<CheckResult>) -> Result<()> {
@@ -810,10 +3385,9 @@ async fn write_results(path: String, format: String, results: Vec<CheckResult>)
     }
 
     tokio::task::spawn_blocking(move || -> Result<()> {
-        let file = OpenOptions::new().create(true).append(true).open(&path)?;
-
         match format.as_str() {
             "json" | "jsonl" => {
+                let file = OpenOptions::new().create(true).append(true).open(&path)?;
                 let mut file = std::io::BufWriter::new(file);
                 for result in &results {
                     serde_json::to_writer(&mut file, result)?;
@@ -821,6 +3395,9 @@ async fn write_results(path: String, format: String, results: Vec<CheckResult>)
                 }
                 file.flush()?;
             }
+            "sqlite" => {
+                store::insert_results(&path, &results)?;
+            }
             other => anyhow::bail!("unsupported output_format: {}", other),
         }
         Ok(())
@@ -836,6 +3413,9 @@ async fn load_check_results(
     until: Option<DateTime<Utc>>,
 ) -> Result<Vec<CheckResult>> {
     let results = tokio::task::spawn_blocking(move || -> Result<Vec<CheckResult>> {
+        if format == "sqlite" {
+            return store::load_results(&path, since, until);
+        }
         // ファイルがない場合は空
         let file = match StdFile::open(&path) {
             Ok(f) => f,
@@ -854,8 +3434,8 @@ async fn load_check_results(
                     }
                     match serde_json::from_str::<CheckResult>(&line) {
                         Ok(result) => {
-                            let in_since = since.map_or(true, |s| result.timestamp >= s);
-                            let in_until = until.map_or(true, |u| result.timestamp <= u);
+                            let in_since = since.is_none_or(|s| result.timestamp >= s);
+                            let in_until = until.is_none_or(|u| result.timestamp <= u);
                             if in_since && in_until {
                                 results.push(result);
                             }
@@ -879,79 +3459,1120 @@ async fn load_check_results(
     Ok(results)
 }
 
-async fn save_last_success_states(states: &[LastSuccessState]) -> Result<()> {
-    let state_dir = "state".to_string();
-    let state_file = format!("{}/last_success.json", state_dir);
+/// `state/` 配下の JSON ファイルを一時ファイル経由で置き換える (ブロッキング)。
+fn write_state_file(file_name: &str, value: &impl Serialize) -> Result<()> {
+    write_json_file(&Path::new("state").join(file_name), value)
+}
+
+/// JSON ファイルを一時ファイル経由で置き換える (ブロッキング)。親ディレクトリがなければ作る。
+fn write_json_file(path: &Path, value: &impl Serialize) -> Result<()> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    std::fs::create_dir_all(dir)?;
+    let mut tmp_file = path.as_os_str().to_owned();
+    tmp_file.push(".tmp");
+    {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&tmp_file)?;
+        let mut writer = std::io::BufWriter::new(&file);
+        serde_json::to_writer_pretty(&mut writer, value)?;
+        writer.flush()?;
+        file.sync_all()?;
+    }
+    // アトミック入替
+    std::fs::rename(&tmp_file, path)?;
+    // ディレクトリエントリの永続化
+    if let Ok(dir) = StdFile::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// `state/` 配下の JSON 配列を読む。ファイルがなければ空、壊れていれば警告して空から始める。
+async fn load_state_file<T: DeserializeOwned + Send + 'static>(
+    file_name: &'static str,
+) -> Result<Vec<T>> {
+    load_json_file(Path::new("state").join(file_name)).await
+}
+
+/// JSON 配列を読む。ファイルがなければ空、壊れていれば警告して空から始める。
+async fn load_json_file<T: DeserializeOwned + Send + 'static>(path: PathBuf) -> Result<Vec<T>> {
+    tokio::task::spawn_blocking(move || -> Result<Vec<T>> {
+        let file = match StdFile::open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let reader = BufReader::new(file);
+        let states = match serde_json::from_reader(reader) {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Failed to parse {}, starting fresh: {}", path.display(), e);
+                Vec::new()
+            }
+        };
+
+        Ok(states)
+    })
+    .await?
+}
+
+async fn save_last_success_states(settings: &Settings, states: &[LastSuccessState]) -> Result<()> {
     let states = states.to_vec();
+    let database = settings.state_database();
 
     tokio::task::spawn_blocking(move || -> Result<()> {
-        std::fs::create_dir_all(&state_dir)?;
-
-        let mut all_states: HashMap<String, LastSuccessState> = HashMap::new();
+        if let Some(database) = database {
+            return store::save_last_success_states(&database, &states);
+        }
+        let mut all_states: HashMap<(String, AddressFamily), LastSuccessState> = HashMap::new();
 
         // 既存の状態を読み込み
-        if let Ok(file) = StdFile::open(&state_file) {
+        if let Ok(file) = StdFile::open("state/last_success.json") {
             let reader = BufReader::new(file);
             if let Ok(existing_states) = serde_json::from_reader::<_, Vec<LastSuccessState>>(reader)
             {
                 for state in existing_states {
-                    all_states.insert(state.url.clone(), state);
+                    all_states.insert((state.url.clone(), state.address_family), state);
                 }
             }
         }
 
         // 新しい状態で更新
         for state in &states {
-            all_states.insert(state.url.clone(), state.clone());
+            all_states.insert((state.url.clone(), state.address_family), state.clone());
         }
 
         let updated_states: Vec<LastSuccessState> = all_states.into_values().collect();
-
-        let tmp_file = format!("{}/last_success.json.tmp", state_dir);
-        {
-            let file = OpenOptions::new()
-                .create(true)
-                .write(true)
-                .truncate(true)
-                .open(&tmp_file)?;
-            let mut writer = std::io::BufWriter::new(&file);
-            serde_json::to_writer_pretty(&mut writer, &updated_states)?;
-            writer.flush()?;
-            file.sync_all()?;
-        }
-        // アトミック入替
-        std::fs::rename(&tmp_file, &state_file)?;
-        // ディレクトリエントリの永続化
-        if let Ok(dir) = StdFile::open(&state_dir) {
-            let _ = dir.sync_all();
-        }
-        Ok(())
+        write_state_file("last_success.json", &updated_states)
     })
     .await??;
 
     Ok(())
 }
 
-async fn load_last_success_states() -> Result<Vec<LastSuccessState>> {
-    let state_file = "state/last_success.json";
+async fn load_last_success_states(settings: &Settings) -> Result<Vec<LastSuccessState>> {
+    match settings.state_database() {
+        Some(database) => {
+            tokio::task::spawn_blocking(move || store::load_last_success_states(&database)).await?
+        }
+        None => load_state_file("last_success.json").await,
+    }
+}
 
-    tokio::task::spawn_blocking(move || -> Result<Vec<LastSuccessState>> {
-        let file = match StdFile::open(state_file) {
-            Ok(f) => f,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
-            Err(e) => return Err(e.into()),
+/// `--import-jsonl`: JSONL の結果をデータベースに入れる。取り込み済みの行は重複させない。
+/// データベースにまだ状態がなければ `state/last_success.json` も移す。
+async fn import_jsonl(settings: &Settings, jsonl_path: &str) -> Result<()> {
+    let Some(database) = settings.state_database() else {
+        anyhow::bail!("--import-jsonl needs output_format = \"sqlite\"");
+    };
+    if !std::path::Path::new(jsonl_path).exists() {
+        anyhow::bail!("{} does not exist", jsonl_path);
+    }
+    let results =
+        load_check_results(jsonl_path.to_string(), "jsonl".to_string(), None, None).await?;
+    let total = results.len();
+    let db = database.clone();
+    let inserted =
+        tokio::task::spawn_blocking(move || store::insert_results(&db, &results)).await??;
+    println!(
+        "Imported {} of {} check results from {} into {} ({} already present)",
+        inserted,
+        total,
+        jsonl_path,
+        database,
+        total - inserted
+    );
+
+    let db = database.clone();
+    if !tokio::task::spawn_blocking(move || store::has_last_success_states(&db)).await?? {
+        let states: Vec<LastSuccessState> = load_state_file("last_success.json").await?;
+        if !states.is_empty() {
+            save_last_success_states(settings, &states).await?;
+            println!(
+                "Imported {} colo state(s) from state/last_success.json",
+                states.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// 死活状態は `state_lock` の下で全件読み込んでから書き戻すので、丸ごと置き換える。
+async fn save_target_health_states(states: Vec<TargetHealthState>) -> Result<()> {
+    tokio::task::spawn_blocking(move || write_state_file("target_health.json", &states)).await?
+}
+
+async fn load_target_health_states() -> Result<Vec<TargetHealthState>> {
+    load_state_file("target_health.json").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    pub(crate) fn at(minutes: i64) -> DateTime<Utc> {
+        DateTime::from_timestamp(1_700_000_000, 0).unwrap() + ChronoDuration::minutes(minutes)
+    }
+
+    /// `path` の colo を順にたどる遷移を `minutes` の時刻に作る
+    fn records(path: &[&str], minutes: &[i64]) -> Vec<ColoChangeRecord> {
+        path.windows(2)
+            .zip(minutes)
+            .map(|(pair, m)| ColoChangeRecord {
+                at: at(*m),
+                from: pair[0].to_string(),
+                to: pair[1].to_string(),
+            })
+            .collect()
+    }
+
+    /// テストごとの一時ディレクトリ (drop で中身ごと消す)
+    pub(crate) struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        pub(crate) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("tracekey-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        pub(crate) fn file(&self, name: &str) -> String {
+            self.0.join(name).to_string_lossy().into_owned()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// 必須の項目だけ埋めた設定に `overrides` のキーを重ねる (ターゲットは https://example.com)
+    fn settings(overrides: serde_json::Value) -> Settings {
+        let mut value = serde_json::json!({
+            "target_urls": ["https://example.com"],
+            "check_interval_seconds": 300,
+            "user_agent": "test",
+            "request_timeout_seconds": 10,
+            "output_format": "none",
+            "output_path": "",
+            "max_concurrent_checks": 1,
+            "notification_concurrency": 1,
+            "reporting": {
+                "enabled": false,
+                "interval": "24h",
+                "output_to_console": false,
+                "output_to_notifiers": false,
+                "misskey_visibility": "home",
+                "rtt_threshold_ms": 500,
+                "p95_rtt_threshold_ms": 1000,
+                "uptime_threshold_percent": 99.5,
+                "critical_uptime_threshold_percent": 90.0,
+            },
+        });
+        if let (Some(value), Some(overrides)) = (value.as_object_mut(), overrides.as_object()) {
+            value.extend(overrides.clone());
+        }
+        serde_json::from_value(value).unwrap()
+    }
+
+    pub(crate) fn target(overrides: serde_json::Value) -> Target {
+        settings(overrides)
+            .resolve_targets(&["hook"])
+            .unwrap()
+            .remove(0)
+    }
+
+    const FLAP: ColoFlap = ColoFlap {
+        threshold: 2,
+        window: ChronoDuration::minutes(10),
+    };
+
+    #[test]
+    fn flapping_needs_more_than_threshold_changes_in_the_window() {
+        let transitions = records(&["NRT", "KIX", "NRT", "KIX"], &[0, 10, 20]);
+        assert!(detect_flapping(&transitions, &FLAP, at(60)).is_empty());
+        let transitions = records(&["NRT", "KIX"], &[0]);
+        assert!(detect_flapping(&transitions, &FLAP, at(60)).is_empty());
+    }
+
+    #[test]
+    fn flapping_period_spans_the_window_after_the_last_change() {
+        let transitions = records(&["NRT", "KIX", "NRT", "HKG", "NRT"], &[0, 3, 6, 9]);
+        let periods = detect_flapping(&transitions, &FLAP, at(60));
+        assert_eq!(periods.len(), 1);
+        assert_eq!(periods[0].start, at(0));
+        assert_eq!(periods[0].end, Some(at(19)));
+        assert_eq!(periods[0].transitions, 4);
+        assert_eq!(periods[0].colos, ["NRT", "KIX", "HKG"]);
+    }
+
+    #[test]
+    fn flapping_still_going_at_the_end_has_no_end() {
+        let transitions = records(&["NRT", "KIX", "NRT", "KIX"], &[50, 53, 56]);
+        let periods = detect_flapping(&transitions, &FLAP, at(60));
+        assert_eq!(periods.len(), 1);
+        assert_eq!(periods[0].start, at(50));
+        assert_eq!(periods[0].end, None);
+        assert_eq!(periods[0].transitions, 3);
+    }
+
+    #[test]
+    fn quiet_window_splits_flapping_periods() {
+        let transitions = records(
+            &["NRT", "KIX", "NRT", "KIX", "NRT", "KIX", "NRT"],
+            &[0, 1, 2, 30, 31, 32],
+        );
+        let periods = detect_flapping(&transitions, &FLAP, at(60));
+        assert_eq!(periods.len(), 2);
+        assert_eq!(
+            (periods[0].start, periods[0].end, periods[0].transitions),
+            (at(0), Some(at(12)), 3)
+        );
+        assert_eq!(
+            (periods[1].start, periods[1].end, periods[1].transitions),
+            (at(30), Some(at(42)), 3)
+        );
+    }
+
+    pub(crate) fn check(minutes: i64, colo: Option<&str>) -> CheckResult {
+        serde_json::from_value(serde_json::json!({
+            "timestamp": at(minutes),
+            "url": "https://example.com",
+            "success": colo.is_some(),
+            "rtt_millis": colo.map(|_| 10),
+            "error": colo.is_none().then_some("timed out"),
+            "colo": colo,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn timeline_and_colo_stats_count_dwell_the_same_way() {
+        // 5 分間隔。NRT の後に 1 時間止まり、失敗を挟んで KIX に移って期間の終わりまで続く
+        let sorted = [
+            check(0, Some("NRT")),
+            check(5, Some("NRT")),
+            check(65, Some("KIX")),
+            check(70, None),
+            check(75, Some("KIX")),
+        ];
+        let interval = ChronoDuration::minutes(5);
+        let timeline = build_colo_timeline(&sorted, interval, at(78));
+        let stats = compute_colo_stats(&sorted, interval, at(78));
+        assert_eq!(timeline.len(), 2);
+        // NRT は 5 分 + 打ち切った 10 分、KIX は失敗まで 5 分 + 最後の 3 分
+        assert_eq!(timeline[0].duration, ChronoDuration::minutes(15));
+        assert_eq!(timeline[0].end, Some(at(65)));
+        assert_eq!(timeline[1].duration, ChronoDuration::minutes(8));
+        assert_eq!(timeline[1].end, None);
+        assert_eq!(timeline[1].checks, 2);
+        for segment in &timeline {
+            let colo = stats.iter().find(|c| c.colo == segment.colo).unwrap();
+            assert_eq!(colo.time, segment.duration);
+        }
+    }
+
+    #[test]
+    fn report_builds_transition_matrix_and_timeline() {
+        let results = [
+            check(0, Some("NRT")),
+            check(5, Some("KIX")),
+            check(10, Some("NRT")),
+            check(15, Some("KIX")),
+            check(20, Some("KIX")),
+        ];
+        let report = generate_report(&results, &[target(serde_json::json!({}))], at(0), at(25));
+        let stats = &report.target_stats[0];
+        assert_eq!(stats.colo_transitions, 3);
+        assert_eq!(stats.flapping_transitions, 0);
+        let matrix: Vec<(&str, &str, usize)> = stats
+            .colo_transition_matrix
+            .iter()
+            .flat_map(|(from, tos)| {
+                tos.iter()
+                    .map(move |(to, n)| (from.as_str(), to.as_str(), *n))
+            })
+            .collect();
+        assert_eq!(matrix, [("KIX", "NRT", 1), ("NRT", "KIX", 2)]);
+        let timeline: Vec<_> = stats
+            .colo_timeline
+            .iter()
+            .map(|s| (s.colo.as_str(), s.start, s.end, s.checks))
+            .collect();
+        assert_eq!(
+            timeline,
+            [
+                ("NRT", at(0), Some(at(5)), 1),
+                ("KIX", at(5), Some(at(10)), 1),
+                ("NRT", at(10), Some(at(15)), 1),
+                ("KIX", at(15), None, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn changes_that_left_the_window_do_not_start_a_period() {
+        // 間隔が窓と同じなら窓から外れる
+        let transitions = records(&["NRT", "KIX", "NRT", "KIX", "NRT"], &[0, 10, 20, 30]);
+        assert!(detect_flapping(&transitions, &FLAP, at(60)).is_empty());
+    }
+
+    /// trace などを記録する前の形式の行
+    const BASELINE_LINE: &str = r#"{"timestamp":"2024-01-01T00:00:00Z","url":"https://example.com","success":true,"rtt_millis":42,"error":null,"colo":"NRT"}"#;
+
+    #[test]
+    fn baseline_jsonl_line_deserializes() {
+        let result: CheckResult = serde_json::from_str(BASELINE_LINE).unwrap();
+        assert_eq!(result.url, "https://example.com");
+        assert_eq!(result.address_family, AddressFamily::Any);
+        assert_eq!(result.rtt_millis, Some(42));
+        assert_eq!(result.colo.as_deref(), Some("NRT"));
+        assert!(result.success);
+        assert!(result.trace.is_none());
+        assert!(result.phases.is_none());
+        assert!(result.samples.is_none());
+        assert!(result.error_kind.is_none());
+        assert!(!result.colo_mismatch);
+        assert!(!result.colo_missing);
+    }
+
+    #[tokio::test]
+    async fn load_check_results_reads_old_and_new_lines() {
+        let dir = TempDir::new("jsonl");
+        let path = dir.file("trace_log.jsonl");
+        let mut new_result = check(5, Some("KIX"));
+        new_result.address_family = AddressFamily::Ipv6;
+        new_result.trace = Some(parse_trace("colo=KIX\nip=2001:db8::1\n"));
+        let lines = format!(
+            "{}\n{}\n",
+            BASELINE_LINE,
+            serde_json::to_string(&new_result).unwrap()
+        );
+        std::fs::write(&path, lines).unwrap();
+
+        let results = load_check_results(path, "jsonl".to_string(), None, None)
+            .await
+            .unwrap();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].colo.as_deref(), Some("NRT"));
+        assert_eq!(results[0].address_family, AddressFamily::Any);
+        assert_eq!(results[1].address_family, AddressFamily::Ipv6);
+        let trace = results[1].trace.as_ref().unwrap();
+        assert_eq!(trace.colo.as_deref(), Some("KIX"));
+        assert_eq!(trace.ip, Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_trace_fills_typed_fields() {
+        let body = "fl=123f45\nh=example.com\nip=203.0.113.7\nts=1700000000.123\n\
+                    visit_scheme=https\nuag=Mozilla/5.0\ncolo=NRT\nsliver=none\nhttp=http/2\n\
+                    loc=JP\ntls=TLSv1.3\nsni=plaintext\nwarp=off\ngateway=off\nrbi=off\n\
+                    kex=X25519\n";
+        let trace = parse_trace(body);
+        assert_eq!(trace.fl.as_deref(), Some("123f45"));
+        assert_eq!(trace.h.as_deref(), Some("example.com"));
+        assert_eq!(trace.ip, Some("203.0.113.7".parse().unwrap()));
+        assert_eq!(trace.ts, Some(1700000000.123));
+        assert_eq!(trace.visit_scheme.as_deref(), Some("https"));
+        assert_eq!(trace.uag.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(trace.colo.as_deref(), Some("NRT"));
+        assert_eq!(trace.sliver.as_deref(), Some("none"));
+        assert_eq!(trace.http.as_deref(), Some("http/2"));
+        assert_eq!(trace.loc.as_deref(), Some("JP"));
+        assert_eq!(trace.tls.as_deref(), Some("TLSv1.3"));
+        assert_eq!(trace.sni.as_deref(), Some("plaintext"));
+        assert_eq!(trace.warp.as_deref(), Some("off"));
+        assert_eq!(trace.gateway.as_deref(), Some("off"));
+        assert_eq!(trace.rbi.as_deref(), Some("off"));
+        assert_eq!(trace.kex.as_deref(), Some("X25519"));
+        assert!(trace.extra.is_empty());
+    }
+
+    #[test]
+    fn parse_trace_keeps_unknown_and_unparsable_values_in_extra() {
+        let trace = parse_trace("colo=KIX\nip=not-an-ip\nts=soon\nfoo=bar\nno separator\n");
+        assert_eq!(trace.colo.as_deref(), Some("KIX"));
+        assert_eq!(trace.ip, None);
+        assert_eq!(trace.ts, None);
+        assert_eq!(
+            trace.extra,
+            BTreeMap::from([
+                ("foo".to_string(), "bar".to_string()),
+                ("ip".to_string(), "not-an-ip".to_string()),
+                ("ts".to_string(), "soon".to_string()),
+            ])
+        );
+    }
+
+    fn headers(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn validate_headers_accepts_a_valid_set() {
+        let valid = headers(&[
+            ("Host", "origin.example.com"),
+            ("Accept", "text/html"),
+            ("User-Agent", "probe/1.0"),
+            ("X-Probe-Token", "a b;c=d"),
+        ]);
+        validate_headers("https://example.com", &valid).unwrap();
+        validate_headers("https://example.com", &BTreeMap::new()).unwrap();
+    }
+
+    #[test]
+    fn validate_headers_rejects_injection() {
+        for (name, value) in [
+            ("X-Test", "a\r\nX-Injected: 1"),
+            ("X-Test", "a\nb"),
+            ("X-Test", "a\rb"),
+            ("X-Test", "a\0b"),
+            ("X-Test\r\nX-Injected", "1"),
+            ("X Test", "1"),
+            ("X-Test:", "1"),
+            ("", "1"),
+        ] {
+            let err = validate_headers("https://example.com", &headers(&[(name, value)]))
+                .unwrap_err()
+                .to_string();
+            assert!(err.ends_with("(target https://example.com)"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn validate_headers_rejects_managed_headers() {
+        for name in [
+            "Connection",
+            "content-length",
+            "Content-Length",
+            "Transfer-Encoding",
+        ] {
+            let err = validate_headers("https://example.com", &headers(&[(name, "x")]))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("managed by tracekey"), "{}", err);
+        }
+    }
+
+    #[test]
+    fn validate_headers_rejects_case_insensitive_duplicates() {
+        let err = validate_headers(
+            "https://example.com",
+            &headers(&[("X-Token", "a"), ("x-token", "b")]),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("more than once"), "{}", err);
+        let err = validate_headers(
+            "https://example.com",
+            &headers(&[("Host", "a.example"), ("HOST", "b.example")]),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("more than once"), "{}", err);
+    }
+
+    /// `notify` の解決結果 (ターゲットごと)
+    fn notify_lists(overrides: serde_json::Value) -> Result<Vec<Vec<String>>> {
+        Ok(settings(overrides)
+            .resolve_targets(&["misskey", "discord"])?
+            .into_iter()
+            .map(|t| t.notify)
+            .collect())
+    }
+
+    #[test]
+    fn targets_without_notify_use_every_sink_by_default() {
+        let lists = notify_lists(serde_json::json!({})).unwrap();
+        assert_eq!(lists, [["misskey", "discord"]]);
+    }
+
+    #[test]
+    fn target_notify_picks_sinks() {
+        let lists = notify_lists(serde_json::json!({
+            "target_urls": [],
+            "targets": [
+                { "url": "https://a.example", "notify": ["discord"] },
+                { "url": "https://b.example", "notify": [] },
+                { "url": "https://c.example" },
+            ],
+        }))
+        .unwrap();
+        assert_eq!(lists, [vec!["discord"], vec![], vec!["misskey", "discord"]]);
+    }
+
+    #[test]
+    fn notify_by_default_false_silences_targets_without_notify() {
+        for overrides in [
+            serde_json::json!({ "notify_by_default": false }),
+            serde_json::json!({ "colo_change_notify_misskey": false }),
+        ] {
+            let lists = notify_lists(overrides.clone()).unwrap();
+            assert_eq!(lists, [Vec::<String>::new()], "{}", overrides);
+        }
+        // 新しい名前が優先される
+        let lists = notify_lists(serde_json::json!({
+            "notify_by_default": true,
+            "colo_change_notify_misskey": false,
+        }))
+        .unwrap();
+        assert_eq!(lists, [["misskey", "discord"]]);
+    }
+
+    #[test]
+    fn unknown_notify_channel_is_rejected() {
+        let err = notify_lists(serde_json::json!({
+            "target_urls": [],
+            "targets": [{ "url": "https://a.example", "notify": ["slack"] }],
+        }))
+        .unwrap_err()
+        .to_string();
+        assert!(
+            err.contains("Unknown notification channel 'slack'"),
+            "{}",
+            err
+        );
+    }
+
+    /// 失敗したチェック (エラーの種類つき)
+    fn failure(minutes: i64, kind: ErrorKind, error: &str) -> CheckResult {
+        let mut result = check(minutes, None);
+        result.error_kind = Some(kind);
+        result.error = Some(error.to_string());
+        result
+    }
+
+    #[test]
+    fn health_goes_degraded_then_down_at_the_threshold() {
+        let mut state = TargetHealthState::new("https://example.com", AddressFamily::Any, at(0));
+        assert!(state.apply(&check(0, Some("NRT")), 3).is_none());
+        assert_eq!(state.status, HealthStatus::Up);
+
+        assert!(
+            state
+                .apply(&failure(5, ErrorKind::Connect, "refused"), 3)
+                .is_none()
+        );
+        assert_eq!(state.status, HealthStatus::Degraded);
+        assert!(
+            state
+                .apply(&failure(10, ErrorKind::Connect, "refused"), 3)
+                .is_none()
+        );
+        assert_eq!(state.status, HealthStatus::Degraded);
+
+        let Some(HealthEvent::Down {
+            failures,
+            kind,
+            error,
+            since,
+        }) = state.apply(&failure(15, ErrorKind::Timeout, "timed out"), 3)
+        else {
+            panic!("expected a down event");
         };
+        assert_eq!(state.status, HealthStatus::Down);
+        assert_eq!(failures, 3);
+        assert_eq!(kind, ErrorKind::Timeout);
+        assert_eq!(error.as_deref(), Some("timed out"));
+        assert_eq!(since, at(5));
 
-        let reader = BufReader::new(file);
-        let states = match serde_json::from_reader(reader) {
-            Ok(s) => s,
-            Err(e) => {
-                eprintln!("Failed to parse last success states, starting fresh: {}", e);
-                Vec::new()
-            }
+        // ダウン中の失敗では重ねて通知しない
+        assert!(
+            state
+                .apply(&failure(20, ErrorKind::Timeout, "timed out"), 3)
+                .is_none()
+        );
+        assert_eq!(state.consecutive_failures, 4);
+    }
+
+    #[test]
+    fn health_does_not_alert_below_the_threshold() {
+        let mut state = TargetHealthState::new("https://example.com", AddressFamily::Any, at(0));
+        for round in 0..3 {
+            let base = round * 15;
+            assert!(
+                state
+                    .apply(&failure(base, ErrorKind::Connect, "refused"), 3)
+                    .is_none()
+            );
+            assert!(
+                state
+                    .apply(&failure(base + 5, ErrorKind::Connect, "refused"), 3)
+                    .is_none()
+            );
+            assert_eq!(state.status, HealthStatus::Degraded);
+            // ダウンに達していないので成功しても復旧の通知は出ない
+            assert!(state.apply(&check(base + 10, Some("NRT")), 3).is_none());
+            assert_eq!(state.status, HealthStatus::Up);
+            assert_eq!(state.consecutive_failures, 0);
+        }
+    }
+
+    #[test]
+    fn health_recovers_once_then_returns_to_up() {
+        let mut state = TargetHealthState::new("https://example.com", AddressFamily::Any, at(0));
+        assert!(
+            state
+                .apply(&failure(0, ErrorKind::Connect, "refused"), 2)
+                .is_none()
+        );
+        assert!(matches!(
+            state.apply(&failure(5, ErrorKind::HttpStatus { code: 503 }, "503"), 2),
+            Some(HealthEvent::Down { .. })
+        ));
+
+        let Some(HealthEvent::Recovered { kind, since }) = state.apply(&check(42, Some("NRT")), 2)
+        else {
+            panic!("expected a recovery event");
         };
+        assert_eq!(state.status, HealthStatus::Recovered);
+        // 復旧の通知には最後のエラーの種類と障害の始まりが載る (所要時間は 42 分)
+        assert_eq!(kind, Some(ErrorKind::HttpStatus { code: 503 }));
+        assert_eq!(since, at(0));
+        assert_eq!(at(42) - since, ChronoDuration::minutes(42));
 
-        Ok(states)
-    })
-    .await?
+        assert!(state.apply(&check(47, Some("NRT")), 2).is_none());
+        assert_eq!(state.status, HealthStatus::Up);
+        assert!(state.failing_since.is_none());
+    }
+
+    const RTT_ALERT: RttAlert = RttAlert {
+        trigger_ms: 500,
+        clear_ms: 300,
+        window: 3,
+    };
+
+    /// RTT を順に流し、各チェックで出たイベントを "high 600" / "cleared 250" の形で返す
+    fn rtt_events(state: &mut TargetHealthState, start: i64, rtts: &[u64]) -> Vec<Option<String>> {
+        rtts.iter()
+            .zip(start..)
+            .map(|(&rtt, minutes)| {
+                let mut result = check(minutes, Some("NRT"));
+                result.rtt_millis = Some(rtt);
+                state
+                    .apply_rtt(&result, &RTT_ALERT)
+                    .map(|event| match event {
+                        HealthEvent::RttHigh { median, .. } => format!("high {}", median),
+                        HealthEvent::RttCleared { median, since, .. } => {
+                            format!("cleared {} since {}", median, (since - at(0)).num_minutes())
+                        }
+                        _ => unreachable!(),
+                    })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rtt_alert_waits_for_a_full_window() {
+        let mut state = TargetHealthState::new("https://example.com", AddressFamily::Any, at(0));
+        assert_eq!(rtt_events(&mut state, 0, &[900, 900]), [None, None]);
+        // 失敗したチェックは窓に入らない
+        assert!(state.apply_rtt(&check(2, None), &RTT_ALERT).is_none());
+        assert_eq!(state.recent_rtts.len(), 2);
+        assert_eq!(
+            rtt_events(&mut state, 3, &[900]),
+            [Some("high 900".to_string())]
+        );
+    }
+
+    #[test]
+    fn rtt_alert_fires_when_the_median_crosses_trigger() {
+        let mut state = TargetHealthState::new("https://example.com", AddressFamily::Any, at(0));
+        // 1 回の外れ値では中央値は上がらない
+        assert_eq!(
+            rtt_events(&mut state, 0, &[100, 100, 2000, 100]),
+            [None, None, None, None]
+        );
+        // 中央値が 499 のうちは出さず、trigger ちょうどの 500 で出す
+        assert_eq!(
+            rtt_events(&mut state, 4, &[499, 499, 500]),
+            [None, None, None]
+        );
+        assert_eq!(state.rtt_alert_since, None);
+        assert_eq!(
+            rtt_events(&mut state, 7, &[500]),
+            [Some("high 500".to_string())]
+        );
+        assert_eq!(state.rtt_alert_since, Some(at(7)));
+    }
+
+    #[test]
+    fn rtt_alert_holds_between_clear_and_trigger_and_clears_below() {
+        let mut state = TargetHealthState::new("https://example.com", AddressFamily::Any, at(0));
+        assert_eq!(
+            rtt_events(&mut state, 0, &[600, 600, 600]),
+            [None, None, Some("high 600".to_string())]
+        );
+        // trigger を下回っても clear までは出さない (再度の high も出さない)
+        assert_eq!(
+            rtt_events(&mut state, 3, &[400, 400, 400, 301, 301, 600, 600]),
+            vec![None; 7]
+        );
+        assert_eq!(state.rtt_alert_since, Some(at(2)));
+        assert_eq!(
+            rtt_events(&mut state, 10, &[300, 300]),
+            [None, Some("cleared 300 since 2".to_string())]
+        );
+        assert_eq!(state.rtt_alert_since, None);
+        assert_eq!(rtt_events(&mut state, 12, &[250]), [None]);
+    }
+
+    /// colo を順に観測し、確定した変更を "NRT->KIX" の形で返す
+    fn observe(state: &mut LastSuccessState, colos: &[&str], settle_checks: u32) -> Vec<String> {
+        colos
+            .iter()
+            .filter_map(|&colo| {
+                state
+                    .observe_colo(colo, settle_checks)
+                    .map(|prev| format!("{}->{}", prev, colo))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn observe_colo_ignores_transient_blips() {
+        let mut state = LastSuccessState::new("https://example.com", AddressFamily::Any, at(0));
+        assert!(observe(&mut state, &["NRT"], 2).is_empty());
+        assert_eq!(state.colo.as_deref(), Some("NRT"));
+
+        // 1 回だけ別の colo を見て戻る、を繰り返しても変更にはならない
+        assert!(observe(&mut state, &["KIX", "NRT", "KIX", "NRT", "HKG", "NRT"], 2).is_empty());
+        assert_eq!(state.colo.as_deref(), Some("NRT"));
+        assert_eq!(state.pending_colo, None);
+        assert_eq!(state.pending_count, 0);
+
+        // 候補が入れ替わると数え直す
+        assert!(observe(&mut state, &["KIX", "HKG"], 2).is_empty());
+        assert_eq!(state.pending_colo.as_deref(), Some("HKG"));
+        assert_eq!(state.pending_count, 1);
+    }
+
+    #[test]
+    fn observe_colo_reports_a_settled_change_once() {
+        let mut state = LastSuccessState::new("https://example.com", AddressFamily::Any, at(0));
+        assert_eq!(
+            observe(&mut state, &["NRT", "KIX", "KIX", "KIX", "KIX"], 2),
+            ["NRT->KIX"]
+        );
+        assert_eq!(state.colo.as_deref(), Some("KIX"));
+        assert_eq!(state.pending_count, 0);
+
+        // settle_checks = 1 なら最初の観測で切り替わる
+        assert_eq!(
+            observe(&mut state, &["NRT", "KIX"], 1),
+            ["KIX->NRT", "NRT->KIX"]
+        );
+    }
+
+    #[test]
+    fn colo_digest_counts_bounces_within_the_window() {
+        let mut digest = ColoDigest::new("NRT".to_string(), at(0));
+        digest.push("KIX", Some(30));
+        assert_eq!(digest.bounces, 0);
+        digest.push("NRT", Some(12));
+        digest.push("KIX", Some(31));
+        assert_eq!(digest.bounces, 2);
+
+        let Event::ColoDigest {
+            from_colo,
+            to_colo,
+            path,
+            bounces,
+            rtt_millis,
+            unexpected,
+            since,
+            duration,
+            ..
+        } = digest.into_event(&target(serde_json::json!({})), AddressFamily::Any, at(15))
+        else {
+            panic!("expected a digest event");
+        };
+        assert_eq!(from_colo, "NRT");
+        assert_eq!(to_colo, "KIX");
+        assert_eq!(path, ["KIX", "NRT", "KIX"]);
+        assert_eq!(bounces, 2);
+        assert_eq!(rtt_millis, Some(31));
+        assert!(!unexpected);
+        assert_eq!(since, at(0));
+        assert_eq!(duration, ChronoDuration::minutes(15));
+
+        let mut digest = ColoDigest::new("NRT".to_string(), at(0));
+        digest.push("KIX", None);
+        digest.push("HKG", None);
+        assert_eq!(digest.bounces, 0);
+    }
+
+    #[test]
+    fn colo_change_notifications_respect_the_cooldown() {
+        let cooldown = ChronoDuration::minutes(30);
+        let mut state = LastSuccessState::new("https://example.com", AddressFamily::Any, at(0));
+        assert!(!state.take_notification_slot(cooldown, at(10)));
+        assert!(!state.take_notification_slot(cooldown, at(30)));
+        assert_eq!(state.last_notification_timestamp, at(0));
+
+        assert!(state.take_notification_slot(cooldown, at(31)));
+        assert_eq!(state.last_notification_timestamp, at(31));
+        assert!(!state.take_notification_slot(cooldown, at(45)));
+        assert!(state.take_notification_slot(cooldown, at(62)));
+    }
+
+    #[test]
+    fn consecutive_failures_form_one_incident() {
+        let results = [
+            check(0, Some("NRT")),
+            failure(5, ErrorKind::Connect, "refused"),
+            failure(10, ErrorKind::Timeout, "timed out"),
+            failure(12, ErrorKind::Timeout, "timed out"),
+            check(15, Some("KIX")),
+            check(20, Some("KIX")),
+        ];
+        let stats = detect_incidents(&results, at(30));
+        assert_eq!(stats.incidents.len(), 1);
+        let incident = &stats.incidents[0];
+        assert_eq!(incident.start, at(5));
+        assert_eq!(incident.end, Some(at(15)));
+        assert_eq!(incident.duration, ChronoDuration::minutes(10));
+        assert_eq!(incident.failed_checks, 3);
+        assert_eq!(
+            incident.error_kinds,
+            BTreeMap::from([(ErrorKind::Connect, 1), (ErrorKind::Timeout, 2)])
+        );
+        assert_eq!(incident.colo_before.as_deref(), Some("NRT"));
+        assert_eq!(incident.colo_after.as_deref(), Some("KIX"));
+
+        // 1 件なら MTTR はその復旧時間、MTBF は残りの稼働時間
+        assert_eq!(stats.mttr, Some(ChronoDuration::minutes(10)));
+        assert_eq!(stats.mtbf, Some(ChronoDuration::minutes(20)));
+        assert_eq!(stats.longest_outage, Some(ChronoDuration::minutes(10)));
+    }
+
+    #[test]
+    fn ongoing_incident_runs_to_the_end_of_the_period() {
+        let results = [
+            check(0, Some("NRT")),
+            failure(5, ErrorKind::Connect, "refused"),
+            failure(10, ErrorKind::Connect, "refused"),
+        ];
+        let stats = detect_incidents(&results, at(18));
+        assert_eq!(stats.incidents.len(), 1);
+        assert_eq!(stats.incidents[0].end, None);
+        assert_eq!(stats.incidents[0].colo_after, None);
+        assert_eq!(stats.incidents[0].duration, ChronoDuration::minutes(13));
+        // 復旧していない障害は MTTR に入らない
+        assert_eq!(stats.mttr, None);
+        assert_eq!(stats.mtbf, Some(ChronoDuration::minutes(5)));
+        assert_eq!(stats.longest_outage, Some(ChronoDuration::minutes(13)));
+    }
+
+    #[test]
+    fn mttr_and_mtbf_without_incidents_are_empty() {
+        for results in [vec![], vec![check(0, Some("NRT")), check(5, Some("NRT"))]] {
+            let stats = detect_incidents(&results, at(10));
+            assert!(stats.incidents.is_empty());
+            assert_eq!(stats.mttr, None);
+            assert_eq!(stats.mtbf, None);
+            assert_eq!(stats.longest_outage, None);
+        }
+    }
+
+    #[test]
+    fn mttr_and_mtbf_average_several_incidents() {
+        let mut results = vec![
+            check(0, Some("NRT")),
+            failure(5, ErrorKind::Connect, "refused"),
+            check(10, Some("NRT")),
+            failure(20, ErrorKind::Timeout, "timed out"),
+            failure(25, ErrorKind::Timeout, "timed out"),
+            check(40, Some("NRT")),
+        ];
+        let stats = detect_incidents(&results, at(60));
+        assert_eq!(stats.incidents.len(), 2);
+        // (5 分 + 20 分) / 2、(60 分 - 25 分) / 2
+        assert_eq!(stats.mttr, Some(ChronoDuration::seconds(750)));
+        assert_eq!(stats.mtbf, Some(ChronoDuration::seconds(1050)));
+        assert_eq!(stats.longest_outage, Some(ChronoDuration::minutes(20)));
+
+        // 継続中の障害は MTBF の件数と停止時間には入るが MTTR には入らない
+        results.push(failure(50, ErrorKind::Connect, "refused"));
+        let stats = detect_incidents(&results, at(60));
+        assert_eq!(stats.incidents.len(), 3);
+        assert_eq!(stats.mttr, Some(ChronoDuration::seconds(750)));
+        assert_eq!(stats.mtbf, Some(ChronoDuration::seconds(500)));
+    }
+
+    fn expectation(
+        colos: &[&str],
+        countries: &[&str],
+        continents: &[&str],
+        max_km: Option<f64>,
+    ) -> ColoExpectation {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect();
+        ColoExpectation {
+            colos: strings(colos),
+            countries: strings(countries),
+            continents: strings(continents),
+            max_distance: max_km.map(|max| (colo::lookup("NRT").unwrap().point(), max)),
+        }
+    }
+
+    #[test]
+    fn colo_expectation_checks_the_allow_list() {
+        let allowed = expectation(&["KIX"], &["SG"], &["EU"], None);
+        for colo in ["KIX", "SIN", "FRA"] {
+            assert!(allowed.check(colo).is_none(), "{}", colo);
+        }
+        for colo in ["NRT", "LAX"] {
+            let unexpected = allowed.check(colo).unwrap();
+            assert!(unexpected.not_allowed, "{}", colo);
+            assert_eq!(unexpected.distance_km, None);
+        }
+        // 許可リストがなければ何でも想定内
+        assert!(expectation(&[], &[], &[], None).check("LAX").is_none());
+    }
+
+    #[test]
+    fn colo_expectation_checks_the_distance_from_the_probe() {
+        let near = expectation(&[], &[], &[], Some(1000.0));
+        assert!(near.check("NRT").is_none());
+        assert!(near.check("KIX").is_none());
+        let unexpected = near.check("SIN").unwrap();
+        assert!(!unexpected.not_allowed);
+        let distance = unexpected.distance_km.unwrap();
+        assert!((5000.0..5600.0).contains(&distance), "{}", distance);
+
+        // 許可リストに当たっても遠すぎれば想定外
+        let both = expectation(&[], &["SG"], &[], Some(1000.0));
+        let unexpected = both.check("SIN").unwrap();
+        assert!(!unexpected.not_allowed);
+        assert!(unexpected.distance_km.is_some());
+    }
+
+    #[test]
+    fn colo_expectation_handles_unknown_colos() {
+        assert!(colo::lookup("ZZZ").is_none());
+        // 所在地が分からないので距離では判定しない
+        assert!(
+            expectation(&[], &[], &[], Some(1000.0))
+                .check("ZZZ")
+                .is_none()
+        );
+        // 国や大陸には当たらない
+        let unexpected = expectation(&[], &["JP"], &["AS"], Some(1000.0))
+            .check("ZZZ")
+            .unwrap();
+        assert!(unexpected.not_allowed);
+        assert_eq!(unexpected.distance_km, None);
+        // colo コードで明示すれば想定内
+        assert!(expectation(&["ZZZ"], &[], &[], None).check("ZZZ").is_none());
+    }
+
+    /// RTT が `ms` のサンプル (`None` は失敗)。本文にはサンプルの番号を入れる
+    fn sampling(warmup: Option<u64>, samples: &[Option<u64>]) -> Sampling {
+        let response = |ms: u64, body: String| ProbeResponse {
+            status: 200,
+            peer: "192.0.2.1".parse().unwrap(),
+            headers: Vec::new(),
+            body,
+            timings: PhaseTimings {
+                connect_millis: Some(ms),
+                ..Default::default()
+            },
+            total: Duration::from_millis(ms),
+        };
+        Sampling {
+            warmup: warmup.map(|ms| Ok(response(ms, "warmup".to_string()))),
+            samples: samples
+                .iter()
+                .enumerate()
+                .map(|(i, ms)| match ms {
+                    Some(ms) => Ok(response(*ms, i.to_string())),
+                    None => Err(anyhow::anyhow!("sample {} failed", i)),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn summarize_samples_reports_median_extremes_and_loss() {
+        // (サンプル, count, failures, min, median, max, loss_ratio, 代表の本文)
+        let cases = [
+            (vec![Some(30)], 1, 0, 30, 30, 30, 0.0, "0"),
+            (
+                vec![Some(30), Some(10), Some(20)],
+                3,
+                0,
+                10,
+                20,
+                30,
+                0.0,
+                "0",
+            ),
+            // 偶数個は中央 2 つの平均を丸める
+            (
+                vec![Some(10), Some(15), Some(40), Some(100)],
+                4,
+                0,
+                10,
+                28,
+                100,
+                0.0,
+                "0",
+            ),
+            // 失敗は RTT に入れず、代表は最初に成功したサンプル
+            (
+                vec![None, Some(50), None, Some(70)],
+                4,
+                2,
+                50,
+                60,
+                70,
+                0.5,
+                "1",
+            ),
+            (
+                vec![Some(12), None, None, None, None],
+                5,
+                4,
+                12,
+                12,
+                12,
+                0.8,
+                "0",
+            ),
+        ];
+        for (samples, count, failures, min, median, max, loss, body) in cases {
+            let (resp, phases, summary) = summarize_samples(sampling(Some(99), &samples)).unwrap();
+            assert_eq!(summary.count, count, "{:?}", samples);
+            assert_eq!(summary.failures, failures, "{:?}", samples);
+            assert_eq!(summary.min_millis, min, "{:?}", samples);
+            assert_eq!(summary.median_millis, median, "{:?}", samples);
+            assert_eq!(summary.max_millis, max, "{:?}", samples);
+            assert_eq!(summary.loss_ratio, loss, "{:?}", samples);
+            assert_eq!(resp.body, body, "{:?}", samples);
+            // フェーズ別の時間はウォームアップのもの
+            assert_eq!(phases.connect_millis, Some(99), "{:?}", samples);
+        }
+
+        // ウォームアップがなければ代表のサンプルのフェーズを使う
+        let (_, phases, _) = summarize_samples(sampling(None, &[None, Some(50)])).unwrap();
+        assert_eq!(phases.connect_millis, Some(50));
+    }
+
+    #[test]
+    fn summarize_samples_fails_when_every_sample_failed() {
+        let err = summarize_samples(sampling(Some(99), &[None, None, None])).unwrap_err();
+        assert_eq!(err.to_string(), "sample 2 failed");
+        let err = summarize_samples(sampling(None, &[])).unwrap_err();
+        assert_eq!(err.to_string(), "No samples were taken");
+    }
 }
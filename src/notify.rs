@@ -0,0 +1,2363 @@
+//! 通知先 (sink) の抽象化。
+//!
+//! colo 変更・死活/RTT アラート・定期レポートはすべて [`Notifiers`] を経由して送る。
+//! sink ごとに書式 ([`Markup`])、リトライ方針、有効/無効を持ち、`[[notifiers]]` で複数並べられる。
+
+use crate::colo;
+use crate::i18n::Locale;
+use crate::outbox::{Outbox, OutboxEntry};
+use crate::probe::{AddressFamily, ErrorKind};
+use crate::template::Templates;
+use crate::{Report, format_chrono_duration, format_report_markup};
+use anyhow::Result;
+use chrono::{DateTime, Duration as ChronoDuration, Local, Utc};
+use futures::future::BoxFuture;
+use humantime::format_duration;
+use lettre::message::{Mailbox, MultiPart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rand::{Rng, rng};
+use reqwest::{Client, RequestBuilder};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Semaphore, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time;
+use url::Url;
+
+/// `[[notifiers]]` の 1 エントリ
+#[derive(Debug, Deserialize, Clone)]
+pub struct SinkSettings {
+    /// ターゲットの `notify` で指定する名前
+    pub name: String,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    /// 定期レポートもこの sink に送るか
+    #[serde(default = "default_true")]
+    pub reports: bool,
+    /// 未指定なら sink の種類ごとの既定値
+    pub max_attempts: Option<u32>,
+    pub retry_initial_delay_ms: Option<u64>,
+    /// イベント 1 件分を描画するテンプレートファイル (未指定なら組み込みの書式)
+    pub event_template: Option<String>,
+    /// レポートを描画するテンプレートファイル
+    pub report_template: Option<String>,
+    /// 組み込みの書式の言語 (未指定ならグローバルの `locale`)
+    pub locale: Option<Locale>,
+    #[serde(flatten)]
+    pub kind: SinkKind,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_visibility() -> String {
+    "home".to_string()
+}
+
+fn default_ntfy_server() -> String {
+    "https://ntfy.sh".to_string()
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum SinkKind {
+    Misskey {
+        url: String,
+        token: String,
+        #[serde(default = "default_visibility")]
+        visibility: String,
+    },
+    Discord {
+        webhook_url: String,
+        username: Option<String>,
+    },
+    Slack {
+        webhook_url: String,
+    },
+    Ntfy {
+        #[serde(default = "default_ntfy_server")]
+        server: String,
+        topic: String,
+        token: Option<String>,
+        /// 1 (min) - 5 (max)
+        priority: Option<u8>,
+    },
+    /// 任意の URL に JSON を POST する
+    Webhook {
+        url: String,
+        #[serde(default)]
+        headers: BTreeMap<String, String>,
+    },
+    /// Matrix のルームに client-server API で投稿する
+    Matrix {
+        homeserver: String,
+        /// `!room:example.org` (エイリアス `#room:example.org` は不可)
+        room_id: String,
+        access_token: String,
+        /// `m.notice` (bot 向け) か `m.text`
+        #[serde(default = "default_matrix_msgtype")]
+        msgtype: String,
+    },
+    /// Mastodon 互換 API (`/api/v1/statuses`) で投稿する
+    Mastodon {
+        url: String,
+        token: String,
+        /// "public", "unlisted", "private", "direct"
+        #[serde(default = "default_mastodon_visibility")]
+        visibility: String,
+        /// 1 投稿の上限文字数。超える分はリプライのスレッドに分ける
+        #[serde(default = "default_mastodon_max_chars")]
+        max_chars: usize,
+        /// 本文を折りたたむ CW
+        spoiler_text: Option<String>,
+    },
+    /// SMTP でメールを送る (テキストと HTML の multipart/alternative)
+    Email {
+        host: String,
+        /// 未指定なら `tls` に応じて 465 / 587 / 25
+        port: Option<u16>,
+        #[serde(default)]
+        tls: SmtpTls,
+        username: Option<String>,
+        password: Option<String>,
+        from: String,
+        to: Vec<String>,
+        #[serde(default = "default_subject_prefix")]
+        subject_prefix: String,
+        timeout_seconds: Option<u64>,
+    },
+}
+
+fn default_matrix_msgtype() -> String {
+    "m.notice".to_string()
+}
+
+fn default_mastodon_visibility() -> String {
+    "unlisted".to_string()
+}
+
+fn default_mastodon_max_chars() -> usize {
+    500
+}
+
+fn default_subject_prefix() -> String {
+    "[tracekey]".to_string()
+}
+
+/// SMTP サーバーとの接続方式
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SmtpTls {
+    #[default]
+    Starttls,
+    /// 接続直後から TLS (SMTPS)
+    Implicit,
+    /// 平文 (ローカルのテスト用 SMTP サーバー向け)
+    None,
+}
+
+/// 送信失敗時の再試行回数と初回の待ち時間 (以降は倍々 + 揺らぎ)
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub initial_delay: Duration,
+}
+
+/// 1 回の送信の失敗。`Retryable` のときだけリトライする。
+#[derive(Debug)]
+pub enum SendError {
+    Retryable(anyhow::Error),
+    Fatal(anyhow::Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SendError::Retryable(e) | SendError::Fatal(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl From<reqwest::Error> for SendError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_builder() {
+            SendError::Fatal(e.into())
+        } else {
+            SendError::Retryable(e.into())
+        }
+    }
+}
+
+/// 通知の中身。書式は sink ごとに [`Notifier::render`] で決まる。
+pub enum Notification<'a> {
+    Events {
+        /// ログ用の種別 ("colo change" など)
+        what: &'a str,
+        events: &'a [Event],
+    },
+    Report(&'a Report),
+    /// ユーザー定義テンプレートで作った本文。`source` は webhook の構造化データなどに使う
+    Templated {
+        text: &'a str,
+        source: &'a Notification<'a>,
+    },
+}
+
+impl Notification<'_> {
+    fn what(&self) -> &str {
+        match self {
+            Notification::Events { what, .. } => what,
+            Notification::Report(_) => "report",
+            Notification::Templated { source, .. } => source.what(),
+        }
+    }
+}
+
+/// 通知に載せるターゲットの情報
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TargetRef {
+    pub name: String,
+    pub url: String,
+    #[serde(default, skip_serializing_if = "AddressFamily::is_any")]
+    pub address_family: AddressFamily,
+}
+
+/// 即時通知するイベント。webhook にはこのまま JSON で送り、アウトボックスにもこの形で残す。
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    ColoChange {
+        target: TargetRef,
+        prev_colo: String,
+        curr_colo: String,
+        rtt_millis: Option<u64>,
+        /// 想定外の colo (`expected_*` の範囲外か `max_colo_distance_km` より遠い) に切り替わった
+        unexpected: bool,
+    },
+    /// digest モードで窓の間にまとめた colo 変更
+    ColoDigest {
+        target: TargetRef,
+        from_colo: String,
+        to_colo: String,
+        /// 切り替わった先の colo (順番どおり)
+        path: Vec<String>,
+        /// 一度いた colo へ戻った回数
+        bounces: u32,
+        rtt_millis: Option<u64>,
+        unexpected: bool,
+        since: DateTime<Utc>,
+        #[serde(
+            rename = "duration_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        duration: ChronoDuration,
+    },
+    /// colo のばたつきが始まった (収まるまで個別の変更は通知しない)
+    ColoFlapping {
+        target: TargetRef,
+        /// 判定の窓の中での変更回数
+        changes: u32,
+        /// 行き来している colo
+        colos: Vec<String>,
+        #[serde(
+            rename = "window_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        window: ChronoDuration,
+        since: DateTime<Utc>,
+    },
+    /// ばたつきが収まった
+    ColoFlappingStopped {
+        target: TargetRef,
+        /// 落ち着いた先の colo
+        colo: String,
+        /// ばたつき中の変更回数
+        changes: u32,
+        since: DateTime<Utc>,
+        #[serde(
+            rename = "duration_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        duration: ChronoDuration,
+    },
+    /// 想定外の colo に載った
+    UnexpectedColo {
+        target: TargetRef,
+        colo: String,
+        /// `expected_colos` / `expected_countries` / `expected_continents` のどれにも当たらない
+        not_allowed: bool,
+        /// プローブの位置からの距離 (`max_colo_distance_km` を設定していて所在地が分かるときだけ)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        distance_km: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        max_distance_km: Option<u64>,
+        rtt_millis: Option<u64>,
+    },
+    /// 想定内の colo に戻った
+    UnexpectedColoCleared {
+        target: TargetRef,
+        colo: String,
+        since: DateTime<Utc>,
+        #[serde(
+            rename = "duration_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        duration: ChronoDuration,
+    },
+    Down {
+        target: TargetRef,
+        kind: ErrorKind,
+        /// 最後に失敗したチェックのエラーメッセージ
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+        failures: u32,
+        since: DateTime<Utc>,
+        #[serde(
+            rename = "duration_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        duration: ChronoDuration,
+    },
+    Recovered {
+        target: TargetRef,
+        kind: Option<ErrorKind>,
+        since: DateTime<Utc>,
+        #[serde(
+            rename = "duration_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        duration: ChronoDuration,
+    },
+    RttHigh {
+        target: TargetRef,
+        median_millis: u64,
+        window: usize,
+        trigger_ms: u64,
+    },
+    RttCleared {
+        target: TargetRef,
+        median_millis: u64,
+        clear_ms: u64,
+        since: DateTime<Utc>,
+        #[serde(
+            rename = "duration_secs",
+            serialize_with = "serialize_secs",
+            deserialize_with = "deserialize_secs"
+        )]
+        duration: ChronoDuration,
+    },
+}
+
+/// 期間は秒数で書き出す (テンプレートでは `duration` フィルタで整形できる)
+pub fn serialize_secs<S: Serializer>(d: &ChronoDuration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_i64(d.num_seconds())
+}
+
+pub fn serialize_opt_secs<S: Serializer>(
+    d: &Option<ChronoDuration>,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => s.serialize_some(&d.num_seconds()),
+        None => s.serialize_none(),
+    }
+}
+
+fn deserialize_secs<'de, D: Deserializer<'de>>(d: D) -> Result<ChronoDuration, D::Error> {
+    i64::deserialize(d).map(ChronoDuration::seconds)
+}
+
+impl Event {
+    pub fn target(&self) -> &TargetRef {
+        match self {
+            Event::ColoChange { target, .. }
+            | Event::ColoDigest { target, .. }
+            | Event::ColoFlapping { target, .. }
+            | Event::ColoFlappingStopped { target, .. }
+            | Event::UnexpectedColo { target, .. }
+            | Event::UnexpectedColoCleared { target, .. }
+            | Event::Down { target, .. }
+            | Event::Recovered { target, .. }
+            | Event::RttHigh { target, .. }
+            | Event::RttCleared { target, .. } => target,
+        }
+    }
+}
+
+/// sink ごとの文字装飾の方言
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Markup {
+    /// Misskey
+    Mfm,
+    /// Discord の Markdown
+    Discord,
+    /// Slack の mrkdwn
+    Slack,
+    /// メール本文などの HTML 断片
+    Html,
+    Plain,
+}
+
+impl Markup {
+    pub fn bold(self, s: &str) -> String {
+        match self {
+            Markup::Mfm | Markup::Discord => format!("**{}**", s),
+            Markup::Slack => format!("*{}*", s),
+            Markup::Html => format!("<b>{}</b>", s),
+            Markup::Plain => s.to_string(),
+        }
+    }
+
+    pub fn link(self, text: &str, url: &str) -> String {
+        match self {
+            Markup::Mfm => format!("?[{}]({})", text, url),
+            // <> で囲むと埋め込みプレビューが付かない
+            Markup::Discord => format!("[{}](<{}>)", text, url),
+            Markup::Slack => format!("<{}|{}>", url, text),
+            Markup::Html => format!("<a href=\"{}\">{}</a>", escape_html(url), escape_html(text)),
+            Markup::Plain => format!("{} ({})", text, url),
+        }
+    }
+
+    pub fn code(self, s: &str) -> String {
+        match self {
+            Markup::Plain => s.to_string(),
+            Markup::Html => format!("<code>{}</code>", escape_html(s)),
+            _ => format!("`{}`", s),
+        }
+    }
+
+    pub fn small(self, s: &str) -> String {
+        match self {
+            Markup::Mfm | Markup::Html => format!("<small>{}</small>", s),
+            _ => s.to_string(),
+        }
+    }
+
+    /// 背景色付きのラベル。MFM 以外はコード表記にする。
+    pub fn badge(self, color: &str, s: &str) -> String {
+        match self {
+            Markup::Mfm => format!(
+                "$[border.color=0000,radius=10 $[bg.color={} $[fg.color=fff  {} ]]]",
+                color, s
+            ),
+            _ => self.code(s),
+        }
+    }
+}
+
+/// `link` / `code` に渡す生の文字列のエスケープ。`bold` などは整形済みの断片を受け取るのでしない。
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn family_suffix(target: &TargetRef, m: Markup) -> String {
+    if target.address_family.is_any() {
+        String::new()
+    } else {
+        format!(" {}", m.small(&target.address_family.to_string()))
+    }
+}
+
+/// RTT を色付きのバッジにする。
+fn rtt_badge(rtt_millis: Option<u64>, m: Markup) -> String {
+    let (rtt_color, rtt_text, rtt_unit): (&str, String, &str) = match rtt_millis {
+        Some(ms @ 0..=299) => ("3a3", ms.to_string(), "ms"), // green
+        Some(ms @ 300..=499) => ("991", ms.to_string(), "ms"), // yellow
+        Some(ms @ 500..=999) => ("c52", ms.to_string(), "ms"), // orange
+        Some(ms) => ("b22", ms.to_string(), "ms"),           // red
+        None => ("999", "N/A".into(), ""),                   // gray for no data
+    };
+    m.badge(rtt_color, &format!("{}{}", rtt_text, m.small(rtt_unit)))
+}
+
+/// colo の所在地を小さく添える。表にないコードには何も付けない。
+fn colo_place(code: &str, m: Markup) -> String {
+    let Some(info) = colo::lookup(code) else {
+        return String::new();
+    };
+    // 上書きファイルの地名はそのまま埋め込まない
+    let place = match m {
+        Markup::Html => escape_html(&info.place()),
+        _ => info.place(),
+    };
+    format!(" {}", m.small(&place))
+}
+
+/// イベント 1 件を 1 行に整形する。
+pub fn render_event(event: &Event, m: Markup, locale: Locale) -> String {
+    let msg = locale.messages();
+    let target = event.target();
+    let link = format!(
+        "{}{}",
+        m.link(&target.name, &target.url),
+        family_suffix(target, m)
+    );
+    match event {
+        Event::ColoChange {
+            prev_colo,
+            curr_colo,
+            rtt_millis,
+            unexpected,
+            ..
+        } => format!(
+            "{}→{}{}{} {} {}",
+            m.small(&m.code(prev_colo)),
+            m.code(curr_colo),
+            colo_place(curr_colo, m),
+            if *unexpected { "⚠️" } else { "" },
+            rtt_badge(*rtt_millis, m),
+            link
+        ),
+        Event::ColoDigest {
+            from_colo,
+            to_colo,
+            path,
+            bounces,
+            rtt_millis,
+            unexpected,
+            duration,
+            ..
+        } => format!(
+            "🔀 {}→{}{}{} {} {} {} {}",
+            m.small(&m.code(from_colo)),
+            m.code(to_colo),
+            colo_place(to_colo, m),
+            if *unexpected { "⚠️" } else { "" },
+            rtt_badge(*rtt_millis, m),
+            link,
+            (msg.colo_digest_detail)(path.len(), *bounces, &format_chrono_duration(*duration)),
+            m.small(&format!("{}→{}", from_colo, path.join("→")))
+        ),
+        Event::ColoFlapping {
+            changes,
+            colos,
+            window,
+            ..
+        } => format!(
+            "🔁 {} {} {} {}",
+            m.bold(msg.colo_flapping),
+            link,
+            colos
+                .iter()
+                .map(|c| m.code(c))
+                .collect::<Vec<_>>()
+                .join("⇄"),
+            (msg.colo_flapping_detail)(*changes, &format_chrono_duration(*window))
+        ),
+        Event::ColoFlappingStopped {
+            colo,
+            changes,
+            duration,
+            ..
+        } => format!(
+            "✅ {} {} {}{} {}",
+            m.bold(msg.colo_flapping_stopped),
+            link,
+            m.code(colo),
+            colo_place(colo, m),
+            (msg.colo_flapping_stopped_detail)(*changes, &format_chrono_duration(*duration))
+        ),
+        Event::UnexpectedColo {
+            colo,
+            not_allowed,
+            distance_km,
+            max_distance_km,
+            rtt_millis,
+            ..
+        } => {
+            let mut details = Vec::new();
+            if *not_allowed {
+                details.push(msg.unexpected_colo_outside.to_string());
+            }
+            if let (Some(distance), Some(max)) = (distance_km, max_distance_km) {
+                details.push((msg.unexpected_colo_distance)(*distance, *max));
+            }
+            format!(
+                "🧭 {} {} {}{} {} {}",
+                m.bold(msg.unexpected_colo),
+                link,
+                m.code(colo),
+                colo_place(colo, m),
+                rtt_badge(*rtt_millis, m),
+                details.join(", ")
+            )
+        }
+        Event::UnexpectedColoCleared { colo, duration, .. } => format!(
+            "✅ {} {} {}{} {}",
+            m.bold(msg.unexpected_colo_cleared),
+            link,
+            m.code(colo),
+            colo_place(colo, m),
+            (msg.unexpected_colo_cleared_detail)(&format_chrono_duration(*duration))
+        ),
+        Event::Down {
+            kind,
+            failures,
+            since,
+            duration,
+            ..
+        } => format!(
+            "🔴 {} {} {} {}",
+            m.bold("DOWN"),
+            link,
+            m.code(&kind.to_string()),
+            (msg.down_detail)(
+                *failures,
+                &since.with_timezone(&Local).format("%H:%M:%S").to_string(),
+                &format_chrono_duration(*duration)
+            )
+        ),
+        Event::Recovered { kind, duration, .. } => format!(
+            "🟢 {} {} {}",
+            m.bold("RECOVERED"),
+            link,
+            (msg.recovered_detail)(
+                &format_chrono_duration(*duration),
+                &m.code(&kind.unwrap_or(ErrorKind::Other).to_string())
+            )
+        ),
+        Event::RttHigh {
+            median_millis,
+            window,
+            trigger_ms,
+            ..
+        } => format!(
+            "🐢 {} {} {}",
+            m.bold(msg.rtt_high),
+            link,
+            (msg.rtt_high_detail)(*window, *median_millis, *trigger_ms)
+        ),
+        Event::RttCleared {
+            median_millis,
+            clear_ms,
+            duration,
+            ..
+        } => format!(
+            "✅ {} {} {}",
+            m.bold(msg.rtt_cleared),
+            link,
+            (msg.rtt_cleared_detail)(
+                *median_millis,
+                *clear_ms,
+                &format_chrono_duration(*duration)
+            )
+        ),
+    }
+}
+
+/// 通知全体を指定の方言で整形する。
+fn render_with(notification: &Notification, m: Markup, locale: Locale) -> String {
+    match notification {
+        Notification::Events { events, .. } => events
+            .iter()
+            .map(|e| render_event(e, m, locale))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        Notification::Report(report) => format_report_markup(report, m, locale),
+        // テンプレートの出力はそのまま使う (HTML にするときだけエスケープ)
+        Notification::Templated { text, .. } if m == Markup::Html => escape_html(text),
+        Notification::Templated { text, .. } => text.to_string(),
+    }
+}
+
+/// 通知先の実装。書式と 1 回分の送信だけを受け持ち、リトライは [`Sink`] が行う。
+pub trait Notifier: Send + Sync {
+    /// ログ用の種類名
+    fn kind(&self) -> &'static str;
+
+    /// 設定で上書きされなかったときのリトライ方針
+    fn default_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn render(&self, notification: &Notification) -> String;
+
+    /// 1 回分の送信。`delivery_id` は同じ通知のリトライ間で変わらないので、冪等キーに使える。
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>>;
+}
+
+/// リクエストを送り、ステータスで成否とリトライ可否を判定する。
+/// 429 と 5xx はリトライし、それ以外のクライアントエラーは即失敗とする。
+async fn send_request(request: RequestBuilder) -> Result<(), SendError> {
+    send_request_for_body(request).await.map(|_| ())
+}
+
+/// `send_request` と同じ判定をして、成功時はレスポンスボディを返す。
+async fn send_request_for_body(request: RequestBuilder) -> Result<String, SendError> {
+    let resp = request.send().await?;
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp.text().await?);
+    }
+    let error_text = resp.text().await.unwrap_or_else(|_| "No body".to_string());
+    let err = anyhow::anyhow!("status {} - {}", status, error_text);
+    if status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(SendError::Fatal(err))
+    } else {
+        Err(SendError::Retryable(err))
+    }
+}
+
+struct MisskeyNotifier {
+    api_url: String,
+    token: String,
+    visibility: String,
+    locale: Locale,
+}
+
+impl Notifier for MisskeyNotifier {
+    fn kind(&self) -> &'static str {
+        "misskey"
+    }
+
+    fn default_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Mfm, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        _delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        let mut params = BTreeMap::new();
+        params.insert("i", self.token.clone());
+        params.insert("text", self.render(notification));
+        params.insert("visibility", self.visibility.clone());
+        Box::pin(send_request(client.post(&self.api_url).json(&params)))
+    }
+}
+
+/// Discord の `content` の上限文字数
+const DISCORD_MAX_CONTENT: usize = 2000;
+
+struct DiscordNotifier {
+    webhook_url: String,
+    username: Option<String>,
+    locale: Locale,
+}
+
+impl Notifier for DiscordNotifier {
+    fn kind(&self) -> &'static str {
+        "discord"
+    }
+
+    fn default_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        truncate_chars(
+            render_with(notification, Markup::Discord, self.locale),
+            DISCORD_MAX_CONTENT,
+        )
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        _delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        let mut body = serde_json::json!({ "content": self.render(notification) });
+        if let Some(username) = &self.username {
+            body["username"] = username.clone().into();
+        }
+        Box::pin(send_request(client.post(&self.webhook_url).json(&body)))
+    }
+}
+
+/// 上限を超えた分を切り詰めて末尾に "…" を付ける。
+fn truncate_chars(text: String, max: usize) -> String {
+    if text.chars().count() <= max {
+        return text;
+    }
+    let mut truncated: String = text.chars().take(max.saturating_sub(1)).collect();
+    truncated.push('…');
+    truncated
+}
+
+struct SlackNotifier {
+    webhook_url: String,
+    locale: Locale,
+}
+
+impl Notifier for SlackNotifier {
+    fn kind(&self) -> &'static str {
+        "slack"
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Slack, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        _delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        let body = serde_json::json!({ "text": self.render(notification) });
+        Box::pin(send_request(client.post(&self.webhook_url).json(&body)))
+    }
+}
+
+struct NtfyNotifier {
+    topic_url: String,
+    token: Option<String>,
+    priority: Option<u8>,
+    locale: Locale,
+}
+
+impl Notifier for NtfyNotifier {
+    fn kind(&self) -> &'static str {
+        "ntfy"
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Plain, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        _delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        let mut request = client
+            .post(&self.topic_url)
+            .header("Title", format!("tracekey: {}", notification.what()))
+            .body(self.render(notification));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(priority) = self.priority {
+            request = request.header("Priority", priority.to_string());
+        }
+        Box::pin(send_request(request))
+    }
+}
+
+struct WebhookNotifier {
+    url: String,
+    headers: Vec<(String, String)>,
+    locale: Locale,
+}
+
+/// 汎用 webhook に送る JSON
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum WebhookPayload<'a> {
+    Events {
+        what: &'a str,
+        text: String,
+        events: &'a [Event],
+    },
+    /// レポートのテンプレートと同じ中身 (`targets` に系列ごとの遷移表やタイムラインを含む) を並べる
+    Report {
+        text: String,
+        #[serde(flatten)]
+        report: &'a Report,
+    },
+}
+
+fn webhook_payload<'a>(notification: &'a Notification, text: String) -> WebhookPayload<'a> {
+    match notification {
+        Notification::Events { what, events } => WebhookPayload::Events { what, text, events },
+        Notification::Report(report) => WebhookPayload::Report { text, report },
+        Notification::Templated { source, .. } => webhook_payload(source, text),
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn kind(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Plain, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        let payload = webhook_payload(notification, self.render(notification));
+        // 受け手が再送を見分けられるよう、リトライ間で変わらない ID を付ける
+        let mut request = client
+            .post(&self.url)
+            .header("X-Tracekey-Delivery", delivery_id)
+            .json(&payload);
+        for (key, value) in &self.headers {
+            request = request.header(key, value);
+        }
+        Box::pin(send_request(request))
+    }
+}
+
+struct MatrixNotifier {
+    /// `.../rooms/{room_id}/send/m.room.message` (末尾にトランザクション ID を付ける)
+    send_url: Url,
+    access_token: String,
+    msgtype: String,
+    locale: Locale,
+}
+
+impl Notifier for MatrixNotifier {
+    fn kind(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn default_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Plain, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        // 同じトランザクション ID の再送はサーバー側で重複排除される
+        let mut url = self.send_url.clone();
+        if let Ok(mut segments) = url.path_segments_mut() {
+            segments.push(delivery_id);
+        }
+        let body = serde_json::json!({
+            "msgtype": self.msgtype,
+            "body": self.render(notification),
+            "format": "org.matrix.custom.html",
+            "formatted_body": render_with(notification, Markup::Html, self.locale).replace('\n', "<br>\n"),
+        });
+        Box::pin(send_request(
+            client.put(url).bearer_auth(&self.access_token).json(&body),
+        ))
+    }
+}
+
+struct MastodonNotifier {
+    api_url: String,
+    token: String,
+    visibility: String,
+    max_chars: usize,
+    spoiler_text: Option<String>,
+    locale: Locale,
+}
+
+/// 行単位で `max_chars` 以内の塊に分ける。1 行で超える場合はその行を切り詰める。
+fn split_chunks(text: &str, max_chars: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+    for line in text.lines() {
+        let line = truncate_chars(line.to_string(), max_chars);
+        let needed = line.chars().count() + usize::from(!current.is_empty());
+        if !current.is_empty() && current.chars().count() + needed > max_chars {
+            chunks.push(std::mem::take(&mut current).trim_end().to_string());
+        }
+        if !current.is_empty() {
+            current.push('\n');
+        }
+        current.push_str(&line);
+    }
+    if !current.trim().is_empty() {
+        chunks.push(current.trim_end().to_string());
+    }
+    chunks
+}
+
+impl Notifier for MastodonNotifier {
+    fn kind(&self) -> &'static str {
+        "mastodon"
+    }
+
+    fn default_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Plain, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        client: &'a Client,
+        notification: &'a Notification,
+        delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        Box::pin(async move {
+            // 長い本文はリプライでつなぐ。Idempotency-Key により、リトライ時は
+            // 投稿済みの分が同じ ID で返ってくるので続きから投稿される
+            let mut in_reply_to: Option<String> = None;
+            for (i, chunk) in split_chunks(&self.render(notification), self.max_chars)
+                .into_iter()
+                .enumerate()
+            {
+                let mut body = serde_json::json!({
+                    "status": chunk,
+                    "visibility": self.visibility,
+                });
+                if let Some(spoiler) = &self.spoiler_text {
+                    body["spoiler_text"] = spoiler.clone().into();
+                }
+                if let Some(id) = &in_reply_to {
+                    body["in_reply_to_id"] = id.clone().into();
+                }
+                let resp = send_request_for_body(
+                    client
+                        .post(&self.api_url)
+                        .bearer_auth(&self.token)
+                        .header("Idempotency-Key", format!("{}-{}", delivery_id, i))
+                        .json(&body),
+                )
+                .await?;
+                let status: serde_json::Value = serde_json::from_str(&resp).map_err(|e| {
+                    SendError::Fatal(anyhow::anyhow!("Invalid status response: {}", e))
+                })?;
+                in_reply_to = status["id"].as_str().map(str::to_string);
+            }
+            Ok(())
+        })
+    }
+}
+
+struct EmailNotifier {
+    transport: AsyncSmtpTransport<Tokio1Executor>,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    subject_prefix: String,
+    locale: Locale,
+}
+
+impl EmailNotifier {
+    fn subject(&self, notification: &Notification) -> String {
+        match notification {
+            Notification::Events { what, events } => {
+                format!(
+                    "{} {} ({})",
+                    self.subject_prefix,
+                    (self.locale.messages().events_subject)(what),
+                    events.len()
+                )
+            }
+            Notification::Report(report) => format!(
+                "{} {} {}",
+                self.subject_prefix,
+                self.locale.messages().report_subject,
+                report.until.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+            ),
+            Notification::Templated { source, .. } => self.subject(source),
+        }
+    }
+
+    fn render_html(&self, notification: &Notification) -> String {
+        format!(
+            "<!DOCTYPE html>\n<html><body>\n{}\n</body></html>\n",
+            render_with(notification, Markup::Html, self.locale).replace('\n', "<br>\n")
+        )
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn kind(&self) -> &'static str {
+        "email"
+    }
+
+    fn default_retry(&self) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn render(&self, notification: &Notification) -> String {
+        render_with(notification, Markup::Plain, self.locale)
+    }
+
+    fn send<'a>(
+        &'a self,
+        _client: &'a Client,
+        notification: &'a Notification,
+        _delivery_id: &'a str,
+    ) -> BoxFuture<'a, Result<(), SendError>> {
+        Box::pin(async move {
+            let mut builder = Message::builder()
+                .from(self.from.clone())
+                .subject(self.subject(notification));
+            for to in &self.to {
+                builder = builder.to(to.clone());
+            }
+            let message = builder
+                .multipart(MultiPart::alternative_plain_html(
+                    self.render(notification),
+                    self.render_html(notification),
+                ))
+                .map_err(|e| SendError::Fatal(e.into()))?;
+            match self.transport.send(message).await {
+                Ok(_) => Ok(()),
+                // 5xx 応答や認証・アドレスの誤りは再送しても通らない
+                Err(e) if e.is_permanent() || e.is_client() => Err(SendError::Fatal(e.into())),
+                Err(e) => Err(SendError::Retryable(e.into())),
+            }
+        })
+    }
+}
+
+fn parse_mailbox(address: &str) -> Result<Mailbox> {
+    address
+        .parse()
+        .map_err(|e| anyhow::anyhow!("Invalid email address {}: {}", address, e))
+}
+
+/// 設定から組み立てた通知先
+pub struct Sink {
+    pub name: String,
+    pub enabled: bool,
+    pub reports: bool,
+    retry: RetryPolicy,
+    notifier: Box<dyn Notifier>,
+    templates: Option<Templates>,
+    locale: Locale,
+}
+
+impl Sink {
+    pub fn from_settings(settings: &SinkSettings, default_locale: Locale) -> Result<Self> {
+        let locale = settings.locale.unwrap_or(default_locale);
+        let notifier: Box<dyn Notifier> = match &settings.kind {
+            SinkKind::Misskey {
+                url,
+                token,
+                visibility,
+            } => Box::new(MisskeyNotifier {
+                api_url: Url::parse(url)?.join("/api/notes/create")?.to_string(),
+                token: token.clone(),
+                visibility: visibility.clone(),
+                locale,
+            }),
+            SinkKind::Discord {
+                webhook_url,
+                username,
+            } => Box::new(DiscordNotifier {
+                webhook_url: Url::parse(webhook_url)?.to_string(),
+                username: username.clone(),
+                locale,
+            }),
+            SinkKind::Slack { webhook_url } => Box::new(SlackNotifier {
+                webhook_url: Url::parse(webhook_url)?.to_string(),
+                locale,
+            }),
+            SinkKind::Ntfy {
+                server,
+                topic,
+                token,
+                priority,
+            } => {
+                if topic.is_empty() {
+                    anyhow::bail!("ntfy topic must not be empty");
+                }
+                if let Some(p) = priority
+                    && !(1..=5).contains(p)
+                {
+                    anyhow::bail!("ntfy priority must be between 1 and 5 (got {})", p);
+                }
+                Box::new(NtfyNotifier {
+                    topic_url: Url::parse(&format!("{}/{}", server.trim_end_matches('/'), topic))?
+                        .to_string(),
+                    token: token.clone().filter(|t| !t.is_empty()),
+                    priority: *priority,
+                    locale,
+                })
+            }
+            SinkKind::Webhook { url, headers } => Box::new(WebhookNotifier {
+                url: Url::parse(url)?.to_string(),
+                headers: headers
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+                locale,
+            }),
+            SinkKind::Matrix {
+                homeserver,
+                room_id,
+                access_token,
+                msgtype,
+            } => {
+                if !room_id.starts_with('!') {
+                    anyhow::bail!("matrix room_id must be a room ID like !room:example.org");
+                }
+                let mut send_url = Url::parse(homeserver)?;
+                send_url
+                    .path_segments_mut()
+                    .map_err(|_| anyhow::anyhow!("Invalid homeserver URL {}", homeserver))?
+                    .pop_if_empty()
+                    .extend([
+                        "_matrix",
+                        "client",
+                        "v3",
+                        "rooms",
+                        room_id,
+                        "send",
+                        "m.room.message",
+                    ]);
+                Box::new(MatrixNotifier {
+                    send_url,
+                    access_token: access_token.clone(),
+                    msgtype: msgtype.clone(),
+                    locale,
+                })
+            }
+            SinkKind::Mastodon {
+                url,
+                token,
+                visibility,
+                max_chars,
+                spoiler_text,
+            } => {
+                if *max_chars == 0 {
+                    anyhow::bail!("mastodon max_chars cannot be 0");
+                }
+                Box::new(MastodonNotifier {
+                    api_url: Url::parse(url)?.join("/api/v1/statuses")?.to_string(),
+                    token: token.clone(),
+                    visibility: visibility.clone(),
+                    max_chars: *max_chars,
+                    spoiler_text: spoiler_text.clone().filter(|s| !s.is_empty()),
+                    locale,
+                })
+            }
+            SinkKind::Email {
+                host,
+                port,
+                tls,
+                username,
+                password,
+                from,
+                to,
+                subject_prefix,
+                timeout_seconds,
+            } => {
+                if to.is_empty() {
+                    anyhow::bail!("email recipients (to) must not be empty");
+                }
+                let mut builder = match tls {
+                    SmtpTls::Starttls => {
+                        AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(host)?
+                    }
+                    SmtpTls::Implicit => AsyncSmtpTransport::<Tokio1Executor>::relay(host)?,
+                    SmtpTls::None => AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(host),
+                };
+                if let Some(port) = port {
+                    builder = builder.port(*port);
+                }
+                if let Some(secs) = timeout_seconds {
+                    builder = builder.timeout(Some(Duration::from_secs(*secs)));
+                }
+                match (username, password) {
+                    (Some(user), Some(pass)) => {
+                        builder = builder.credentials(Credentials::new(user.clone(), pass.clone()));
+                    }
+                    (None, None) => {}
+                    _ => anyhow::bail!("email username and password must be set together"),
+                }
+                Box::new(EmailNotifier {
+                    transport: builder.build(),
+                    from: parse_mailbox(from)?,
+                    to: to
+                        .iter()
+                        .map(|addr| parse_mailbox(addr))
+                        .collect::<Result<_>>()?,
+                    subject_prefix: subject_prefix.clone(),
+                    locale,
+                })
+            }
+        };
+
+        let default_retry = notifier.default_retry();
+        let retry = RetryPolicy {
+            max_attempts: settings.max_attempts.unwrap_or(default_retry.max_attempts),
+            initial_delay: settings
+                .retry_initial_delay_ms
+                .map_or(default_retry.initial_delay, Duration::from_millis),
+        };
+        if retry.max_attempts == 0 {
+            anyhow::bail!("max_attempts cannot be 0");
+        }
+        let templates = Templates::load(
+            settings.event_template.as_deref(),
+            settings.report_template.as_deref(),
+        )?;
+        Ok(Self {
+            name: settings.name.clone(),
+            enabled: settings.enabled,
+            reports: settings.reports,
+            retry,
+            notifier,
+            templates,
+            locale,
+        })
+    }
+
+    /// テンプレートがあればそれで本文を作る。描画に失敗したら組み込みの書式に戻す。
+    fn templated(&self, notification: &Notification) -> Option<String> {
+        let templates = self.templates.as_ref()?;
+        let result = match notification {
+            Notification::Events { events, .. } if templates.has_event() => {
+                templates.render_events(events, &self.name, self.locale)
+            }
+            Notification::Report(report) if templates.has_report() => {
+                templates.render_report(report, &self.name, self.locale)
+            }
+            _ => return None,
+        };
+        match result {
+            Ok(text) => Some(text),
+            Err(e) => {
+                eprintln!(
+                    "Failed to render template for {}: {:#}; using the built-in format",
+                    self.name, e
+                );
+                None
+            }
+        }
+    }
+
+    pub fn render(&self, notification: &Notification) -> String {
+        match self.templated(notification) {
+            Some(text) => self.notifier.render(&Notification::Templated {
+                text: &text,
+                source: notification,
+            }),
+            None => self.notifier.render(notification),
+        }
+    }
+
+    /// 1 回だけ送る。アウトボックスのワーカーはこれを使い、再送の間隔はアウトボックス側で決める。
+    pub async fn send_once(
+        &self,
+        client: &Client,
+        notification: &Notification<'_>,
+        delivery_id: &str,
+    ) -> Result<(), SendError> {
+        let text = self.templated(notification);
+        let templated;
+        let notification = match &text {
+            Some(text) => {
+                templated = Notification::Templated {
+                    text,
+                    source: notification,
+                };
+                &templated
+            }
+            None => notification,
+        };
+        match self.notifier.send(client, notification, delivery_id).await {
+            Ok(()) => Ok(()),
+            Err(SendError::Fatal(e)) => Err(SendError::Fatal(anyhow::anyhow!(
+                "{} client error {:#}",
+                self.notifier.kind(),
+                e
+            ))),
+            Err(SendError::Retryable(e)) => Err(SendError::Retryable(e)),
+        }
+    }
+
+    /// リトライ方針に従って送る (アウトボックスを通さないレポート用)。
+    /// 待ち時間は倍々に延ばし、最大 1 秒の揺らぎを加える。
+    /// 試行回数を使い切った場合は `Retryable`、再送しても通らない失敗は `Fatal` を返す。
+    pub async fn deliver(
+        &self,
+        client: &Client,
+        notification: &Notification<'_>,
+        delivery_id: &str,
+    ) -> Result<(), SendError> {
+        let mut attempts = 0;
+        let mut delay = self.retry.initial_delay;
+
+        loop {
+            attempts += 1;
+            match self.send_once(client, notification, delivery_id).await {
+                Ok(()) => return Ok(()),
+                Err(e @ SendError::Fatal(_)) => return Err(e),
+                Err(SendError::Retryable(e)) => {
+                    eprintln!(
+                        "Attempt {} failed: {} ({}) returned {:#}",
+                        attempts,
+                        self.name,
+                        self.notifier.kind(),
+                        e
+                    );
+                }
+            }
+
+            if attempts >= self.retry.max_attempts {
+                return Err(SendError::Retryable(anyhow::anyhow!(
+                    "Failed to post to {} after {} attempts",
+                    self.name,
+                    attempts
+                )));
+            }
+
+            time::sleep(delay).await;
+            // jitter は u64 を明示し、Duration は飽和演算で安全に拡大
+            let jitter_ms: u64 = rng().random_range(0u64..1000u64);
+            delay = delay
+                .saturating_mul(2)
+                .saturating_add(Duration::from_millis(jitter_ms));
+        }
+    }
+}
+
+/// 通知ごとの一意な ID (アウトボックスの重複排除と sink 側の冪等キーに使う)
+fn new_delivery_id(sink: &str) -> String {
+    format!(
+        "{}-{}-{:08x}",
+        sink,
+        Utc::now().timestamp_millis(),
+        rng().random::<u32>()
+    )
+}
+
+/// イベント通知の ID。sink・種別・観測時刻・イベントの内容から決まるので、
+/// 同じ観測から作り直した通知はアウトボックスで 1 件にまとまり、sink 側の冪等キーも揃う。
+fn event_delivery_id(sink: &str, what: &str, observed: DateTime<Utc>, events: &[Event]) -> String {
+    // FNV-1a (64 bit)。ファイルに残る ID なのでビルドをまたいで変わらないハッシュを使う
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for &b in bytes.iter().chain(b"\0") {
+            hash = (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    feed(sink.as_bytes());
+    feed(what.as_bytes());
+    feed(&observed.timestamp_millis().to_le_bytes());
+    for event in events {
+        feed(serde_json::to_string(event).unwrap_or_default().as_bytes());
+    }
+    format!("{}-{}-{:016x}", sink, observed.timestamp_millis(), hash)
+}
+
+/// アウトボックスの配送ラウンドが失敗したときの待ち時間 (1 分から倍々、最大 30 分)
+fn outbox_backoff(attempts: u32) -> ChronoDuration {
+    ChronoDuration::minutes((1 << attempts.saturating_sub(1).min(5)).min(30))
+}
+
+/// 次の期限を確認しに行く最大の間隔
+const OUTBOX_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// 全 sink への送信をまとめて扱う
+pub struct Notifiers {
+    client: Client,
+    sinks: Vec<Arc<Sink>>,
+    /// 全 sink 合計の同時送信数の上限
+    semaphore: Arc<Semaphore>,
+    outbox: Outbox,
+    /// これより古いアウトボックスのエントリは送らずに捨てる
+    max_age: ChronoDuration,
+}
+
+impl Notifiers {
+    pub fn new(
+        client: Client,
+        sinks: Vec<Sink>,
+        concurrency: usize,
+        outbox: Outbox,
+        max_age: ChronoDuration,
+    ) -> Self {
+        Self {
+            client,
+            sinks: sinks.into_iter().map(Arc::new).collect(),
+            semaphore: Arc::new(Semaphore::new(concurrency)),
+            outbox,
+            max_age,
+        }
+    }
+
+    /// イベントを sink ごとにまとめてアウトボックスに積む。送信は [`Notifiers::run_outbox`] が行う。
+    /// `routes(sink_name, event)` が true のイベントだけがその sink に届く。
+    /// `observed` はイベントの元になったチェックの時刻で、エントリの ID に使う。
+    pub async fn enqueue_events(
+        &self,
+        what: &str,
+        observed: DateTime<Utc>,
+        events: &[Event],
+        routes: impl Fn(&str, &Event) -> bool,
+    ) {
+        let now = Utc::now();
+        let entries = self
+            .sinks
+            .iter()
+            .filter(|s| s.enabled)
+            .filter_map(|sink| {
+                let events: Vec<Event> = events
+                    .iter()
+                    .filter(|e| routes(&sink.name, e))
+                    .cloned()
+                    .collect();
+                (!events.is_empty()).then(|| OutboxEntry {
+                    id: event_delivery_id(&sink.name, what, observed, &events),
+                    sink: sink.name.clone(),
+                    what: what.to_string(),
+                    events,
+                    created: now,
+                    attempts: 0,
+                    next_attempt: now,
+                    last_error: None,
+                })
+            })
+            .collect();
+        self.outbox.enqueue(entries).await;
+    }
+
+    /// アウトボックスを配送し続けるワーカー。起動時に前回の残りから送り始める。
+    /// `shutdown` が立ったら新しい配送は始めず、送信中のものを `grace` だけ待って終わる。
+    pub async fn run_outbox(self: Arc<Self>, mut shutdown: watch::Receiver<bool>, grace: Duration) {
+        let mut in_flight: JoinSet<Result<(), SendError>> = JoinSet::new();
+        let mut busy: HashMap<task::Id, String> = HashMap::new();
+
+        loop {
+            let wait = self.start_due_deliveries(&mut in_flight, &mut busy).await;
+            tokio::select! {
+                Some(joined) = in_flight.join_next_with_id() => {
+                    self.finish_delivery(joined, &mut busy).await;
+                }
+                _ = self.outbox.woken() => {}
+                _ = time::sleep(wait) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+
+        if !in_flight.is_empty() {
+            println!(
+                "Waiting up to {} for {} in-flight notification(s)...",
+                format_duration(grace),
+                in_flight.len()
+            );
+            let drain = async {
+                while let Some(joined) = in_flight.join_next_with_id().await {
+                    self.finish_delivery(joined, &mut busy).await;
+                }
+            };
+            if time::timeout(grace, drain).await.is_err() {
+                eprintln!(
+                    "Gave up waiting for {} notification(s); they stay in the outbox",
+                    in_flight.len()
+                );
+                in_flight.abort_all();
+            }
+        }
+    }
+
+    /// 期限の来たエントリの配送を始め、次に確認しに行くまでの時間を返す。
+    /// 送れなくなったエントリ (sink が消えた、古すぎる) はここで捨てる。
+    async fn start_due_deliveries(
+        &self,
+        in_flight: &mut JoinSet<Result<(), SendError>>,
+        busy: &mut HashMap<task::Id, String>,
+    ) -> Duration {
+        let now = Utc::now();
+        let mut entries = self.outbox.lock().await;
+        let before = entries.len();
+        entries.retain(|e| {
+            if busy.values().any(|id| *id == e.id) {
+                return true;
+            }
+            if !self.sinks.iter().any(|s| s.name == e.sink) {
+                eprintln!(
+                    "Dropping {} for {}: notifier is no longer configured",
+                    e.what, e.sink
+                );
+                return false;
+            }
+            if now - e.created > self.max_age {
+                eprintln!(
+                    "Dropping {} for {} after {} attempt(s): older than {} (last error: {})",
+                    e.what,
+                    e.sink,
+                    e.attempts,
+                    format_chrono_duration(self.max_age),
+                    e.last_error.as_deref().unwrap_or("none")
+                );
+                return false;
+            }
+            true
+        });
+        if entries.len() != before {
+            self.outbox.persist(&entries).await;
+        }
+
+        let mut next_due = now + ChronoDuration::from_std(OUTBOX_POLL_INTERVAL).unwrap_or_default();
+        let mut due = Vec::new();
+        for entry in entries.iter() {
+            if busy.values().any(|id| *id == entry.id) {
+                continue;
+            }
+            if entry.next_attempt > now {
+                next_due = next_due.min(entry.next_attempt);
+            } else {
+                due.push(entry.clone());
+            }
+        }
+        drop(entries);
+
+        for entry in due {
+            // 無効にした sink の分は送らずに残し、有効に戻せば outbox_max_age までは送る
+            let Some(sink) = self
+                .sinks
+                .iter()
+                .find(|s| s.name == entry.sink && s.enabled)
+                .cloned()
+            else {
+                continue;
+            };
+            let client = self.client.clone();
+            let sem_clone = self.semaphore.clone();
+            let id = entry.id.clone();
+            let handle = in_flight.spawn(async move {
+                let _permit = sem_clone.acquire_owned().await;
+                let notification = Notification::Events {
+                    what: &entry.what,
+                    events: &entry.events,
+                };
+                println!("Posting {} to {}...", entry.what, sink.name);
+                sink.send_once(&client, &notification, &entry.id).await
+            });
+            busy.insert(handle.id(), id);
+        }
+        (next_due - now).to_std().unwrap_or(Duration::ZERO)
+    }
+
+    /// 配送結果をアウトボックスに反映する。成功と再送不能な失敗は取り除き、
+    /// それ以外は待ち時間を延ばして次のラウンドに回す。
+    async fn finish_delivery(
+        &self,
+        joined: Result<(task::Id, Result<(), SendError>), task::JoinError>,
+        busy: &mut HashMap<task::Id, String>,
+    ) {
+        let (task_id, result) = match joined {
+            Ok((task_id, result)) => (task_id, result),
+            Err(e) => (
+                e.id(),
+                Err(SendError::Retryable(anyhow::anyhow!(
+                    "delivery task failed: {}",
+                    e
+                ))),
+            ),
+        };
+        let Some(id) = busy.remove(&task_id) else {
+            return;
+        };
+        let mut entries = self.outbox.lock().await;
+        let Some(pos) = entries.iter().position(|e| e.id == id) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                let entry = entries.remove(pos);
+                println!("Posted {} to {} successfully.", entry.what, entry.sink);
+            }
+            Err(SendError::Fatal(e)) => {
+                let entry = entries.remove(pos);
+                eprintln!("Failed to post {} to {}: {}", entry.what, entry.sink, e);
+            }
+            Err(SendError::Retryable(e)) => {
+                let entry = &mut entries[pos];
+                entry.attempts += 1;
+                entry.last_error = Some(format!("{:#}", e));
+                entry.next_attempt = Utc::now() + outbox_backoff(entry.attempts);
+                eprintln!(
+                    "Failed to post {} to {}: {:#}; retrying at {}",
+                    entry.what,
+                    entry.sink,
+                    e,
+                    entry.next_attempt.with_timezone(&Local).format("%H:%M:%S")
+                );
+            }
+        }
+        self.outbox.persist(&entries).await;
+    }
+
+    /// レポートを受け取る sink に順に送る。`dry_run` なら送らずに各 sink の書式で表示する。
+    /// レポートは次の回に作り直せるのでアウトボックスには積まない。
+    /// 1 つでも送れなかった sink があればまとめてエラーにする (`--report` の終了コード用)。
+    pub async fn send_report(&self, report: &Report, dry_run: bool) -> Result<()> {
+        let notification = Notification::Report(report);
+        let mut failed = Vec::new();
+        for sink in self.sinks.iter().filter(|s| s.reports) {
+            if dry_run {
+                println!(
+                    "\n--- {} Dry Run ---\n{}",
+                    sink.name,
+                    sink.render(&notification)
+                );
+                continue;
+            }
+            if !sink.enabled {
+                continue;
+            }
+            println!("Posting report to {}...", sink.name);
+            match sink
+                .deliver(&self.client, &notification, &new_delivery_id(&sink.name))
+                .await
+            {
+                Ok(()) => println!("Report posted to {} successfully.", sink.name),
+                Err(e) => {
+                    eprintln!("Failed to post report to {}: {}", sink.name, e);
+                    failed.push(sink.name.as_str());
+                }
+            }
+        }
+        if !failed.is_empty() {
+            anyhow::bail!("Failed to post report to {}", failed.join(", "));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    /// 受け取った封筒とメッセージ本体
+    struct Received {
+        mail_from: String,
+        rcpt_to: Vec<String>,
+        data: String,
+    }
+
+    /// 1 通だけ受け取る最小限の SMTP サーバー (TLS・認証なし)
+    async fn smtp_stand_in(listener: TcpListener) -> Received {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let mut received = Received {
+            mail_from: String::new(),
+            rcpt_to: Vec::new(),
+            data: String::new(),
+        };
+        write.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            let upper = line.to_ascii_uppercase();
+            let reply: &[u8] = if upper.starts_with("EHLO") || upper.starts_with("HELO") {
+                b"250-localhost\r\n250 8BITMIME\r\n"
+            } else if upper.starts_with("MAIL FROM:") {
+                received.mail_from = line[10..].trim().to_string();
+                b"250 OK\r\n"
+            } else if upper.starts_with("RCPT TO:") {
+                received.rcpt_to.push(line[8..].trim().to_string());
+                b"250 OK\r\n"
+            } else if upper == "DATA" {
+                write.write_all(b"354 End data with .\r\n").await.unwrap();
+                while let Some(data_line) = lines.next_line().await.unwrap() {
+                    if data_line == "." {
+                        break;
+                    }
+                    received.data.push_str(&data_line);
+                    received.data.push('\n');
+                }
+                b"250 OK: queued\r\n"
+            } else if upper == "QUIT" {
+                write.write_all(b"221 Bye\r\n").await.unwrap();
+                break;
+            } else {
+                b"502 Command not implemented\r\n"
+            };
+            write.write_all(reply).await.unwrap();
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn email_delivers_text_and_html_to_every_recipient() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(smtp_stand_in(listener));
+
+        let settings: SinkSettings = serde_json::from_value(serde_json::json!({
+            "name": "mail",
+            "type": "email",
+            "host": "127.0.0.1",
+            "port": port,
+            "tls": "none",
+            "from": "tracekey <tracekey@example.com>",
+            "to": ["ops@example.com", "Oncall <oncall@example.org>"],
+            "max_attempts": 1,
+            "locale": "en",
+        }))
+        .unwrap();
+        let sink = Sink::from_settings(&settings, Locale::En).unwrap();
+        let events = [Event::RttHigh {
+            target: TargetRef {
+                name: "Example".to_string(),
+                url: "https://example.com".to_string(),
+                address_family: AddressFamily::Any,
+            },
+            median_millis: 812,
+            window: 5,
+            trigger_ms: 500,
+        }];
+        let notification = Notification::Events {
+            what: "health change",
+            events: &events,
+        };
+        sink.deliver(&Client::new(), &notification, "test")
+            .await
+            .unwrap();
+
+        let received = server.await.unwrap();
+        assert_eq!(received.mail_from, "<tracekey@example.com>");
+        assert_eq!(
+            received.rcpt_to,
+            ["<ops@example.com>", "<oncall@example.org>"]
+        );
+        // quoted-printable の折り返し (行末の `=`) を戻してから本文を見る
+        let data = received.data.replace("=\n", "");
+        assert!(
+            data.contains("To: ops@example.com, Oncall <oncall@example.org>"),
+            "{}",
+            data
+        );
+        assert!(
+            data.contains("Subject: [tracekey] Health change (1)"),
+            "{}",
+            data
+        );
+        assert!(
+            data.contains("Content-Type: multipart/alternative"),
+            "{}",
+            data
+        );
+        let (plain, html) = data
+            .split_once("Content-Type: text/html")
+            .expect("no HTML part");
+        assert!(plain.contains("Content-Type: text/plain"), "{}", data);
+        assert!(
+            plain.contains(
+                "RTT HIGH Example (https://example.com) median of the last 5 checks 812ms"
+            ),
+            "{}",
+            data
+        );
+        assert!(html.contains("<b>RTT HIGH</b>"), "{}", data);
+    }
+
+    #[test]
+    fn webhook_report_carries_per_target_stats() {
+        use crate::tests::{at, check, target};
+        let results = [
+            check(0, Some("NRT")),
+            check(5, Some("KIX")),
+            check(10, Some("NRT")),
+        ];
+        let report =
+            crate::generate_report(&results, &[target(serde_json::json!({}))], at(0), at(15));
+        let notification = Notification::Report(&report);
+        let payload =
+            serde_json::to_value(webhook_payload(&notification, "text".to_string())).unwrap();
+        assert_eq!(payload["type"], "report");
+        assert_eq!(payload["text"], "text");
+        assert_eq!(payload["overall_uptime"], 100.0);
+        let stats = &payload["targets"][0];
+        assert_eq!(stats["url"], "https://example.com");
+        assert_eq!(stats["colo_transition_matrix"]["NRT"]["KIX"], 1);
+        assert_eq!(stats["colo_transition_matrix"]["KIX"]["NRT"], 1);
+        let timeline = stats["colo_timeline"].as_array().unwrap();
+        assert_eq!(timeline.len(), 3);
+        assert_eq!(timeline[1]["colo"], "KIX");
+    }
+
+    /// 受け取ったリクエスト (ヘッダ名は小文字にそろえる)
+    struct Request {
+        head: String,
+        body: String,
+    }
+
+    /// リクエストごとに `statuses` の順に応答し (使い切ったら最後の値を返し続ける)、
+    /// 受け取ったリクエストを返す最小限の HTTP サーバー
+    async fn http_stand_in(
+        statuses: Vec<u16>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for i in 0.. {
+                let (stream, _) = listener.accept().await.unwrap();
+                let status = statuses[i.min(statuses.len() - 1)];
+                let (read, mut write) = stream.into_split();
+                let mut reader = BufReader::new(read);
+                let mut length = 0;
+                let mut head = String::new();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).await.unwrap();
+                    let line = line.trim_end();
+                    if line.is_empty() {
+                        break;
+                    }
+                    head.push_str(&line.to_ascii_lowercase());
+                    head.push('\n');
+                    if let Some((name, value)) = line.split_once(':')
+                        && name.eq_ignore_ascii_case("content-length")
+                    {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; length];
+                tokio::io::AsyncReadExt::read_exact(&mut reader, &mut body)
+                    .await
+                    .unwrap();
+                let _ = tx.send(Request {
+                    head,
+                    body: String::from_utf8(body).unwrap(),
+                });
+                write
+                    .write_all(
+                        format!(
+                            "HTTP/1.1 {} X\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                            status
+                        )
+                        .as_bytes(),
+                    )
+                    .await
+                    .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    /// `fields` に名前・リトライ回数・ロケールを足して sink を作る
+    fn sink(fields: serde_json::Value) -> Sink {
+        let mut value = serde_json::json!({ "name": "hook", "max_attempts": 1, "locale": "en" });
+        value
+            .as_object_mut()
+            .unwrap()
+            .extend(fields.as_object().unwrap().clone());
+        let settings: SinkSettings = serde_json::from_value(value).unwrap();
+        Sink::from_settings(&settings, Locale::En).unwrap()
+    }
+
+    fn webhook_sink(url: &str) -> Sink {
+        sink(serde_json::json!({ "type": "webhook", "url": url }))
+    }
+
+    fn rtt_high(url: &str) -> Event {
+        Event::RttHigh {
+            target: TargetRef {
+                name: "Example".to_string(),
+                url: url.to_string(),
+                address_family: AddressFamily::Any,
+            },
+            median_millis: 812,
+            window: 5,
+            trigger_ms: 500,
+        }
+    }
+
+    /// 期限の来たエントリを送り、終わった配送をすべてアウトボックスに反映する
+    async fn run_round(notifiers: &Notifiers) -> usize {
+        let mut in_flight = JoinSet::new();
+        let mut busy = HashMap::new();
+        notifiers
+            .start_due_deliveries(&mut in_flight, &mut busy)
+            .await;
+        let started = in_flight.len();
+        while let Some(joined) = in_flight.join_next_with_id().await {
+            notifiers.finish_delivery(joined, &mut busy).await;
+        }
+        started
+    }
+
+    #[test]
+    fn outbox_backoff_doubles_up_to_thirty_minutes() {
+        let minutes: Vec<i64> = (1..=8).map(|a| outbox_backoff(a).num_minutes()).collect();
+        assert_eq!(minutes, [1, 2, 4, 8, 16, 30, 30, 30]);
+    }
+
+    #[test]
+    fn event_delivery_id_depends_on_content() {
+        use crate::tests::at;
+        let events = [rtt_high("https://example.com")];
+        let id = event_delivery_id("hook", "health change", at(0), &events);
+        assert_eq!(
+            id,
+            event_delivery_id("hook", "health change", at(0), &events)
+        );
+        assert!(id.starts_with("hook-"), "{}", id);
+        for other in [
+            event_delivery_id("other", "health change", at(0), &events),
+            event_delivery_id("hook", "colo change", at(0), &events),
+            event_delivery_id("hook", "health change", at(1), &events),
+            event_delivery_id(
+                "hook",
+                "health change",
+                at(0),
+                &[rtt_high("https://example.org")],
+            ),
+        ] {
+            assert_ne!(id, other);
+        }
+    }
+
+    #[tokio::test]
+    async fn outbox_retries_with_backoff_until_delivered() {
+        use crate::tests::at;
+        let (url, mut requests) = http_stand_in(vec![503, 200]).await;
+        let notifiers = Notifiers::new(
+            Client::new(),
+            vec![webhook_sink(&url)],
+            1,
+            Outbox::in_memory(),
+            ChronoDuration::days(1),
+        );
+        let events = [rtt_high("https://example.com")];
+        let routes = |_: &str, _: &Event| true;
+        notifiers
+            .enqueue_events("health change", at(0), &events, routes)
+            .await;
+        // 同じ観測から作り直した通知は 1 件にまとまる
+        notifiers
+            .enqueue_events("health change", at(0), &events, routes)
+            .await;
+        assert_eq!(notifiers.outbox.lock().await.len(), 1);
+
+        let before = Utc::now();
+        assert_eq!(run_round(&notifiers).await, 1);
+        {
+            let entries = notifiers.outbox.lock().await;
+            assert_eq!(entries.len(), 1);
+            assert_eq!(entries[0].attempts, 1);
+            assert!(entries[0].last_error.as_deref().unwrap().contains("503"));
+            let wait = entries[0].next_attempt - before;
+            assert!(
+                wait >= ChronoDuration::minutes(1) && wait < ChronoDuration::minutes(2),
+                "{}",
+                wait
+            );
+        }
+        // 待ち時間の間は送らない
+        assert_eq!(run_round(&notifiers).await, 0);
+
+        notifiers.outbox.lock().await[0].next_attempt = Utc::now();
+        assert_eq!(run_round(&notifiers).await, 1);
+        assert!(notifiers.outbox.lock().await.is_empty());
+
+        let first = requests.recv().await.unwrap();
+        let second = requests.recv().await.unwrap();
+        assert_eq!(first.body, second.body);
+        assert!(
+            first.body.contains("\"event\":\"rtt_high\""),
+            "{}",
+            first.body
+        );
+    }
+
+    #[tokio::test]
+    async fn outbox_gives_up_on_entries_older_than_max_age() {
+        use crate::tests::at;
+        let (url, _) = http_stand_in(vec![200]).await;
+        let notifiers = Notifiers::new(
+            Client::new(),
+            vec![webhook_sink(&url)],
+            1,
+            Outbox::in_memory(),
+            ChronoDuration::hours(1),
+        );
+        notifiers
+            .enqueue_events(
+                "health change",
+                at(0),
+                &[rtt_high("https://example.com")],
+                |_, _| true,
+            )
+            .await;
+        {
+            let mut entries = notifiers.outbox.lock().await;
+            entries[0].attempts = 7;
+            entries[0].created = Utc::now() - ChronoDuration::minutes(61);
+        }
+        assert_eq!(run_round(&notifiers).await, 0);
+        assert!(notifiers.outbox.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn outbox_keeps_entries_for_disabled_sinks() {
+        use crate::tests::at;
+        let (url, _) = http_stand_in(vec![200]).await;
+        let mut sink = webhook_sink(&url);
+        sink.enabled = false;
+        let notifiers = Notifiers::new(
+            Client::new(),
+            vec![sink],
+            1,
+            Outbox::in_memory(),
+            ChronoDuration::days(1),
+        );
+        // 無効な sink には新しく積まないので、前回の残りとして直接入れる
+        let events = vec![rtt_high("https://example.com")];
+        notifiers
+            .outbox
+            .enqueue(vec![OutboxEntry {
+                id: event_delivery_id("hook", "health change", at(0), &events),
+                sink: "hook".to_string(),
+                what: "health change".to_string(),
+                events,
+                created: Utc::now(),
+                attempts: 0,
+                next_attempt: Utc::now(),
+                last_error: None,
+            }])
+            .await;
+        assert_eq!(run_round(&notifiers).await, 0);
+        assert_eq!(notifiers.outbox.lock().await.len(), 1);
+    }
+
+    /// `sink` に 1 件の RTT 通知を送り、受け取ったリクエストを返す
+    async fn deliver_to(
+        sink: Sink,
+        mut requests: tokio::sync::mpsc::UnboundedReceiver<Request>,
+    ) -> Request {
+        let events = [rtt_high("https://example.com")];
+        let notification = Notification::Events {
+            what: "health change",
+            events: &events,
+        };
+        sink.deliver(&Client::new(), &notification, "delivery-1")
+            .await
+            .unwrap();
+        requests.recv().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn discord_posts_content_and_username() {
+        let (url, requests) = http_stand_in(vec![204]).await;
+        let sink = sink(serde_json::json!({
+            "type": "discord",
+            "webhook_url": url,
+            "username": "tracekey",
+        }));
+        let request = deliver_to(sink, requests).await;
+        assert!(request.head.starts_with("post /hook "), "{}", request.head);
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        let keys: Vec<&String> = body.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["content", "username"]);
+        assert_eq!(body["username"], "tracekey");
+        let content = body["content"].as_str().unwrap();
+        assert!(content.contains("812ms"), "{}", content);
+    }
+
+    #[tokio::test]
+    async fn slack_posts_text() {
+        let (url, requests) = http_stand_in(vec![200]).await;
+        let sink = sink(serde_json::json!({ "type": "slack", "webhook_url": url }));
+        let request = deliver_to(sink, requests).await;
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        let keys: Vec<&String> = body.as_object().unwrap().keys().collect();
+        assert_eq!(keys, ["text"]);
+        let text = body["text"].as_str().unwrap();
+        assert!(text.contains("812ms"), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn ntfy_posts_plain_text_with_title_token_and_priority() {
+        let (url, requests) = http_stand_in(vec![200]).await;
+        let server = url.trim_end_matches("/hook");
+        let sink = sink(serde_json::json!({
+            "type": "ntfy",
+            "server": format!("{}/", server),
+            "topic": "hook",
+            "token": "tk_secret",
+            "priority": 4,
+        }));
+        let request = deliver_to(sink, requests).await;
+        assert!(request.head.starts_with("post /hook "), "{}", request.head);
+        for line in [
+            "title: tracekey: health change",
+            "authorization: bearer tk_secret",
+            "priority: 4",
+        ] {
+            assert!(request.head.contains(line), "{}", request.head);
+        }
+        assert!(!request.body.starts_with('{'), "{}", request.body);
+        assert!(request.body.contains("812ms"), "{}", request.body);
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_events_with_delivery_id_and_headers() {
+        let (url, requests) = http_stand_in(vec![200]).await;
+        let sink = sink(serde_json::json!({
+            "type": "webhook",
+            "url": url,
+            "headers": { "X-Api-Key": "k" },
+        }));
+        let request = deliver_to(sink, requests).await;
+        assert!(
+            request.head.contains("x-tracekey-delivery: delivery-1"),
+            "{}",
+            request.head
+        );
+        assert!(request.head.contains("x-api-key: k"), "{}", request.head);
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["type"], "events");
+        assert_eq!(body["what"], "health change");
+        assert!(body["text"].as_str().unwrap().contains("812ms"));
+        let event = &body["events"][0];
+        assert_eq!(event["event"], "rtt_high");
+        assert_eq!(event["target"]["url"], "https://example.com");
+        assert_eq!(event["median_millis"], 812);
+    }
+
+    #[test]
+    fn discord_truncates_content_to_the_limit() {
+        let sink = sink(serde_json::json!({
+            "type": "discord",
+            "webhook_url": "https://discord.example/api/webhooks/1/x",
+        }));
+        let events: Vec<Event> = (0..200)
+            .map(|i| rtt_high(&format!("https://example.com/{}", i)))
+            .collect();
+        let notification = Notification::Events {
+            what: "health change",
+            events: &events,
+        };
+        let content = sink.render(&notification);
+        assert_eq!(content.chars().count(), DISCORD_MAX_CONTENT);
+        assert!(content.ends_with('…'), "{}", content);
+
+        let exact = "あ".repeat(DISCORD_MAX_CONTENT);
+        assert_eq!(truncate_chars(exact.clone(), DISCORD_MAX_CONTENT), exact);
+        let over = truncate_chars(format!("{}い", exact), DISCORD_MAX_CONTENT);
+        assert_eq!(over.chars().count(), DISCORD_MAX_CONTENT);
+        assert!(over.ends_with("あ…"));
+    }
+
+    #[tokio::test]
+    async fn enqueue_events_routes_events_per_sink() {
+        use crate::tests::at;
+        let notifiers = Notifiers::new(
+            Client::new(),
+            vec![
+                sink(
+                    serde_json::json!({ "name": "ops", "type": "webhook", "url": "http://127.0.0.1:9/" }),
+                ),
+                sink(
+                    serde_json::json!({ "name": "team", "type": "webhook", "url": "http://127.0.0.1:9/" }),
+                ),
+                sink(
+                    serde_json::json!({ "name": "quiet", "type": "webhook", "url": "http://127.0.0.1:9/" }),
+                ),
+            ],
+            1,
+            Outbox::in_memory(),
+            ChronoDuration::days(1),
+        );
+        let events = [
+            rtt_high("https://example.com"),
+            rtt_high("https://example.org"),
+        ];
+        // ops はすべて、team は example.org だけ、quiet には何も送らない
+        notifiers
+            .enqueue_events("health change", at(0), &events, |sink, event| match sink {
+                "ops" => true,
+                "team" => event.target().url == "https://example.org",
+                _ => false,
+            })
+            .await;
+        let entries = notifiers.outbox.lock().await;
+        let routed: Vec<(&str, Vec<&str>)> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.sink.as_str(),
+                    e.events.iter().map(|ev| ev.target().url.as_str()).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            routed,
+            [
+                ("ops", vec!["https://example.com", "https://example.org"]),
+                ("team", vec!["https://example.org"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn split_chunks_packs_lines_within_the_limit() {
+        assert_eq!(split_chunks("short text", 500), ["short text"]);
+        let chunks = split_chunks("aaaa\nbbbb\ncccc\n\ndd", 9);
+        assert_eq!(chunks, ["aaaa\nbbbb", "cccc\n\ndd"]);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 9, "{:?}", chunk);
+        }
+        assert!(split_chunks("", 9).is_empty());
+        assert!(split_chunks("\n\n", 9).is_empty());
+    }
+
+    #[test]
+    fn split_chunks_cuts_a_line_longer_than_the_limit() {
+        let long = "x".repeat(25);
+        assert_eq!(
+            split_chunks(&format!("short\n{}\nend", long), 10),
+            ["short", "xxxxxxxxx…", "end"]
+        );
+    }
+
+    #[test]
+    fn split_chunks_counts_characters_not_bytes() {
+        // 3 バイト文字でも文字数で数える
+        assert_eq!(split_chunks("あいう\nえお", 6), ["あいう\nえお"]);
+        assert_eq!(split_chunks("あいう\nえお", 5), ["あいう", "えお"]);
+
+        let chunks = split_chunks("東京から大阪へ切り替わりました\n復旧しました", 10);
+        assert_eq!(chunks, ["東京から大阪へ切り…", "復旧しました"]);
+        for chunk in &chunks {
+            assert!(chunk.chars().count() <= 10, "{:?}", chunk);
+        }
+    }
+}
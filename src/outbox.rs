@@ -0,0 +1,204 @@
+//! 送信待ちの通知を `state/outbox.json` に永続化するキュー。
+//!
+//! 通知は sink ごとに 1 エントリとして積み、配送に成功するか諦めるまでファイルに残す。
+//! プロセスを止めても次回起動時に残りから送り直す。
+
+use crate::notify::Event;
+use crate::{load_json_file, write_json_file};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::PathBuf;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+
+const OUTBOX_FILE: &str = "state/outbox.json";
+
+/// 1 つの sink に送る 1 通分
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutboxEntry {
+    /// 重複排除と sink 側の冪等キーに使う。sink・種別・観測時刻・イベントから決まるので、
+    /// 同じ観測を積み直しても (再起動をまたいでも) 送信待ちの間は 1 件にまとまる。
+    /// 送り終えたエントリの ID は覚えていない。
+    pub id: String,
+    pub sink: String,
+    /// ログ用の種別 ("colo change" など)
+    pub what: String,
+    pub events: Vec<Event>,
+    pub created: DateTime<Utc>,
+    /// 失敗した送信の数 (1 ラウンドにつき 1 回だけ送り、間隔はアウトボックス側で延ばす)
+    #[serde(default)]
+    pub attempts: u32,
+    pub next_attempt: DateTime<Utc>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+pub struct Outbox {
+    entries: Mutex<Vec<OutboxEntry>>,
+    /// エントリが追加されたときにワーカーを起こす
+    wake: Notify,
+    /// 書き出し先。`None` ならファイルを読み書きしない (`--report` の 1 回実行用)
+    file: Option<PathBuf>,
+}
+
+impl Outbox {
+    /// 前回の残りを読み込む。同じ id のエントリは最初の 1 件だけ残し、
+    /// 待ち時間の途中で止まったものも起動直後に送り直す。
+    pub async fn load() -> Result<Self> {
+        Self::load_from(PathBuf::from(OUTBOX_FILE)).await
+    }
+
+    /// [`Outbox::load`] の読み書きするファイルを指定する版
+    pub async fn load_from(file: PathBuf) -> Result<Self> {
+        let now = Utc::now();
+        let mut seen = HashSet::new();
+        let entries: Vec<OutboxEntry> = load_json_file(file.clone())
+            .await?
+            .into_iter()
+            .filter(|e: &OutboxEntry| seen.insert(e.id.clone()))
+            .map(|e| OutboxEntry {
+                next_attempt: e.next_attempt.min(now),
+                ..e
+            })
+            .collect();
+        if !entries.is_empty() {
+            println!(
+                "Loaded {} pending notification(s) from outbox",
+                entries.len()
+            );
+        }
+        Ok(Self {
+            entries: Mutex::new(entries),
+            wake: Notify::new(),
+            file: Some(file),
+        })
+    }
+
+    /// ファイルに触れない空のキュー。前回の残りを読まず、書き出しもしない。
+    pub fn in_memory() -> Self {
+        Self {
+            entries: Mutex::new(Vec::new()),
+            wake: Notify::new(),
+            file: None,
+        }
+    }
+
+    /// 既にある id は無視して追加し、ファイルに書き出してからワーカーを起こす。
+    pub async fn enqueue(&self, new_entries: Vec<OutboxEntry>) {
+        if new_entries.is_empty() {
+            return;
+        }
+        let mut entries = self.entries.lock().await;
+        for entry in new_entries {
+            if !entries.iter().any(|e| e.id == entry.id) {
+                entries.push(entry);
+            }
+        }
+        self.persist(&entries).await;
+        drop(entries);
+        self.wake.notify_one();
+    }
+
+    pub async fn lock(&self) -> MutexGuard<'_, Vec<OutboxEntry>> {
+        self.entries.lock().await
+    }
+
+    pub async fn woken(&self) {
+        self.wake.notified().await
+    }
+
+    /// 書き出しに失敗してもメモリ上のキューは生きているので、警告だけ出して続ける。
+    pub async fn persist(&self, entries: &[OutboxEntry]) {
+        let Some(file) = self.file.clone() else {
+            return;
+        };
+        let entries = entries.to_vec();
+        let result = tokio::task::spawn_blocking(move || write_json_file(&file, &entries)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Failed to save notification outbox: {}", e),
+            Err(e) => eprintln!("Failed to save notification outbox: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::TargetRef;
+    use crate::probe::AddressFamily;
+    use crate::tests::TempDir;
+
+    fn entry(id: &str, next_attempt: DateTime<Utc>) -> OutboxEntry {
+        OutboxEntry {
+            id: id.to_string(),
+            sink: "hook".to_string(),
+            what: "health change".to_string(),
+            events: vec![Event::RttHigh {
+                target: TargetRef {
+                    name: "Example".to_string(),
+                    url: "https://example.com".to_string(),
+                    address_family: AddressFamily::Any,
+                },
+                median_millis: 812,
+                window: 5,
+                trigger_ms: 500,
+            }],
+            created: Utc::now(),
+            attempts: 2,
+            next_attempt,
+            last_error: Some("status 503".to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn persisted_entries_load_back_due_now() {
+        let dir = TempDir::new("outbox");
+        let file = PathBuf::from(dir.file("outbox.json"));
+        let later = Utc::now() + chrono::Duration::minutes(30);
+
+        let outbox = Outbox::load_from(file.clone()).await.unwrap();
+        assert!(outbox.lock().await.is_empty());
+        outbox
+            .enqueue(vec![
+                entry("a", later),
+                entry("b", later),
+                entry("a", later),
+            ])
+            .await;
+        drop(outbox);
+
+        let before = Utc::now();
+        let loaded = Outbox::load_from(file).await.unwrap();
+        let entries = loaded.lock().await;
+        let ids: Vec<&str> = entries.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, ["a", "b"]);
+        for e in entries.iter() {
+            assert!(e.next_attempt <= Utc::now() && e.next_attempt >= before);
+            assert_eq!(e.attempts, 2);
+            assert_eq!(e.last_error.as_deref(), Some("status 503"));
+            assert_eq!(e.events.len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn load_drops_duplicate_ids_in_the_file() {
+        let dir = TempDir::new("outbox-dupes");
+        let file = PathBuf::from(dir.file("outbox.json"));
+        let now = Utc::now();
+        crate::write_json_file(&file, &[entry("a", now), entry("a", now), entry("b", now)])
+            .unwrap();
+        let loaded = Outbox::load_from(file).await.unwrap();
+        assert_eq!(loaded.lock().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn in_memory_outbox_writes_nothing() {
+        let dir = TempDir::new("outbox-memory");
+        let outbox = Outbox::in_memory();
+        outbox.enqueue(vec![entry("a", Utc::now())]).await;
+        assert_eq!(outbox.lock().await.len(), 1);
+        assert!(!std::path::Path::new(&dir.file("outbox.json")).exists());
+    }
+}
@@ -0,0 +1,1114 @@
+//! フェーズ別 (DNS / TCP 接続 / TLS ハンドシェイク / TTFB / ボディ転送) の所要時間を
+//! 計測するための最小限の HTTP/1.1 クライアント。
+//!
+//! reqwest は接続確立の内訳を公開しないため、監視用のリクエストだけはここで直接組み立てる。
+//! リダイレクトは `MAX_REDIRECTS` 回まで追い、ボディは `MAX_BODY_BYTES` までしか読まない。
+//! `Accept-Encoding` は送らないので圧縮されたボディは来ない。
+//! HTTP/2 とプロキシ (`HTTPS_PROXY` など) には対応せず、常にエッジへ直接 HTTP/1.1 で接続する。
+//! 複数のアドレスが解決できたときは IPv6/IPv4 を交互に並べ、応答のないアドレスを待ち続けないよう
+//! 少しずつずらして並行に接続を試す (RFC 8305 の Happy Eyeballs と同じ考え方)。
+
+use anyhow::{Context, Result};
+use futures::StreamExt;
+use futures::stream::FuturesUnordered;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{self, Instant};
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::{self, ClientConfig, pki_types::ServerName};
+use url::Url;
+
+/// 追いかけるリダイレクトの最大回数 (reqwest の既定と同じ)
+const MAX_REDIRECTS: usize = 10;
+/// これより大きいボディは読まずにエラーにする
+const MAX_BODY_BYTES: usize = 1024 * 1024;
+/// 接続中のアドレスが応答しないとき、次のアドレスへの接続を並行して始めるまでの時間 (RFC 8305 の推奨値)
+const CONNECT_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// 1 回のリクエストにおける各フェーズの所要時間 (ミリ秒)。
+/// 該当しないフェーズ (IP 直指定時の DNS、http の TLS など) は `None`。
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PhaseTimings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dns_millis: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_millis: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_millis: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttfb_millis: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_millis: Option<u64>,
+}
+
+impl PhaseTimings {
+    /// リダイレクトを追ったときに各ホップの時間を足し合わせる。
+    fn add(&mut self, other: &PhaseTimings) {
+        fn sum(a: &mut Option<u64>, b: Option<u64>) {
+            if let Some(b) = b {
+                *a = Some(a.unwrap_or(0) + b);
+            }
+        }
+        sum(&mut self.dns_millis, other.dns_millis);
+        sum(&mut self.connect_millis, other.connect_millis);
+        sum(&mut self.tls_millis, other.tls_millis);
+        sum(&mut self.ttfb_millis, other.ttfb_millis);
+        sum(&mut self.body_millis, other.body_millis);
+    }
+}
+
+/// 接続に使うアドレスファミリー。`Any` はリゾルバが返した順に試す。
+#[derive(
+    Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum AddressFamily {
+    #[default]
+    Any,
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    pub fn is_any(&self) -> bool {
+        *self == AddressFamily::Any
+    }
+
+    fn matches(&self, ip: &IpAddr) -> bool {
+        match self {
+            AddressFamily::Any => true,
+            AddressFamily::Ipv4 => ip.is_ipv4(),
+            AddressFamily::Ipv6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl fmt::Display for AddressFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AddressFamily::Any => "any",
+            AddressFamily::Ipv4 => "IPv4",
+            AddressFamily::Ipv6 => "IPv6",
+        })
+    }
+}
+
+/// 監視リクエストのメソッド。ヘッダだけ見ればよいプロバイダは `Head` でボディを受け取らない。
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Head,
+}
+
+impl Method {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+        }
+    }
+}
+
+/// 監視リクエスト 1 件分の指定
+#[derive(Debug, Clone, Copy)]
+pub struct ProbeRequest<'a> {
+    pub method: Method,
+    pub url: &'a Url,
+    pub family: AddressFamily,
+    /// `Host` / `Accept` / `User-Agent` があれば既定の値を置き換える
+    pub headers: &'a [(&'a str, &'a str)],
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ProbeResponse {
+    pub status: u16,
+    /// 実際に接続したエッジのアドレス
+    pub peer: IpAddr,
+    /// 小文字化したヘッダ名と値
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+    pub timings: PhaseTimings,
+    pub total: Duration,
+}
+
+impl ProbeResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+/// 失敗した段階による分類。ログには `"timeout"` や `"http_503"` のような文字列で残す。
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(into = "String", try_from = "String")]
+pub enum ErrorKind {
+    Dns,
+    Connect,
+    Tls,
+    Timeout,
+    HttpStatus {
+        code: u16,
+    },
+    BodyRead,
+    Parse,
+    /// 上記以外 (設定の不備など)、または分類を持たない過去のログ
+    Other,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Dns => f.write_str("dns"),
+            ErrorKind::Connect => f.write_str("connect"),
+            ErrorKind::Tls => f.write_str("tls"),
+            ErrorKind::Timeout => f.write_str("timeout"),
+            ErrorKind::HttpStatus { code } => write!(f, "http_{}", code),
+            ErrorKind::BodyRead => f.write_str("body_read"),
+            ErrorKind::Parse => f.write_str("parse"),
+            ErrorKind::Other => f.write_str("other"),
+        }
+    }
+}
+
+impl From<ErrorKind> for String {
+    fn from(kind: ErrorKind) -> Self {
+        kind.to_string()
+    }
+}
+
+impl TryFrom<String> for ErrorKind {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        Ok(match value.as_str() {
+            "dns" => ErrorKind::Dns,
+            "connect" => ErrorKind::Connect,
+            "tls" => ErrorKind::Tls,
+            "timeout" => ErrorKind::Timeout,
+            "body_read" => ErrorKind::BodyRead,
+            "parse" => ErrorKind::Parse,
+            "other" => ErrorKind::Other,
+            _ => match value.strip_prefix("http_").and_then(|c| c.parse().ok()) {
+                Some(code) => ErrorKind::HttpStatus { code },
+                None => return Err(format!("unknown error kind: {}", value)),
+            },
+        })
+    }
+}
+
+/// 分類付きのプローブエラー。`anyhow::Error` に包んで返し、呼び出し側で `downcast_ref` する。
+#[derive(Debug)]
+pub struct ProbeError {
+    pub kind: ErrorKind,
+    source: anyhow::Error,
+    /// 非 2xx で失敗したときのレスポンス (ヘッダを見たい呼び出し側向け)
+    response: Option<Box<ProbeResponse>>,
+}
+
+impl fmt::Display for ProbeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.source)
+    }
+}
+
+impl std::error::Error for ProbeError {}
+
+impl ProbeError {
+    /// エラーチェーンから分類を取り出す。分類のないエラーは `Other`。
+    pub fn kind_of(err: &anyhow::Error) -> ErrorKind {
+        err.chain()
+            .find_map(|e| e.downcast_ref::<ProbeError>())
+            .map_or(ErrorKind::Other, |e| e.kind)
+    }
+
+    /// エラーチェーンから非 2xx のレスポンスを取り出す。
+    pub fn response_of(err: &anyhow::Error) -> Option<&ProbeResponse> {
+        err.chain()
+            .find_map(|e| e.downcast_ref::<ProbeError>())
+            .and_then(|e| e.response.as_deref())
+    }
+}
+
+/// IPv6 と IPv4 が交互になるよう並べ替える。先頭のファミリーとファミリー内の順はリゾルバに従う。
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_v6 = addrs.first().is_some_and(|a| a.is_ipv6());
+    let (mut first, mut second): (VecDeque<_>, VecDeque<_>) =
+        addrs.into_iter().partition(|a| a.is_ipv6() == first_is_v6);
+    let mut ordered = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        ordered.extend(first.pop_front());
+        ordered.extend(second.pop_front());
+    }
+    ordered
+}
+
+async fn connect_one(
+    addr: SocketAddr,
+) -> std::result::Result<(SocketAddr, TcpStream), (SocketAddr, std::io::Error)> {
+    TcpStream::connect(addr)
+        .await
+        .map(|tcp| (addr, tcp))
+        .map_err(|e| (addr, e))
+}
+
+/// `addrs` に順に接続する。前の試行が `CONNECT_ATTEMPT_DELAY` の間に終わらなければ待たずに次も始め、
+/// 最初につながったものを使う (残りは捨てる)。すべて失敗したら最後のエラーを返す。
+/// `addrs` は空でないこと。
+async fn connect_any(
+    addrs: &[SocketAddr],
+) -> std::result::Result<(SocketAddr, TcpStream), (SocketAddr, std::io::Error)> {
+    let mut attempts = FuturesUnordered::new();
+    let mut next = addrs.iter().copied();
+    let mut last_err = None;
+    loop {
+        if attempts.is_empty() {
+            match next.next() {
+                Some(addr) => attempts.push(connect_one(addr)),
+                None => return Err(last_err.expect("addrs is not empty")),
+            }
+        }
+        tokio::select! {
+            Some(result) = attempts.next() => match result {
+                Ok(connected) => return Ok(connected),
+                // 失敗したら遅延を待たずに次のアドレスへ
+                Err(e) => {
+                    last_err = Some(e);
+                    attempts.extend(next.next().map(connect_one));
+                }
+            },
+            _ = time::sleep(CONNECT_ATTEMPT_DELAY), if next.len() > 0 => {
+                attempts.extend(next.next().map(connect_one));
+            }
+        }
+    }
+}
+
+fn fail(kind: ErrorKind, message: impl Into<String>) -> anyhow::Error {
+    ProbeError {
+        kind,
+        source: anyhow::Error::msg(message.into()),
+        response: None,
+    }
+    .into()
+}
+
+trait ResultExt<T> {
+    fn kind(self, kind: ErrorKind) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn kind(self, kind: ErrorKind) -> Result<T> {
+        self.map_err(|source| {
+            ProbeError {
+                kind,
+                source,
+                response: None,
+            }
+            .into()
+        })
+    }
+}
+
+/// 確立済みの接続。keep-alive で使い回せる間は `reusable` が立つ。
+struct Connection {
+    stream: Box<dyn Io>,
+    peer: IpAddr,
+    reusable: bool,
+    /// 接続確立にかかった時間 (最初のリクエストにだけ計上する)
+    setup: Option<(PhaseTimings, Duration)>,
+}
+
+/// `Prober::sample` の結果
+pub struct Sampling {
+    /// 接続を使い回すときのウォームアップ (サンプルには含めない)
+    pub warmup: Option<Result<ProbeResponse>>,
+    pub samples: Vec<Result<ProbeResponse>>,
+}
+
+pub struct Prober {
+    tls: TlsConnector,
+    user_agent: String,
+}
+
+impl Prober {
+    pub fn new(user_agent: &str) -> Result<Self> {
+        use rustls_platform_verifier::BuilderVerifierExt;
+
+        let provider = Arc::new(rustls::crypto::aws_lc_rs::default_provider());
+        let mut config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_platform_verifier()?
+            .with_no_client_auth();
+        // 手組みのクライアントは HTTP/1.1 しか話せない
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        Ok(Self {
+            tls: TlsConnector::from(Arc::new(config)),
+            user_agent: user_agent.to_string(),
+        })
+    }
+
+    /// `family` のアドレスで `url` にリクエストを送り、リダイレクトを追った先が非 2xx ならエラーとして扱う。
+    pub async fn send(&self, req: &ProbeRequest<'_>) -> Result<ProbeResponse> {
+        let ProbeRequest {
+            method,
+            url,
+            family,
+            headers,
+            timeout,
+        } = *req;
+        time::timeout(timeout, async {
+            let mut conn = self.connect(url, family).await?;
+            let resp = self.request(&mut conn, method, url, headers, false).await?;
+            self.finish(method, url, family, headers, resp).await
+        })
+        .await
+        .map_err(|_| {
+            fail(
+                ErrorKind::Timeout,
+                format!("Request timed out after {:?}", timeout),
+            )
+        })?
+    }
+
+    /// `count` 回リクエストを送る。`reuse_connection` のときは先にウォームアップを 1 回送って
+    /// 接続を確立し、以降のサンプルはその接続を使い回す (切れたら張り直す)。
+    pub async fn sample(
+        &self,
+        req: &ProbeRequest<'_>,
+        count: usize,
+        reuse_connection: bool,
+    ) -> Sampling {
+        if !reuse_connection {
+            let mut samples = Vec::with_capacity(count);
+            for _ in 0..count {
+                samples.push(self.send(req).await);
+            }
+            return Sampling {
+                warmup: None,
+                samples,
+            };
+        }
+
+        let ProbeRequest {
+            method,
+            url,
+            family,
+            headers,
+            timeout,
+        } = *req;
+        let mut conn: Option<Connection> = None;
+        let run = async |conn: &mut Option<Connection>| -> Result<ProbeResponse> {
+            time::timeout(timeout, async {
+                let c = match conn {
+                    Some(c) if c.reusable => c,
+                    _ => conn.insert(self.connect(url, family).await?),
+                };
+                let res = self.request(c, method, url, headers, true).await;
+                if res.is_err() {
+                    *conn = None;
+                }
+                self.finish(method, url, family, headers, res?).await
+            })
+            .await
+            .unwrap_or_else(|_| {
+                *conn = None;
+                Err(fail(
+                    ErrorKind::Timeout,
+                    format!("Request timed out after {:?}", timeout),
+                ))
+            })
+        };
+
+        let warmup = run(&mut conn).await;
+        let mut samples = Vec::with_capacity(count);
+        for _ in 0..count {
+            samples.push(run(&mut conn).await);
+        }
+        Sampling {
+            warmup: Some(warmup),
+            samples,
+        }
+    }
+
+    /// リダイレクトなら新しい接続で追いかけ (各ホップの時間は合算する)、最終的な状態コードを検査する。
+    /// 別ホストへのリダイレクトでは `User-Agent` 以外の追加ヘッダを送らない。
+    async fn finish(
+        &self,
+        method: Method,
+        url: &Url,
+        family: AddressFamily,
+        headers: &[(&str, &str)],
+        mut resp: ProbeResponse,
+    ) -> Result<ProbeResponse> {
+        let mut url = url.clone();
+        let mut hops = 0;
+        while matches!(resp.status, 301 | 302 | 303 | 307 | 308) {
+            let Some(location) = resp.header("location") else {
+                break;
+            };
+            if hops == MAX_REDIRECTS {
+                return Err(fail(
+                    ErrorKind::HttpStatus { code: resp.status },
+                    format!(
+                        "Too many redirects (more than {}) for {}",
+                        MAX_REDIRECTS, url
+                    ),
+                ));
+            }
+            let next = url
+                .join(location)
+                .with_context(|| format!("Invalid redirect location: {}", location))
+                .kind(ErrorKind::Parse)?;
+            if !matches!(next.scheme(), "http" | "https") {
+                return Err(fail(
+                    ErrorKind::Parse,
+                    format!("Unsupported redirect location: {}", next),
+                ));
+            }
+            let forwarded: Vec<(&str, &str)> = headers
+                .iter()
+                .filter(|(name, _)| {
+                    next.host_str() == url.host_str() || name.eq_ignore_ascii_case("user-agent")
+                })
+                .copied()
+                .collect();
+            let mut conn = self.connect(&next, family).await?;
+            let mut next_resp = self
+                .request(&mut conn, method, &next, &forwarded, false)
+                .await?;
+            let mut timings = resp.timings;
+            timings.add(&next_resp.timings);
+            next_resp.timings = timings;
+            next_resp.total += resp.total;
+            resp = next_resp;
+            url = next;
+            hops += 1;
+        }
+
+        if !(200..300).contains(&resp.status) {
+            return Err(ProbeError {
+                kind: ErrorKind::HttpStatus { code: resp.status },
+                source: anyhow::anyhow!("HTTP status {} for {}", resp.status, url),
+                response: Some(Box::new(resp)),
+            }
+            .into());
+        }
+        Ok(resp)
+    }
+
+    async fn connect(&self, url: &Url, family: AddressFamily) -> Result<Connection> {
+        let host = url
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("URL has no host: {}", url))?;
+        let port = url
+            .port_or_known_default()
+            .ok_or_else(|| anyhow::anyhow!("URL has no known port: {}", url))?;
+        let is_https = match url.scheme() {
+            "https" => true,
+            "http" => false,
+            other => anyhow::bail!("Unsupported URL scheme '{}'", other),
+        };
+
+        let start = Instant::now();
+        let mut timings = PhaseTimings::default();
+
+        // DNS
+        let addrs: Vec<SocketAddr> = match url.host() {
+            Some(url::Host::Ipv4(ip)) => vec![SocketAddr::new(ip.into(), port)],
+            Some(url::Host::Ipv6(ip)) => vec![SocketAddr::new(ip.into(), port)],
+            _ => {
+                let phase = Instant::now();
+                let addrs: Vec<_> = tokio::net::lookup_host((host, port))
+                    .await
+                    .with_context(|| format!("DNS resolution failed for {}", host))
+                    .kind(ErrorKind::Dns)?
+                    .collect();
+                timings.dns_millis = Some(millis(phase.elapsed()));
+                addrs
+            }
+        };
+        let addrs: Vec<SocketAddr> = addrs
+            .into_iter()
+            .filter(|a| family.matches(&a.ip()))
+            .collect();
+        if addrs.is_empty() {
+            return Err(fail(
+                ErrorKind::Dns,
+                format!(
+                    "DNS resolution returned no addresses for {} ({})",
+                    host, family
+                ),
+            ));
+        }
+
+        // TCP 接続 (ファミリーを交互に並べ、遅いアドレスは待たずに次も試す)
+        let phase = Instant::now();
+        let (peer, tcp) = match connect_any(&interleave_families(addrs)).await {
+            Ok((addr, tcp)) => (addr.ip(), tcp),
+            Err((addr, e)) => {
+                return Err(e)
+                    .with_context(|| format!("TCP connect to {} failed", addr))
+                    .kind(ErrorKind::Connect);
+            }
+        };
+        let _ = tcp.set_nodelay(true);
+        timings.connect_millis = Some(millis(phase.elapsed()));
+
+        // TLS
+        let stream: Box<dyn Io> = if is_https {
+            let server_name = ServerName::try_from(host.trim_matches(|c| c == '[' || c == ']'))
+                .with_context(|| format!("Invalid TLS server name: {}", host))
+                .kind(ErrorKind::Tls)?
+                .to_owned();
+            let phase = Instant::now();
+            let tls = self
+                .tls
+                .connect(server_name, tcp)
+                .await
+                .with_context(|| format!("TLS handshake with {} failed", host))
+                .kind(ErrorKind::Tls)?;
+            timings.tls_millis = Some(millis(phase.elapsed()));
+            Box::new(tls)
+        } else {
+            Box::new(tcp)
+        };
+
+        Ok(Connection {
+            stream,
+            peer,
+            reusable: true,
+            setup: Some((timings, start.elapsed())),
+        })
+    }
+
+    /// 確立済みの接続でリクエストを 1 回送る。
+    /// 接続して最初のリクエストには DNS/接続/TLS の時間も含める。
+    async fn request(
+        &self,
+        conn: &mut Connection,
+        method: Method,
+        url: &Url,
+        extra_headers: &[(&str, &str)],
+        keep_alive: bool,
+    ) -> Result<ProbeResponse> {
+        let (mut timings, setup_time) = conn.setup.take().unwrap_or_default();
+        let start = Instant::now();
+        let stream = &mut conn.stream;
+
+        // リクエスト送信から最初のバイト受信まで
+        let host = url.host_str().unwrap_or_default();
+        let host_header = match url.port() {
+            Some(p) => format!("{}:{}", host, p),
+            None => host.to_string(),
+        };
+        let target = match url.query() {
+            Some(q) => format!("{}?{}", url.path(), q),
+            None => url.path().to_string(),
+        };
+        let mut request = format!(
+            "{} {} HTTP/1.1\r\nConnection: {}\r\n",
+            method.as_str(),
+            target,
+            if keep_alive { "keep-alive" } else { "close" }
+        );
+        // 既定のヘッダは同名の追加ヘッダがあればそちらで置き換える
+        let defaults = [
+            ("Host", host_header.as_str()),
+            ("Accept", "*/*"),
+            ("User-Agent", self.user_agent.as_str()),
+        ];
+        for (name, value) in defaults {
+            if !extra_headers
+                .iter()
+                .any(|(extra, _)| extra.eq_ignore_ascii_case(name))
+            {
+                request.push_str(&format!("{}: {}\r\n", name, value));
+            }
+        }
+        for (name, value) in extra_headers {
+            request.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        request.push_str("\r\n");
+        let phase = Instant::now();
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .context("Failed to send request")
+            .kind(ErrorKind::Connect)?;
+        stream
+            .flush()
+            .await
+            .context("Failed to send request")
+            .kind(ErrorKind::Connect)?;
+
+        let mut buf = Vec::with_capacity(4096);
+        let mut chunk = [0u8; 4096];
+        let n = stream
+            .read(&mut chunk)
+            .await
+            .context("Failed to read response")
+            .kind(ErrorKind::BodyRead)?;
+        if n == 0 {
+            return Err(fail(
+                ErrorKind::BodyRead,
+                "Connection closed before response",
+            ));
+        }
+        timings.ttfb_millis = Some(millis(phase.elapsed()));
+        buf.extend_from_slice(&chunk[..n]);
+
+        let header_end = loop {
+            if let Some(pos) = find_subslice(&buf, b"\r\n\r\n") {
+                break pos + 4;
+            }
+            let n = stream
+                .read(&mut chunk)
+                .await
+                .context("Failed to read response headers")
+                .kind(ErrorKind::BodyRead)?;
+            if n == 0 {
+                return Err(fail(
+                    ErrorKind::BodyRead,
+                    "Connection closed while reading response headers",
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        let head = String::from_utf8_lossy(&buf[..header_end]).into_owned();
+        let mut head_lines = head.split("\r\n");
+        let status = parse_status_line(head_lines.next().unwrap_or_default())?;
+        let headers: Vec<(String, String)> = head_lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(k, v)| (k.trim().to_ascii_lowercase(), v.trim().to_string()))
+            .collect();
+
+        // ボディ受信完了まで
+        let phase = Instant::now();
+        let mut body = buf.split_off(header_end);
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.as_str())
+        };
+        conn.reusable =
+            keep_alive && !header("connection").is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        if method == Method::Head || matches!(status, 100..=199 | 204 | 304) {
+            // ボディを持たないレスポンス
+            body.clear();
+        } else if header("transfer-encoding")
+            .is_some_and(|v| v.to_ascii_lowercase().contains("chunked"))
+        {
+            body = read_chunked(stream, body, MAX_BODY_BYTES).await?;
+        } else if let Some(len) = header("content-length").and_then(|v| v.parse::<usize>().ok()) {
+            if len > MAX_BODY_BYTES {
+                return Err(body_too_large());
+            }
+            while body.len() < len {
+                let n = stream
+                    .read(&mut chunk)
+                    .await
+                    .context("Failed to read response body")
+                    .kind(ErrorKind::BodyRead)?;
+                if n == 0 {
+                    return Err(fail(
+                        ErrorKind::BodyRead,
+                        "Connection closed before the full response body was received",
+                    ));
+                }
+                body.extend_from_slice(&chunk[..n]);
+            }
+            body.truncate(len);
+        } else {
+            // 長さが分からないので接続が閉じるまで読む (再利用不可)
+            conn.reusable = false;
+            let limit = (MAX_BODY_BYTES + 1).saturating_sub(body.len()) as u64;
+            (&mut *stream)
+                .take(limit)
+                .read_to_end(&mut body)
+                .await
+                .context("Failed to read response body")
+                .kind(ErrorKind::BodyRead)?;
+            if body.len() > MAX_BODY_BYTES {
+                return Err(body_too_large());
+            }
+        }
+        if method == Method::Get {
+            timings.body_millis = Some(millis(phase.elapsed()));
+        }
+        let total = setup_time + start.elapsed();
+
+        Ok(ProbeResponse {
+            status,
+            peer: conn.peer,
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+            timings,
+            total,
+        })
+    }
+}
+
+pub fn millis(d: Duration) -> u64 {
+    std::cmp::min(d.as_millis(), u64::MAX as u128) as u64
+}
+
+fn body_too_large() -> anyhow::Error {
+    fail(
+        ErrorKind::BodyRead,
+        format!("Response body exceeds {} bytes", MAX_BODY_BYTES),
+    )
+}
+
+fn find_subslice(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+fn parse_status_line(line: &str) -> Result<u16> {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(version), Some(code)) if version.starts_with("HTTP/") => code
+            .parse()
+            .with_context(|| format!("Invalid HTTP status line: {}", line))
+            .kind(ErrorKind::Parse),
+        _ => Err(fail(
+            ErrorKind::Parse,
+            format!("Invalid HTTP status line: {}", line),
+        )),
+    }
+}
+
+/// `Transfer-Encoding: chunked` のボディを復号する。`buf` は既に受信済みの分。
+/// 復号後のボディが `limit` バイトを超えたらエラー。
+async fn read_chunked<S: AsyncRead + Unpin + ?Sized>(
+    stream: &mut S,
+    mut buf: Vec<u8>,
+    limit: usize,
+) -> Result<Vec<u8>> {
+    let mut body = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let mut pos = 0;
+    loop {
+        // チャンクサイズ行
+        let line_end = loop {
+            if let Some(i) = find_subslice(&buf[pos..], b"\r\n") {
+                break pos + i;
+            }
+            let n = stream
+                .read(&mut chunk)
+                .await
+                .context("Failed to read response body")
+                .kind(ErrorKind::BodyRead)?;
+            if n == 0 {
+                return Err(fail(
+                    ErrorKind::BodyRead,
+                    "Connection closed inside chunked response body",
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        };
+        let size_str = String::from_utf8_lossy(&buf[pos..line_end]);
+        let size_str = size_str.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .with_context(|| format!("Invalid chunk size: {}", size_str))
+            .kind(ErrorKind::Parse)?;
+        pos = line_end + 2;
+        if body.len().saturating_add(size) > limit {
+            return Err(body_too_large());
+        }
+        if size == 0 {
+            // トレーラーを読み飛ばし、終端の空行まで消費する (接続の再利用に備える)
+            loop {
+                if let Some(i) = find_subslice(&buf[pos..], b"\r\n") {
+                    if i == 0 {
+                        return Ok(body);
+                    }
+                    pos += i + 2;
+                    continue;
+                }
+                let n = stream
+                    .read(&mut chunk)
+                    .await
+                    .context("Failed to read response body")
+                    .kind(ErrorKind::BodyRead)?;
+                if n == 0 {
+                    return Ok(body);
+                }
+                buf.extend_from_slice(&chunk[..n]);
+            }
+        }
+        while buf.len() < pos + size + 2 {
+            let n = stream
+                .read(&mut chunk)
+                .await
+                .context("Failed to read response body")
+                .kind(ErrorKind::BodyRead)?;
+            if n == 0 {
+                return Err(fail(
+                    ErrorKind::BodyRead,
+                    "Connection closed inside chunked response body",
+                ));
+            }
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        body.extend_from_slice(&buf[pos..pos + size]);
+        pos += size + 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_subslice_finds_first_match() {
+        assert_eq!(find_subslice(b"ab\r\n\r\ncd\r\n\r\n", b"\r\n\r\n"), Some(2));
+        assert_eq!(find_subslice(b"abc", b"\r\n"), None);
+        assert_eq!(find_subslice(b"", b"\r\n"), None);
+        assert_eq!(find_subslice(b"\r", b"\r\n"), None);
+    }
+
+    #[test]
+    fn parse_status_line_reads_code() {
+        assert_eq!(parse_status_line("HTTP/1.1 200 OK").unwrap(), 200);
+        assert_eq!(parse_status_line("HTTP/1.0 404").unwrap(), 404);
+        assert_eq!(
+            parse_status_line("HTTP/1.1 503 Service Unavailable").unwrap(),
+            503
+        );
+    }
+
+    #[test]
+    fn parse_status_line_rejects_garbage() {
+        for line in ["", "HTTP/1.1", "ICY 200 OK", "HTTP/1.1 abc OK"] {
+            let err = parse_status_line(line).unwrap_err();
+            assert_eq!(ProbeError::kind_of(&err), ErrorKind::Parse, "{:?}", line);
+        }
+    }
+
+    #[tokio::test]
+    async fn read_chunked_decodes_body_and_trailers() {
+        let mut rest: &[u8] = b"lo\r\n6;ext=1\r\n world\r\n0\r\nX-Trailer: 1\r\n\r\n";
+        let body = read_chunked(&mut rest, b"5\r\nhel".to_vec(), 1024)
+            .await
+            .unwrap();
+        assert_eq!(body, b"hello world");
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn read_chunked_stops_at_terminator() {
+        let mut rest: &[u8] = b"3\r\nabc\r\n0\r\n\r\nHTTP/1.1 200 OK\r\n";
+        let body = read_chunked(&mut rest, Vec::new(), 1024).await.unwrap();
+        assert_eq!(body, b"abc");
+    }
+
+    #[tokio::test]
+    async fn read_chunked_rejects_truncated_and_invalid_input() {
+        let mut rest: &[u8] = b"";
+        let err = read_chunked(&mut rest, b"a\r\nabc".to_vec(), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(ProbeError::kind_of(&err), ErrorKind::BodyRead);
+
+        let mut rest: &[u8] = b"";
+        let err = read_chunked(&mut rest, b"zz\r\n".to_vec(), 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(ProbeError::kind_of(&err), ErrorKind::Parse);
+    }
+
+    #[tokio::test]
+    async fn read_chunked_enforces_limit() {
+        let mut rest: &[u8] = b"";
+        let err = read_chunked(&mut rest, b"8\r\n12345678\r\n0\r\n\r\n".to_vec(), 4)
+            .await
+            .unwrap_err();
+        assert_eq!(ProbeError::kind_of(&err), ErrorKind::BodyRead);
+    }
+
+    #[test]
+    fn phase_timings_add_sums_present_phases() {
+        let mut a = PhaseTimings {
+            dns_millis: Some(3),
+            connect_millis: Some(10),
+            ttfb_millis: Some(20),
+            body_millis: Some(1),
+            ..Default::default()
+        };
+        a.add(&PhaseTimings {
+            connect_millis: Some(12),
+            tls_millis: Some(30),
+            ttfb_millis: Some(25),
+            body_millis: Some(2),
+            ..Default::default()
+        });
+        assert_eq!(a.dns_millis, Some(3));
+        assert_eq!(a.connect_millis, Some(22));
+        assert_eq!(a.tls_millis, Some(30));
+        assert_eq!(a.ttfb_millis, Some(45));
+        assert_eq!(a.body_millis, Some(3));
+    }
+
+    #[test]
+    fn interleave_families_alternates_starting_with_first() {
+        let v6a: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let v6b: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let v4a: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let v4b: SocketAddr = "192.0.2.2:443".parse().unwrap();
+        assert_eq!(
+            interleave_families(vec![v6a, v6b, v4a, v4b]),
+            vec![v6a, v4a, v6b, v4b]
+        );
+        assert_eq!(
+            interleave_families(vec![v4a, v4b, v6a]),
+            vec![v4a, v6a, v4b]
+        );
+        assert_eq!(interleave_families(vec![v4a, v4b]), vec![v4a, v4b]);
+    }
+
+    #[tokio::test]
+    async fn connect_any_falls_back_to_a_working_address() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let good = listener.local_addr().unwrap();
+        let closed = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+        let (addr, _) = connect_any(&[closed, good]).await.unwrap();
+        assert_eq!(addr, good);
+
+        let (addr, _) = connect_any(&[closed]).await.unwrap_err();
+        assert_eq!(addr, closed);
+    }
+
+    /// HTTP/1.1 keep-alive を話す最小のサーバ。受け付けた接続数を返す。
+    /// `/redirect` → `/ok`、`/ok` は "ok"、`/large` は上限を超える長さを宣言、
+    /// `/unbounded` は長さなしで上限を超えるボディを送って閉じる。
+    async fn serve() -> (Url, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    loop {
+                        let end = loop {
+                            if let Some(end) = find_subslice(&buf, b"\r\n\r\n") {
+                                break end;
+                            }
+                            match stream.read(&mut chunk).await {
+                                Ok(0) | Err(_) => return,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                        };
+                        let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+                        buf.drain(..end + 4);
+                        let path = head.split(' ').nth(1).unwrap_or("/");
+                        let response: Vec<u8> = match path {
+                            "/redirect" => {
+                                b"HTTP/1.1 302 Found\r\nLocation: /ok\r\nContent-Length: 0\r\n\r\n"
+                                    .to_vec()
+                            }
+                            "/ok" => b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok".to_vec(),
+                            "/large" => format!(
+                                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                                MAX_BODY_BYTES + 1
+                            )
+                            .into_bytes(),
+                            "/unbounded" => {
+                                let mut r =
+                                    b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n".to_vec();
+                                r.resize(r.len() + MAX_BODY_BYTES + 1, b'x');
+                                r
+                            }
+                            _ => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                        };
+                        if stream.write_all(&response).await.is_err() || path == "/unbounded" {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+        (base, accepted)
+    }
+
+    fn get(url: &Url) -> ProbeRequest<'_> {
+        ProbeRequest {
+            method: Method::Get,
+            url,
+            family: AddressFamily::Any,
+            headers: &[],
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[tokio::test]
+    async fn send_follows_redirects() {
+        let (base, _) = serve().await;
+        let prober = Prober::new("test").unwrap();
+        let url = base.join("redirect").unwrap();
+        let resp = prober.send(&get(&url)).await.unwrap();
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.body, "ok");
+
+        let url = base.join("missing").unwrap();
+        let err = prober.send(&get(&url)).await.unwrap_err();
+        assert_eq!(
+            ProbeError::kind_of(&err),
+            ErrorKind::HttpStatus { code: 404 }
+        );
+    }
+
+    #[tokio::test]
+    async fn send_caps_the_body_size() {
+        let (base, _) = serve().await;
+        let prober = Prober::new("test").unwrap();
+        for path in ["large", "unbounded"] {
+            let url = base.join(path).unwrap();
+            let err = prober.send(&get(&url)).await.unwrap_err();
+            assert_eq!(ProbeError::kind_of(&err), ErrorKind::BodyRead, "{}", path);
+        }
+    }
+
+    #[tokio::test]
+    async fn sample_reuses_the_connection() {
+        use std::sync::atomic::Ordering;
+
+        let (base, accepted) = serve().await;
+        let prober = Prober::new("test").unwrap();
+        let url = base.join("ok").unwrap();
+
+        let sampling = prober.sample(&get(&url), 3, true).await;
+        assert!(sampling.warmup.unwrap().is_ok());
+        assert_eq!(sampling.samples.len(), 3);
+        assert!(sampling.samples.iter().all(|s| s.is_ok()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+
+        let sampling = prober.sample(&get(&url), 3, false).await;
+        assert!(sampling.warmup.is_none());
+        assert!(sampling.samples.iter().all(|s| s.is_ok()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 4);
+    }
+}
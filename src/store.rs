@@ -0,0 +1,365 @@
+//! `output_format = "sqlite"` のときの保存先。
+//!
+//! チェック結果は `check_results` テーブルに 1 行ずつ入れ、timestamp の索引で
+//! レポート期間だけを読む ((url, timestamp, family) は UNIQUE 制約で重複を防ぐ)。行には SQL で集計しやすい列と、`CheckResult` 全体の JSON (`data`) を持つ。
+//! colo 変更検知の状態 (`LastSuccessState`) も同じデータベースの `last_success_states` に置く。
+//! 接続はパスごとにプロセス内で 1 本だけ開いて使い回す。
+//! どの関数もブロッキングなので `spawn_blocking` の中から呼ぶ。
+
+use crate::probe::AddressFamily;
+use crate::{CheckResult, LastSuccessState};
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use rusqlite::{Connection, OptionalExtension, params};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::sync::{LazyLock, Mutex, PoisonError};
+use std::time::Duration;
+
+/// 監視とレポートを別プロセスで動かしたときに書き込みを待つ時間
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS check_results (
+    id INTEGER PRIMARY KEY,
+    url TEXT NOT NULL,
+    address_family TEXT NOT NULL,
+    timestamp_ms INTEGER NOT NULL,
+    success INTEGER NOT NULL,
+    rtt_millis INTEGER,
+    colo TEXT,
+    error_kind TEXT,
+    data TEXT NOT NULL,
+    UNIQUE (url, timestamp_ms, address_family)
+);
+CREATE INDEX IF NOT EXISTS check_results_timestamp ON check_results (timestamp_ms);
+CREATE TABLE IF NOT EXISTS last_success_states (
+    url TEXT NOT NULL,
+    address_family TEXT NOT NULL,
+    data TEXT NOT NULL,
+    PRIMARY KEY (url, address_family)
+);
+";
+
+/// SQLite のデータベースファイルの先頭 16 バイト
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+
+/// パスごとの開いたままの接続
+static CONNECTIONS: LazyLock<Mutex<HashMap<String, Connection>>> = LazyLock::new(Default::default);
+
+/// `path` が既にあって SQLite のデータベースでなければエラーにする
+/// (JSONL の出力先をそのまま使った場合に、書き込みのたびに失敗しないよう起動時に確かめる)。
+pub fn check_database(path: &str) -> Result<()> {
+    let mut header = Vec::new();
+    match File::open(path) {
+        Ok(file) => {
+            file.take(SQLITE_HEADER.len() as u64)
+                .read_to_end(&mut header)
+                .with_context(|| format!("Failed to read {}", path))?;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open {}", path)),
+    }
+    // 空のファイルは SQLite が新しいデータベースとして使える
+    if !header.is_empty() && header != SQLITE_HEADER {
+        anyhow::bail!(
+            "{} is not an SQLite database; with output_format = \"sqlite\" set output_path to a separate file (e.g. \"tracekey.db\")",
+            path
+        );
+    }
+    Ok(())
+}
+
+/// データベースを開き、テーブルがなければ作る。
+fn open(path: &str) -> Result<Connection> {
+    let conn =
+        Connection::open(path).with_context(|| format!("Failed to open database {}", path))?;
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    // 監視の書き込み中でもレポートが読めるようにする
+    conn.pragma_update(None, "journal_mode", "WAL")?;
+    conn.execute_batch(SCHEMA)?;
+    Ok(conn)
+}
+
+/// `path` の接続で `f` を実行する。初めてのパスならここで開く。
+fn with_connection<T>(path: &str, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<T> {
+    let mut connections = CONNECTIONS.lock().unwrap_or_else(PoisonError::into_inner);
+    let conn = match connections.entry(path.to_string()) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(open(path)?),
+    };
+    f(conn)
+}
+
+/// 読むだけの処理で空のデータベースを作らないよう、ファイルがなければ `None`。
+fn with_existing<T>(path: &str, f: impl FnOnce(&mut Connection) -> Result<T>) -> Result<Option<T>> {
+    if !Path::new(path).exists() {
+        return Ok(None);
+    }
+    with_connection(path, f).map(Some)
+}
+
+fn family_key(family: AddressFamily) -> String {
+    family.to_string().to_ascii_lowercase()
+}
+
+/// 結果を追加する。同じ (url, timestamp, family) の行が既にあれば飛ばし、追加した件数を返す。
+pub fn insert_results(path: &str, results: &[CheckResult]) -> Result<usize> {
+    with_connection(path, |conn| {
+        let tx = conn.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO check_results
+                    (url, address_family, timestamp_ms, success, rtt_millis, colo, error_kind, data)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            )?;
+            for result in results {
+                inserted += stmt.execute(params![
+                    result.url,
+                    family_key(result.address_family),
+                    result.timestamp.timestamp_millis(),
+                    result.success,
+                    result.rtt_millis.map(|ms| ms as i64),
+                    result.colo,
+                    result.error_kind.map(|kind| kind.to_string()),
+                    serde_json::to_string(result)?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    })
+}
+
+/// 期間内の結果を時刻順に読む。
+pub fn load_results(
+    path: &str,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+) -> Result<Vec<CheckResult>> {
+    Ok(with_existing(path, |conn| {
+        let mut stmt = conn.prepare(
+            "SELECT id, data FROM check_results
+             WHERE timestamp_ms >= ?1 AND timestamp_ms <= ?2
+             ORDER BY timestamp_ms, id",
+        )?;
+        let rows = stmt.query_map(
+            params![
+                since.map_or(i64::MIN, |s| s.timestamp_millis()),
+                until.map_or(i64::MAX, |u| u.timestamp_millis()),
+            ],
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?)),
+        )?;
+        let mut results = Vec::new();
+        for row in rows {
+            let (id, data) = row?;
+            match serde_json::from_str::<CheckResult>(&data) {
+                Ok(result) => results.push(result),
+                Err(e) => eprintln!("Skip malformed check result row {}: {}", id, e),
+            }
+        }
+        Ok(results)
+    })?
+    .unwrap_or_default())
+}
+
+/// 渡された系列の状態だけを書き換える (他の系列はそのまま残る)。
+pub fn save_last_success_states(path: &str, states: &[LastSuccessState]) -> Result<()> {
+    with_connection(path, |conn| {
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT INTO last_success_states (url, address_family, data) VALUES (?1, ?2, ?3)
+                 ON CONFLICT (url, address_family) DO UPDATE SET data = excluded.data",
+            )?;
+            for state in states {
+                stmt.execute(params![
+                    state.url,
+                    family_key(state.address_family),
+                    serde_json::to_string(state)?,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    })
+}
+
+pub fn load_last_success_states(path: &str) -> Result<Vec<LastSuccessState>> {
+    Ok(with_existing(path, |conn| {
+        let mut stmt = conn.prepare("SELECT url, address_family, data FROM last_success_states")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+        let mut states = Vec::new();
+        for row in rows {
+            let (url, family, data) = row?;
+            match serde_json::from_str(&data) {
+                Ok(state) => states.push(state),
+                Err(e) => eprintln!(
+                    "Failed to parse saved state for {} ({}), starting fresh: {}",
+                    url, family, e
+                ),
+            }
+        }
+        Ok(states)
+    })?
+    .unwrap_or_default())
+}
+
+/// 状態テーブルに 1 行でもあるか (JSON の状態ファイルから移すかの判断用)
+pub fn has_last_success_states(path: &str) -> Result<bool> {
+    Ok(with_existing(path, |conn| {
+        Ok(conn
+            .query_row("SELECT 1 FROM last_success_states LIMIT 1", [], |_| Ok(()))
+            .optional()?
+            .is_some())
+    })?
+    .unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{TempDir, at, check};
+
+    fn colos(results: &[CheckResult]) -> Vec<(i64, Option<&str>)> {
+        results
+            .iter()
+            .map(|r| ((r.timestamp - at(0)).num_minutes(), r.colo.as_deref()))
+            .collect()
+    }
+
+    #[test]
+    fn inserted_results_load_back_by_range() {
+        let dir = TempDir::new("store-range");
+        let db = dir.file("tracekey.db");
+        let mut v6 = check(5, Some("KIX"));
+        v6.address_family = AddressFamily::Ipv6;
+        let results = [
+            check(10, Some("NRT")),
+            check(0, Some("NRT")),
+            check(5, None),
+            v6,
+            check(15, Some("HKG")),
+        ];
+        assert_eq!(insert_results(&db, &results).unwrap(), 5);
+
+        let loaded = load_results(&db, Some(at(5)), Some(at(10))).unwrap();
+        assert_eq!(
+            colos(&loaded),
+            [(5, None), (5, Some("KIX")), (10, Some("NRT"))]
+        );
+        assert_eq!(loaded[1].address_family, AddressFamily::Ipv6);
+        assert!(!loaded[0].success);
+        assert_eq!(loaded[0].error.as_deref(), Some("timed out"));
+
+        assert_eq!(load_results(&db, None, None).unwrap().len(), 5);
+        assert!(load_results(&db, Some(at(20)), None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reimporting_results_skips_existing_rows() {
+        let dir = TempDir::new("store-dedupe");
+        let db = dir.file("tracekey.db");
+        let first = [check(0, Some("NRT")), check(5, Some("NRT"))];
+        assert_eq!(insert_results(&db, &first).unwrap(), 2);
+        assert_eq!(insert_results(&db, &first).unwrap(), 0);
+
+        // 同じ時刻でも別の結果で上書きはしない
+        let overlapping = [check(5, Some("KIX")), check(10, Some("KIX"))];
+        assert_eq!(insert_results(&db, &overlapping).unwrap(), 1);
+        let loaded = load_results(&db, None, None).unwrap();
+        assert_eq!(
+            colos(&loaded),
+            [(0, Some("NRT")), (5, Some("NRT")), (10, Some("KIX"))]
+        );
+    }
+
+    #[test]
+    fn saving_states_upserts_per_series() {
+        let dir = TempDir::new("store-states");
+        let db = dir.file("tracekey.db");
+        assert!(load_last_success_states(&db).unwrap().is_empty());
+        assert!(!has_last_success_states(&db).unwrap());
+        // 読むだけではデータベースを作らない
+        assert!(!Path::new(&db).exists());
+
+        let state = |url: &str, family, colo: &str| {
+            let mut state = LastSuccessState::new(url, family, at(0));
+            state.colo = Some(colo.to_string());
+            state
+        };
+        save_last_success_states(
+            &db,
+            &[
+                state("https://a.example", AddressFamily::Any, "NRT"),
+                state("https://b.example", AddressFamily::Ipv4, "KIX"),
+            ],
+        )
+        .unwrap();
+        assert!(has_last_success_states(&db).unwrap());
+        save_last_success_states(
+            &db,
+            &[
+                state("https://a.example", AddressFamily::Any, "HKG"),
+                state("https://b.example", AddressFamily::Ipv6, "SIN"),
+            ],
+        )
+        .unwrap();
+
+        let mut loaded: Vec<_> = load_last_success_states(&db)
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.url, s.address_family, s.colo.unwrap()))
+            .collect();
+        loaded.sort();
+        assert_eq!(
+            loaded,
+            [
+                (
+                    "https://a.example".to_string(),
+                    AddressFamily::Any,
+                    "HKG".to_string()
+                ),
+                (
+                    "https://b.example".to_string(),
+                    AddressFamily::Ipv4,
+                    "KIX".to_string()
+                ),
+                (
+                    "https://b.example".to_string(),
+                    AddressFamily::Ipv6,
+                    "SIN".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn check_database_rejects_other_files() {
+        let dir = TempDir::new("store-check");
+        let jsonl = dir.file("results.jsonl");
+        std::fs::write(&jsonl, "{\"url\":\"https://example.com\"}\n").unwrap();
+        let err = check_database(&jsonl).unwrap_err().to_string();
+        assert!(err.contains("is not an SQLite database"), "{}", err);
+
+        // ないファイルと空のファイルは新しいデータベースとして使える
+        check_database(&dir.file("missing.db")).unwrap();
+        let empty = dir.file("empty.db");
+        std::fs::write(&empty, "").unwrap();
+        check_database(&empty).unwrap();
+
+        let db = dir.file("tracekey.db");
+        insert_results(&db, &[check(0, Some("NRT"))]).unwrap();
+        check_database(&db).unwrap();
+    }
+}
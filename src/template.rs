@@ -0,0 +1,106 @@
+//! ユーザー定義の通知テンプレート (minijinja)。
+//!
+//! イベントは 1 件ずつ `Event` を、レポートは `Report` をそのままシリアライズしたものを
+//! コンテキストとして渡す。期間は `*_secs` の秒数、時刻は RFC 3339 文字列になるので、
+//! `duration` / `localtime` フィルタで整形する。colo コードは `colo` フィルタで所在地に引ける。
+
+use crate::Report;
+use crate::colo;
+use crate::i18n::Locale;
+use crate::notify::Event;
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use humantime::format_duration;
+use minijinja::{Environment, UndefinedBehavior, Value, context};
+use std::time::Duration;
+
+const EVENT: &str = "event";
+const REPORT: &str = "report";
+
+/// 1 つの通知先 (またはコンソール) 用に読み込んだテンプレート
+pub struct Templates {
+    env: Environment<'static>,
+}
+
+impl Templates {
+    /// 指定されたファイルを読み込む。どちらも未指定なら `None`。
+    pub fn load(event_path: Option<&str>, report_path: Option<&str>) -> Result<Option<Self>> {
+        if event_path.is_none() && report_path.is_none() {
+            return Ok(None);
+        }
+        let mut env = Environment::new();
+        // 無いキーを出力したらエラーにして組み込みの書式に戻す。`{% if %}` での有無の確認はできる
+        env.set_undefined_behavior(UndefinedBehavior::SemiStrict);
+        env.set_trim_blocks(true);
+        env.set_lstrip_blocks(true);
+        env.add_filter("duration", duration_filter);
+        env.add_filter("localtime", localtime_filter);
+        env.add_filter("colo", colo_filter);
+        for (name, path) in [(EVENT, event_path), (REPORT, report_path)] {
+            let Some(path) = path else {
+                continue;
+            };
+            let source = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read template {}", path))?;
+            env.add_template_owned(name, source)
+                .with_context(|| format!("Invalid template {}", path))?;
+        }
+        Ok(Some(Self { env }))
+    }
+
+    pub fn has_event(&self) -> bool {
+        self.env.get_template(EVENT).is_ok()
+    }
+
+    pub fn has_report(&self) -> bool {
+        self.env.get_template(REPORT).is_ok()
+    }
+
+    /// イベントを 1 件ずつ描画して改行でつなぐ。
+    pub fn render_events(&self, events: &[Event], sink: &str, locale: Locale) -> Result<String> {
+        let template = self.env.get_template(EVENT)?;
+        let lines = events
+            .iter()
+            .map(|event| {
+                template
+                    .render(
+                        context! { sink => sink, locale => locale, ..Value::from_serialize(event) },
+                    )
+                    .map(|line| line.trim_end().to_string())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(lines.join("\n"))
+    }
+
+    pub fn render_report(&self, report: &Report, sink: &str, locale: Locale) -> Result<String> {
+        let template = self.env.get_template(REPORT)?;
+        Ok(template
+            .render(context! { sink => sink, locale => locale, ..Value::from_serialize(report) })?)
+    }
+}
+
+/// 秒数を "1h 2m 3s" 形式にする。
+fn duration_filter(secs: Option<i64>) -> String {
+    match secs {
+        Some(secs) => format_duration(Duration::from_secs(secs.max(0) as u64)).to_string(),
+        None => "N/A".to_string(),
+    }
+}
+
+/// RFC 3339 の時刻をローカル時刻にして strftime 形式で整形する。
+fn localtime_filter(value: String, format: Option<String>) -> Result<String, minijinja::Error> {
+    let time = DateTime::parse_from_rfc3339(&value).map_err(|e| {
+        minijinja::Error::new(
+            minijinja::ErrorKind::InvalidOperation,
+            format!("not an RFC 3339 time: {} ({})", value, e),
+        )
+    })?;
+    let format = format.as_deref().unwrap_or("%Y-%m-%d %H:%M:%S %Z");
+    Ok(time.with_timezone(&Local).format(format).to_string())
+}
+
+/// colo コードの所在地 (`code` / `city` / `country` / `continent` / `latitude` / `longitude`)。
+/// 表にないコードは none。
+fn colo_filter(code: String) -> Value {
+    colo::lookup(&code).map_or(Value::from(()), Value::from_serialize)
+}